@@ -0,0 +1,227 @@
+// Player accounts and their saved characters.
+//
+// The auth service owns accounts.ron, mapping usernames to a stable player id which doubles as the
+// netcode client id. The game server owns the character files, one per player id. Keeping the two apart
+// means the auth service can run as its own process without the two writing over each other.
+
+use std::collections::BTreeMap;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex, MutexGuard };
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{ Commands, Entity, Name, Query, Res, Resource };
+use lightyear::prelude::ClientId;
+use rustc_hash::{ FxHashMap, FxHashSet };
+use serde::{ Deserialize, Serialize };
+
+use crate::game::inventory::InsertItem;
+use crate::game::items::{ spawn_item_stack, Inventory, ItemProperties, ItemStorage, Quantity };
+use crate::game::migrations::SaveKind;
+use crate::game::save::{
+    merged_properties,
+    read_save,
+    read_versioned,
+    saved_properties,
+    write_save,
+    SaveError,
+    SavedProperty,
+    SAVE_VERSION,
+};
+use crate::network::protocol::PlayerPosition;
+
+const ACCOUNTS_FILE: &str = "accounts.ron";
+const CHARACTERS_DIR: &str = "characters";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub player_id: u64,
+    // See network::auth::hash_password.
+    pub password_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountStore {
+    // Ids are never reused, even if an account gets deleted by hand.
+    pub next_player_id: u64,
+    pub accounts: FxHashMap<String, Account>,
+}
+
+// The host's local client doesn't log in, so it has no account.
+pub const HOST_PLAYER_ID: u64 = 0;
+
+impl Default for AccountStore {
+    fn default() -> Self {
+        Self { next_player_id: HOST_PLAYER_ID + 1, accounts: Default::default() }
+    }
+}
+
+impl AccountStore {
+    pub fn create(&mut self, username: &str, password_hash: String) -> u64 {
+        let player_id = self.next_player_id;
+        self.next_player_id += 1;
+        self.accounts.insert(username.to_string(), Account { player_id, password_hash });
+        player_id
+    }
+
+    // Accounts are keyed by username, so this has to look through all of them.
+    pub fn username(&self, player_id: u64) -> Option<&str> {
+        self.accounts
+            .iter()
+            .find(|(_, account)| account.player_id == player_id)
+            .map(|(username, _)| username.as_str())
+    }
+}
+
+pub struct AccountBook {
+    pub store: AccountStore,
+    pub path: PathBuf,
+    // Players with a live session. Only the game server running in the same process fills this in,
+    // so a standalone auth service can't turn away a second login itself.
+    pub online: FxHashSet<u64>,
+}
+
+impl AccountBook {
+    pub fn save(&self) -> Result<(), SaveError> {
+        write_save(&self.path, &self.store)
+    }
+}
+
+// Shared between the auth service thread and the game server.
+#[derive(Resource, Clone)]
+pub struct Accounts(pub Arc<Mutex<AccountBook>>);
+
+impl Accounts {
+    pub fn load(save_dir: &Path) -> Result<Self, SaveError> {
+        let path = save_dir.join(ACCOUNTS_FILE);
+        let store = read_save(&path)?.unwrap_or_default();
+        Ok(Self(Arc::new(Mutex::new(AccountBook { store, path, online: Default::default() }))))
+    }
+
+    // A panic while holding the lock doesn't make the data any less valid, so just carry on.
+    pub fn lock(&self) -> MutexGuard<'_, AccountBook> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// The account's player id, which is what the auth service put in the connect token.
+pub fn player_id(client_id: ClientId) -> u64 {
+    match client_id {
+        ClientId::Netcode(id) | ClientId::Steam(id) | ClientId::Local(id) => id,
+    }
+}
+
+// A stack and, if it holds anything, whatever is inside it.
+// The stack itself is kept the same way as a SavedItem in the world save.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemSave {
+    pub id: String,
+    pub quantity: u32,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, SavedProperty>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contents: Vec<ItemSave>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CharacterSave {
+    pub version: u32,
+    pub position: (f32, f32, f32),
+    pub inventory: Vec<ItemSave>,
+}
+
+impl Default for CharacterSave {
+    fn default() -> Self {
+        Self { version: SAVE_VERSION, position: Default::default(), inventory: Vec::new() }
+    }
+}
+
+pub fn character_path(save_dir: &Path, player_id: u64) -> PathBuf {
+    save_dir.join(CHARACTERS_DIR).join(format!("{}.ron", player_id))
+}
+
+// Everything inside the holder's inventory, nested containers included.
+pub fn snapshot_items(
+    holder: Entity,
+    item_storage: &ItemStorage,
+    inventories: &Query<&Inventory>,
+    items: &Query<(&Name, &Quantity, &ItemProperties)>
+) -> Vec<ItemSave> {
+    let Ok(inventory) = inventories.get(holder) else {
+        return Vec::new();
+    };
+    let mut saves: Vec<ItemSave> = inventory.items
+        .iter()
+        .filter_map(|item| {
+            let (name, quantity, properties) = items.get(*item).ok()?;
+            Some(ItemSave {
+                id: name.as_str().to_string(),
+                quantity: quantity.0,
+                properties: saved_properties(item_storage, name.as_str(), properties),
+                contents: snapshot_items(*item, item_storage, inventories, items),
+            })
+        })
+        .collect();
+    // Sets have no order, this keeps saves stable between runs.
+    saves.sort_by(|a, b| a.id.cmp(&b.id).then(b.quantity.cmp(&a.quantity)));
+    saves
+}
+
+// Respawns saved items into the holder through the transfer API, so weights come out right.
+// Bags get their capacity from their definition again, anything that doesn't fit anymore is
+// logged and dropped by InsertItem.
+// Returns the ids of items that no longer exist in the definitions, which get dropped too.
+pub fn spawn_saved_items(
+    commands: &mut Commands,
+    item_storage: &Res<ItemStorage>,
+    holder: Entity,
+    saves: &[ItemSave]
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    for save in saves {
+        let Some(item) = spawn_item_stack(commands, item_storage, &save.id, save.quantity) else {
+            missing.push(save.id.clone());
+            continue;
+        };
+        if !save.properties.is_empty() {
+            commands.entity(item).insert(merged_properties(item_storage, &save.id, &save.properties));
+        }
+        // Queued before the item goes into the holder, so the holder's weight includes them.
+        missing.extend(spawn_saved_items(commands, item_storage, item, &save.contents));
+        commands.queue(InsertItem { item, container: holder });
+    }
+    missing
+}
+
+// What it takes to snapshot a character, bundled so the systems that need it don't drown in queries.
+#[derive(SystemParam)]
+pub struct CharacterQueries<'w, 's> {
+    pub positions: Query<'w, 's, &'static PlayerPosition>,
+    pub inventories: Query<'w, 's, &'static Inventory>,
+    pub items: Query<'w, 's, (&'static Name, &'static Quantity, &'static ItemProperties)>,
+    pub item_storage: Res<'w, ItemStorage>,
+}
+
+impl CharacterQueries<'_, '_> {
+    pub fn snapshot(&self, player: Entity) -> Option<CharacterSave> {
+        let position = self.positions.get(player).ok()?;
+        Some(CharacterSave {
+            version: SAVE_VERSION,
+            position: (position.x, position.y, position.z),
+            inventory: snapshot_items(player, &self.item_storage, &self.inventories, &self.items),
+        })
+    }
+}
+
+// Older characters are upgraded in memory and written back in the new format when the player leaves.
+pub fn load_character(save_dir: &Path, player_id: u64) -> Result<Option<CharacterSave>, SaveError> {
+    let character = read_versioned(&character_path(save_dir, player_id), SaveKind::Character)?;
+    Ok(character.map(|(character, _)| character))
+}
+
+pub fn save_character(
+    save_dir: &Path,
+    player_id: u64,
+    character: &CharacterSave
+) -> Result<(), SaveError> {
+    write_save(&character_path(save_dir, player_id), character)
+}
@@ -6,8 +6,11 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
-use std::net::SocketAddr;
+use std::net::{ Ipv4Addr, SocketAddr };
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::convert::Infallible;
+use std::fmt;
 use std::time::Duration;
 
 use bevy::asset::ron;
@@ -27,23 +30,125 @@ use serde::{ Deserialize, Serialize };
 
 use crate::utils::settings::*;
 use crate::network::shared::*;
+use crate::network::auth::Credentials;
+#[cfg(all(feature = "client", feature = "server"))]
+use crate::game::accounts::HOST_PLAYER_ID;
+#[cfg(feature = "client")]
+use crate::network::client::{ interpolation_config, prediction_config };
 
 #[cfg(feature = "gui")]
 use crate::render::ui::UiRenderPlugin;
 #[cfg(feature = "gui")]
 use bevy::window::PresentMode;
 
+// Every override can also be set through its FLESHBORN_* environment variable.
+// Precedence is CLI flag > environment variable > settings file.
 #[derive(Parser, PartialEq, Debug)]
 pub struct Cli {
+    /// Path to the RON settings file
+    #[arg(long, env = "FLESHBORN_SETTINGS", default_value = "assets/settings.ron")]
+    pub settings: PathBuf,
+
+    /// Disable any rendering-related plugins on the server
+    #[arg(long, env = "FLESHBORN_HEADLESS")]
+    pub headless: Option<bool>,
+
+    /// Enable bevy_inspector_egui on both client and server
+    #[arg(long, env = "FLESHBORN_INSPECTOR")]
+    pub inspector: Option<bool>,
+
+    /// The ip address of the server to connect to
+    #[arg(long, env = "FLESHBORN_SERVER_ADDR")]
+    pub server_addr: Option<Ipv4Addr>,
+
+    /// The port of the server to connect to
+    #[arg(long, env = "FLESHBORN_SERVER_PORT")]
+    pub server_port: Option<u16>,
+
+    /// The client port to listen on
+    #[arg(long, env = "FLESHBORN_CLIENT_PORT")]
+    pub client_port: Option<u16>,
+
+    /// Which transport the client uses
+    #[arg(long, env = "FLESHBORN_TRANSPORT", value_enum)]
+    pub transport: Option<ClientTransports>,
+
+    /// Ignore any link conditioner set in the settings file
+    #[arg(long, env = "FLESHBORN_NO_CONDITIONER")]
+    pub no_conditioner: bool,
+
+    /// Account to log in to the server's auth service with
+    #[cfg(feature = "client")]
+    #[arg(long, env = "FLESHBORN_USERNAME")]
+    pub username: Option<String>,
+
+    /// Password for --username
+    #[cfg(feature = "client")]
+    #[arg(long, env = "FLESHBORN_PASSWORD")]
+    pub password: Option<Secret>,
+
+    /// Invite code to register a new account with, using --username and --password
     #[cfg(feature = "client")]
-    #[arg(short, long)]
-    client_id: Option<u64>,
+    #[arg(long, env = "FLESHBORN_INVITE")]
+    pub invite: Option<Secret>,
+
+    /// Only run the auth service, e.g. on a different machine than the game server.
+    /// It can't tell who is online, so logging in twice at once is left to the game server to refuse
+    #[cfg(feature = "server")]
+    #[arg(long)]
+    pub auth_only: bool,
+
+    /// Read a password from stdin, print its hash for the auth credentials file and exit
+    #[cfg(feature = "server")]
+    #[arg(long)]
+    pub hash_password: bool,
+
+    /// Upgrade a world or character save to the current format, check it against the item definitions and exit
+    #[cfg(feature = "server")]
+    #[arg(long, value_name = "PATH")]
+    pub migrate_save: Option<PathBuf>,
+
+    /// With --migrate-save, only report what would change and leave the file as it is
+    #[cfg(feature = "server")]
+    #[arg(long, requires = "migrate_save")]
+    pub dry_run: bool,
 
     #[cfg(all(feature = "client", feature = "server"))]
     #[arg(short, long, default_value = "host-server", value_enum)]
     pub mode: ServerMode,
 }
 
+// A CLI value that shouldn't show up in logs, since the whole Cli gets logged on startup.
+#[derive(Clone, PartialEq)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+#[cfg(feature = "client")]
+impl Cli {
+    // With an invite code this registers a new account, otherwise it logs in to an existing one.
+    pub fn credentials(&self) -> Option<Credentials> {
+        let username = self.username.clone()?;
+        let password = self.password.as_ref()?.0.clone();
+        Some(match &self.invite {
+            Some(code) => Credentials::Invite { username, password, code: code.0.clone() },
+            None => Credentials::Password { username, password },
+        })
+    }
+}
+
 // Parses cli args so that they can be used to configure compilation etc.
 pub fn cli() -> Cli {
     info!("Parsing command-line arguments.");
@@ -70,6 +175,7 @@ impl SendApp {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum Apps {
     Client {
         app: App,
@@ -109,7 +215,7 @@ impl Apps {
                     ServerMode::HostServer => {
                         info!("Mode selected: HostServer");
                         let client_net_config = client::NetConfig::Local {
-                            id: cli.client_id.unwrap_or(settings.client.client_id),
+                            id: HOST_PLAYER_ID,
                         };
                         info!("Client network configuration fetched");
                         let (app, client_config, server_config) = combined_app(
@@ -137,13 +243,12 @@ impl Apps {
                         info!("Client transport configuration: {:?}", transport_config);
 
                         // create client app
+                        // the server address comes from the connect token, the channels don't care about it
+                        // Nothing goes over a wire between the two, so there's nothing to compress.
                         let net_config = build_client_netcode_config(
-                            cli.client_id.unwrap_or(settings.client.client_id),
-                            // when communicating via channels, we need to use the address `LOCAL_SOCKET` for the server
-                            LOCAL_SOCKET,
                             settings.client.conditioner.as_ref(),
-                            &settings.shared,
-                            transport_config
+                            transport_config,
+                            CompressionConfig::None
                         );
                         info!("Client network configuration built");
                         let (client_app, client_config) = client_app(settings.clone(), net_config);
@@ -180,9 +285,7 @@ impl Apps {
                     settings.client.server_port
                 );
                 info!("Server address for client: {}", server_addr);
-                let client_id = cli.client_id.unwrap_or(settings.client.client_id);
-                info!("Client ID: {}", client_id);
-                let net_config = get_client_net_config(&settings, client_id);
+                let net_config = get_client_net_config(&settings);
                 info!("Client network configuration recieved");
                 let (app, config) = client_app(settings, net_config);
                 info!("Client App created successfully.");
@@ -516,7 +619,7 @@ fn new_headless_app() -> App {
 #[cfg(feature = "client")]
 fn client_app(settings: Settings, net_config: client::NetConfig) -> (App, ClientConfig) {
     info!("Building Client App with settings: {:?} and netconfig", settings);
-    let app = new_gui_app(settings.client.inspector);
+    let mut app = new_gui_app(settings.client.inspector);
     info!("GUI App for Client created.");
 
     let client_config = ClientConfig {
@@ -526,9 +629,12 @@ fn client_app(settings: Settings, net_config: client::NetConfig) -> (App, Client
             send_interval: SERVER_REPLICATION_INTERVAL,
             ..default()
         },
+        prediction: prediction_config(),
+        interpolation: interpolation_config(),
         ..default()
     };
     info!("ClientConfig initialized");
+    app.insert_resource(settings);
     (app, client_config)
 }
 
@@ -549,28 +655,30 @@ fn server_app(
         // Winit doesn't support two event loops in the same thread.
         if #[cfg(feature = "client")] {
             info!("Client feature is enabled. Creating Headless App for Server.");
-            let app = new_headless_app();
+            let mut app = new_headless_app();
         } else if #[cfg(feature = "gui")] {
             info!("GUI feature is enabled. Creating GUI App for Server.");
-            let app = new_gui_app(settings.server.inspector);
+            let mut app = new_gui_app(settings.server.inspector);
         } else {
             info!("Creating Headless App for Server.");
-            let app = new_headless_app();
+            let mut app = new_headless_app();
         }
     }
     // configure the network configuration
     let mut net_configs = get_server_net_configs(&settings);
     info!("Initial Server network configurations");
+    // Only ever channels to a client in the same process, nothing to compress there.
     let extra_net_configs = extra_transport_configs
         .into_iter()
         .map(|c| {
-            build_server_netcode_config(settings.server.conditioner.as_ref(), &settings.shared, c)
+            build_server_netcode_config(&settings.server, c, CompressionConfig::None)
         });
     net_configs.extend(extra_net_configs);
     info!("Extended Server network configurations with extra transports");
     let server_config = ServerConfig {
         shared: shared_config(Mode::Separate),
         net: net_configs,
+        packet: build_server_packet_config(&settings.server),
         replication: ReplicationConfig {
             send_interval: SERVER_REPLICATION_INTERVAL,
             ..default()
@@ -578,6 +686,7 @@ fn server_app(
         ..default()
     };
     info!("ServerConfig initialized: {:?}", server_config);
+    app.insert_resource(settings);
     (app, server_config)
 }
 
@@ -593,21 +702,23 @@ fn combined_app(
         settings,
         extra_transport_configs
     );
-    let app = new_gui_app(settings.client.inspector || settings.server.inspector);
+    let mut app = new_gui_app(settings.client.inspector || settings.server.inspector);
     info!("GUI App for Combined App created.");
     // server config
     let mut net_configs = get_server_net_configs(&settings);
     info!("Initial Combined App server network configurations recieved");
+    // Only ever channels to a client in the same process, nothing to compress there.
     let extra_net_configs = extra_transport_configs
         .into_iter()
         .map(|c| {
-            build_server_netcode_config(settings.server.conditioner.as_ref(), &settings.shared, c)
+            build_server_netcode_config(&settings.server, c, CompressionConfig::None)
         });
     net_configs.extend(extra_net_configs);
     info!("Extended Combined App server network configurations with extra transports");
     let server_config = ServerConfig {
         shared: shared_config(Mode::HostServer),
         net: net_configs,
+        packet: build_server_packet_config(&settings.server),
         replication: ReplicationConfig {
             send_interval: SERVER_REPLICATION_INTERVAL,
             ..default()
@@ -623,5 +734,6 @@ fn combined_app(
         ..default()
     };
     info!("Combined App ClientConfig initialized");
+    app.insert_resource(settings);
     (app, client_config, server_config)
 }
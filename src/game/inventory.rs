@@ -0,0 +1,837 @@
+// Server-side item transfers. Anything that moves an item into, out of, or between containers
+// should go through MoveItemRequest, either as an event or queued straight onto Commands.
+// Each request is applied as a single command with full world access, so both inventories
+// and the item's ParentContainer are updated together or not at all.
+// It's also the only place Inventory::current_weight is maintained, including for every
+// container further up the chain when containers are nested.
+// Stackable items are merged automatically when they enter an inventory holding an identical
+// stack, and SplitStackRequest/MergeStacksRequest cover doing it by hand.
+// Freshly spawned items, e.g. from a save, go in with InsertItem, which gets rid of what doesn't fit.
+
+use bevy::ecs::world::Command;
+use bevy::prelude::{
+    App,
+    Commands,
+    Entity,
+    Event,
+    EventReader,
+    Component,
+    Name,
+    Plugin,
+    Update,
+    World,
+    info,
+    warn,
+};
+use rustc_hash::FxHashMap;
+use std::fmt;
+
+use crate::game::items::{ Inventory, Item, ItemProperties, MaxStack, ParentContainer, Quantity };
+use crate::utils::common::{ DisplayName, Icon, Tags, Weight };
+
+pub struct InventoryPlugin;
+
+impl Plugin for InventoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<MoveItemRequest>();
+        app.add_event::<SplitStackRequest>();
+        app.add_event::<MergeStacksRequest>();
+        app.add_event::<ItemMoved>();
+        app.add_event::<ItemMoveFailed>();
+        app.add_event::<StackSplit>();
+        app.add_event::<StacksMerged>();
+        app.add_systems(
+            Update,
+            (
+                queue_requests::<MoveItemRequest>,
+                queue_requests::<SplitStackRequest>,
+                queue_requests::<MergeStacksRequest>,
+            )
+        );
+    }
+}
+
+// Asks the server to move an item. `None` on either side means the item is loose in the world.
+// `quantity` moves only part of a stack, None moves all of it.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveItemRequest {
+    pub item: Entity,
+    pub from: Option<Entity>,
+    pub to: Option<Entity>,
+    pub quantity: Option<u32>,
+}
+
+// Splits `quantity` units off a stack into a new stack in the same place.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitStackRequest {
+    pub item: Entity,
+    pub quantity: u32,
+}
+
+// Moves as much of `source` as fits into `target`. Both have to be in the same place.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergeStacksRequest {
+    pub source: Entity,
+    pub target: Entity,
+}
+
+// Sent after a transfer has been applied. `item` is the stack that moved, which is a new entity
+// when only part of a stack was requested. If it was merged completely into `merged_into`
+// it has already been despawned.
+#[derive(Event, Debug, Clone, PartialEq, Eq)]
+pub struct ItemMoved {
+    pub item: Entity,
+    pub from: Option<Entity>,
+    pub to: Option<Entity>,
+    pub quantity: u32,
+    pub merged_into: Vec<Entity>,
+}
+
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackSplit {
+    pub item: Entity,
+    pub new_stack: Entity,
+    pub quantity: u32,
+}
+
+// `source` has been despawned if all of it fit into `target`.
+#[derive(Event, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StacksMerged {
+    pub source: Entity,
+    pub target: Entity,
+    pub quantity: u32,
+}
+
+// Sent when a request was rejected. Nothing was changed.
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct ItemMoveFailed {
+    pub request: MoveItemRequest,
+    pub error: TransferError,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferError {
+    // Both sides were None, or both were the same container.
+    NothingToMove,
+    MissingItem(Entity),
+    MissingContainer(Entity),
+    // The item isn't where the request says it is. `source` is None for "loose in the world".
+    ItemNotInSource {
+        item: Entity,
+        source: Option<Entity>,
+    },
+    OverCapacity {
+        container: Entity,
+        weight_limit: f32,
+        resulting_weight: f32,
+    },
+    // Putting a container inside itself, or inside something it already holds.
+    WouldContainItself {
+        item: Entity,
+        target: Entity,
+    },
+    InvalidQuantity {
+        item: Entity,
+        requested: u32,
+        available: u32,
+    },
+    NotStackable {
+        item: Entity,
+        other: Entity,
+    },
+    DifferentContainers {
+        item: Entity,
+        other: Entity,
+    },
+    StackFull(Entity),
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::NothingToMove => write!(f, "source and target are the same"),
+            TransferError::MissingItem(item) => write!(f, "{} is not an item", item),
+            TransferError::MissingContainer(container) => {
+                write!(f, "{} is not a container", container)
+            }
+            TransferError::ItemNotInSource { item, source: Some(source) } => {
+                write!(f, "item {} is not in container {}", item, source)
+            }
+            TransferError::ItemNotInSource { item, source: None } => {
+                write!(f, "item {} is inside a container, not loose in the world", item)
+            }
+            TransferError::OverCapacity { container, weight_limit, resulting_weight } => {
+                write!(
+                    f,
+                    "container {} would hold {} with a limit of {}",
+                    container,
+                    resulting_weight,
+                    weight_limit
+                )
+            }
+            TransferError::WouldContainItself { item, target } => {
+                write!(f, "container {} can't go inside {}, which it holds", item, target)
+            }
+            TransferError::InvalidQuantity { item, requested, available } => {
+                write!(f, "can't take {} from item {} which has {}", requested, item, available)
+            }
+            TransferError::NotStackable { item, other } => {
+                write!(f, "items {} and {} can't be stacked together", item, other)
+            }
+            TransferError::DifferentContainers { item, other } => {
+                write!(f, "items {} and {} aren't in the same place", item, other)
+            }
+            TransferError::StackFull(item) => write!(f, "stack {} is already full", item),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+fn stack_size(world: &World, item: Entity) -> u32 {
+    world.get::<Quantity>(item).map_or(1, |quantity| quantity.0)
+}
+
+fn unit_weight(world: &World, item: Entity) -> f32 {
+    world.get::<Weight>(item).map_or(0.0, |weight| weight.0)
+}
+
+// An entity's own weight (times its quantity) plus, if it's a container, everything inside it.
+pub fn total_weight(world: &World, entity: Entity) -> f32 {
+    let own = unit_weight(world, entity) * (stack_size(world, entity) as f32);
+    let contents = world.get::<Inventory>(entity).map_or(0.0, |inventory| inventory.current_weight);
+    own + contents
+}
+
+// Whether two items are interchangeable, i.e. the same definition with the same properties.
+// Containers carry their contents around with them, so they never stack.
+pub fn can_stack(world: &World, item: Entity, other: Entity) -> bool {
+    let max_stack = world.get::<MaxStack>(item).map_or(1, |max| max.0);
+    item != other &&
+        max_stack > 1 &&
+        world.get::<MaxStack>(other).map_or(1, |max| max.0) == max_stack &&
+        world.get::<Inventory>(item).is_none() &&
+        world.get::<Inventory>(other).is_none() &&
+        world.get::<Name>(item) == world.get::<Name>(other) &&
+        world.get::<ItemProperties>(item) == world.get::<ItemProperties>(other)
+}
+
+// Weight of the part of the stack a request actually moves.
+fn moved_weight(world: &World, request: &MoveItemRequest) -> f32 {
+    match request.quantity {
+        Some(amount) if amount < stack_size(world, request.item) => {
+            unit_weight(world, request.item) * (amount as f32)
+        }
+        _ => total_weight(world, request.item),
+    }
+}
+
+// The container itself, then whatever container holds it, and so on up to the outermost one.
+fn container_chain(world: &World, container: Entity) -> Vec<Entity> {
+    let mut chain = vec![container];
+    let mut current = container;
+    while let Some(parent) = world.get::<ParentContainer>(current) {
+        current = parent.0;
+        // Shouldn't be possible through the transfer API, but don't spin forever if it happens.
+        if chain.contains(&current) {
+            break;
+        }
+        chain.push(current);
+    }
+    chain
+}
+
+// How much each container's cached weight changes if the request goes through.
+// Containers that hold both the source and the target end up with a net change of zero.
+fn weight_changes(world: &World, request: &MoveItemRequest) -> FxHashMap<Entity, f32> {
+    let weight = moved_weight(world, request);
+    let mut changes = FxHashMap::default();
+    if let Some(source) = request.from {
+        for container in container_chain(world, source) {
+            *changes.entry(container).or_insert(0.0) -= weight;
+        }
+    }
+    if let Some(target) = request.to {
+        for container in container_chain(world, target) {
+            *changes.entry(container).or_insert(0.0) += weight;
+        }
+    }
+    changes
+}
+
+// Checks a request against the world without changing anything.
+fn validate_transfer(world: &World, request: &MoveItemRequest) -> Result<(), TransferError> {
+    let MoveItemRequest { item, from, to, quantity } = *request;
+
+    if from == to {
+        return Err(TransferError::NothingToMove);
+    }
+    if world.get::<Item>(item).is_none() {
+        return Err(TransferError::MissingItem(item));
+    }
+    if let Some(requested) = quantity {
+        let available = stack_size(world, item);
+        if requested == 0 || requested > available {
+            return Err(TransferError::InvalidQuantity { item, requested, available });
+        }
+    }
+
+    match from {
+        Some(source) => {
+            let inventory = world
+                .get::<Inventory>(source)
+                .ok_or(TransferError::MissingContainer(source))?;
+            if !inventory.items.contains(&item) {
+                return Err(TransferError::ItemNotInSource { item, source: from });
+            }
+        }
+        None => {
+            if world.get::<ParentContainer>(item).is_some() {
+                return Err(TransferError::ItemNotInSource { item, source: None });
+            }
+        }
+    }
+
+    if let Some(target) = to {
+        if world.get::<Inventory>(target).is_none() {
+            return Err(TransferError::MissingContainer(target));
+        }
+        if container_chain(world, target).contains(&item) {
+            return Err(TransferError::WouldContainItself { item, target });
+        }
+    }
+
+    // Every container up the chain has to have room, not just the one the item goes into.
+    for (container, change) in weight_changes(world, request) {
+        if change <= 0.0 {
+            continue;
+        }
+        let Some(inventory) = world.get::<Inventory>(container) else {
+            continue;
+        };
+        let resulting_weight = inventory.current_weight + change;
+        if resulting_weight > inventory.weight_limit {
+            return Err(TransferError::OverCapacity {
+                container,
+                weight_limit: inventory.weight_limit,
+                resulting_weight,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn clone_component<T: Component + Clone>(world: &mut World, from: Entity, to: Entity) {
+    if let Some(component) = world.get::<T>(from).cloned() {
+        world.entity_mut(to).insert(component);
+    }
+}
+
+// Takes `amount` units off a stack and returns them as a new, loose item.
+// Doesn't check anything or touch weights, callers take care of both.
+fn take_from_stack(world: &mut World, item: Entity, amount: u32) -> Entity {
+    let new_stack = world.spawn((Item, Quantity(amount))).id();
+    clone_component::<Name>(world, item, new_stack);
+    clone_component::<DisplayName>(world, item, new_stack);
+    clone_component::<Weight>(world, item, new_stack);
+    clone_component::<Icon>(world, item, new_stack);
+    clone_component::<Tags>(world, item, new_stack);
+    clone_component::<ItemProperties>(world, item, new_stack);
+    clone_component::<MaxStack>(world, item, new_stack);
+    if let Some(mut quantity) = world.get_mut::<Quantity>(item) {
+        quantity.0 -= amount;
+    }
+    new_stack
+}
+
+// Moves as many units as fit from one stack into another, returning how many moved.
+// Doesn't touch weights either, since the total being carried doesn't change.
+fn transfer_units(world: &mut World, source: Entity, target: Entity) -> u32 {
+    let max_stack = world.get::<MaxStack>(target).map_or(1, |max| max.0);
+    let room = max_stack.saturating_sub(stack_size(world, target));
+    let amount = room.min(stack_size(world, source));
+    if amount > 0 {
+        if let Some(mut quantity) = world.get_mut::<Quantity>(target) {
+            quantity.0 += amount;
+        }
+        if let Some(mut quantity) = world.get_mut::<Quantity>(source) {
+            quantity.0 -= amount;
+        }
+    }
+    amount
+}
+
+// Tops up the identical stacks already in `container` from `item`, returning the ones that grew.
+fn merge_into_inventory(world: &mut World, item: Entity, container: Entity) -> Vec<Entity> {
+    let Some(inventory) = world.get::<Inventory>(container) else {
+        return Vec::new();
+    };
+    let mut candidates: Vec<Entity> = inventory.items
+        .iter()
+        .copied()
+        .filter(|other| can_stack(world, item, *other))
+        .collect();
+    // Fill the same stacks first every time, regardless of hash order.
+    candidates.sort();
+
+    let mut merged_into = Vec::new();
+    for other in candidates {
+        if stack_size(world, item) == 0 {
+            break;
+        }
+        if transfer_units(world, item, other) > 0 {
+            merged_into.push(other);
+        }
+    }
+    merged_into
+}
+
+// Despawns an item along with everything inside it, however deeply nested.
+fn despawn_with_contents(world: &mut World, item: Entity) {
+    let mut pending = vec![item];
+    while let Some(entity) = pending.pop() {
+        if let Some(inventory) = world.get::<Inventory>(entity) {
+            pending.extend(inventory.items.iter().copied());
+        }
+        world.despawn(entity);
+    }
+}
+
+// Moves an item, updating both inventories and ParentContainer.
+// Either everything is applied or, on error, nothing is.
+pub fn move_item(world: &mut World, request: MoveItemRequest) -> Result<ItemMoved, TransferError> {
+    validate_transfer(world, &request)?;
+    let MoveItemRequest { item, from, to, quantity } = request;
+
+    // Worked out before anything moves, since the chains change once ParentContainer does.
+    for (container, change) in weight_changes(world, &request) {
+        if let Some(mut inventory) = world.get_mut::<Inventory>(container) {
+            inventory.current_weight += change;
+        }
+    }
+
+    // Moving part of a stack leaves the original where it is and moves a new stack instead.
+    let moving = match quantity {
+        Some(amount) if amount < stack_size(world, item) => take_from_stack(world, item, amount),
+        _ => {
+            if let Some(source) = from {
+                if let Some(mut inventory) = world.get_mut::<Inventory>(source) {
+                    inventory.items.remove(&item);
+                }
+            }
+            item
+        }
+    };
+    let moved_quantity = stack_size(world, moving);
+
+    let mut merged_into = Vec::new();
+    match to {
+        Some(target) => {
+            merged_into = merge_into_inventory(world, moving, target);
+            if stack_size(world, moving) == 0 {
+                world.despawn(moving);
+            } else {
+                if let Some(mut inventory) = world.get_mut::<Inventory>(target) {
+                    inventory.items.insert(moving);
+                }
+                world.entity_mut(moving).insert(ParentContainer(target));
+            }
+        }
+        None => {
+            world.entity_mut(moving).remove::<ParentContainer>();
+        }
+    }
+
+    Ok(ItemMoved { item: moving, from, to, quantity: moved_quantity, merged_into })
+}
+
+// Splits a stack in place. The new stack ends up in the same container as the old one.
+pub fn split_stack(world: &mut World, request: SplitStackRequest) -> Result<StackSplit, TransferError> {
+    let SplitStackRequest { item, quantity } = request;
+    if world.get::<Item>(item).is_none() {
+        return Err(TransferError::MissingItem(item));
+    }
+    let available = stack_size(world, item);
+    if quantity == 0 || quantity >= available {
+        return Err(TransferError::InvalidQuantity { item, requested: quantity, available });
+    }
+
+    let new_stack = take_from_stack(world, item, quantity);
+    if let Some(parent) = world.get::<ParentContainer>(item).map(|parent| parent.0) {
+        if let Some(mut inventory) = world.get_mut::<Inventory>(parent) {
+            inventory.items.insert(new_stack);
+        }
+        world.entity_mut(new_stack).insert(ParentContainer(parent));
+    }
+
+    Ok(StackSplit { item, new_stack, quantity })
+}
+
+// Merges one stack into another in the same place, despawning the source if it empties.
+pub fn merge_stacks(
+    world: &mut World,
+    request: MergeStacksRequest
+) -> Result<StacksMerged, TransferError> {
+    let MergeStacksRequest { source, target } = request;
+    for item in [source, target] {
+        if world.get::<Item>(item).is_none() {
+            return Err(TransferError::MissingItem(item));
+        }
+    }
+    if !can_stack(world, source, target) {
+        return Err(TransferError::NotStackable { item: source, other: target });
+    }
+    let source_parent = world.get::<ParentContainer>(source).map(|parent| parent.0);
+    if source_parent != world.get::<ParentContainer>(target).map(|parent| parent.0) {
+        return Err(TransferError::DifferentContainers { item: source, other: target });
+    }
+
+    let quantity = transfer_units(world, source, target);
+    if quantity == 0 {
+        return Err(TransferError::StackFull(target));
+    }
+    if stack_size(world, source) == 0 {
+        if let Some(mut inventory) = source_parent.and_then(|parent| world.get_mut::<Inventory>(parent)) {
+            inventory.items.remove(&source);
+        }
+        world.despawn(source);
+    }
+
+    Ok(StacksMerged { source, target, quantity })
+}
+
+impl Command for MoveItemRequest {
+    fn apply(self, world: &mut World) {
+        match move_item(world, self) {
+            Ok(moved) => {
+                info!(
+                    "Moved {} of item with id {:?} from {:?} into {:?}",
+                    moved.quantity,
+                    moved.item,
+                    moved.from,
+                    moved.to
+                );
+                world.send_event(moved);
+            }
+            Err(error) => {
+                warn!("Rejected move of item with id {:?}: {}", self.item, error);
+                world.send_event(ItemMoveFailed { request: self, error });
+            }
+        }
+    }
+}
+
+impl Command for SplitStackRequest {
+    fn apply(self, world: &mut World) {
+        match split_stack(world, self) {
+            Ok(split) => {
+                world.send_event(split);
+            }
+            Err(error) => warn!("Rejected split of item with id {:?}: {}", self.item, error),
+        }
+    }
+}
+
+impl Command for MergeStacksRequest {
+    fn apply(self, world: &mut World) {
+        match merge_stacks(world, self) {
+            Ok(merged) => {
+                world.send_event(merged);
+            }
+            Err(error) => warn!("Rejected merge of item with id {:?}: {}", self.source, error),
+        }
+    }
+}
+
+// Puts a freshly spawned, loose item into a container. If it can't go in, e.g. because it's too
+// heavy, it's despawned along with its contents instead of being left lying around nowhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsertItem {
+    pub item: Entity,
+    pub container: Entity,
+}
+
+impl Command for InsertItem {
+    fn apply(self, world: &mut World) {
+        let request = MoveItemRequest {
+            item: self.item,
+            from: None,
+            to: Some(self.container),
+            quantity: None,
+        };
+        match move_item(world, request) {
+            Ok(moved) => {
+                world.send_event(moved);
+            }
+            Err(error) => {
+                let name = world.get::<Name>(self.item).map_or_else(String::new, |name| name.to_string());
+                warn!(
+                    "Discarded item {} with id {:?} that couldn't go into {:?}: {}",
+                    name,
+                    self.item,
+                    self.container,
+                    error
+                );
+                despawn_with_contents(world, self.item);
+            }
+        }
+    }
+}
+
+// Requests sent as events are queued as commands so they get the same all-or-nothing handling.
+fn queue_requests<R: Event + Command + Copy>(mut requests: EventReader<R>, mut commands: Commands) {
+    for request in requests.read() {
+        commands.queue(*request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(world: &mut World, weight: f32, weight_limit: f32) -> Entity {
+        world
+            .spawn((
+                Item,
+                Name::new("bag"),
+                Weight(weight),
+                Inventory { weight_limit, ..Default::default() },
+            ))
+            .id()
+    }
+
+    fn item(world: &mut World, name: &'static str, weight: f32) -> Entity {
+        world.spawn((Item, Name::new(name), Weight(weight))).id()
+    }
+
+    fn stack(world: &mut World, name: &'static str, weight: f32, quantity: u32, max_stack: u32) -> Entity {
+        world
+            .spawn((
+                Item,
+                Name::new(name),
+                Weight(weight),
+                Quantity(quantity),
+                MaxStack(max_stack),
+                ItemProperties::default(),
+            ))
+            .id()
+    }
+
+    fn move_into(
+        world: &mut World,
+        item: Entity,
+        from: Option<Entity>,
+        to: Option<Entity>
+    ) -> Result<ItemMoved, TransferError> {
+        move_item(world, MoveItemRequest { item, from, to, quantity: None })
+    }
+
+    fn contains(world: &World, container: Entity, item: Entity) -> bool {
+        world.get::<Inventory>(container).unwrap().items.contains(&item)
+    }
+
+    fn weight_of(world: &World, container: Entity) -> f32 {
+        world.get::<Inventory>(container).unwrap().current_weight
+    }
+
+    #[test]
+    fn transfers_keep_both_sides_in_sync() {
+        let mut world = World::new();
+        let backpack = container(&mut world, 2.0, 50.0);
+        let chest = container(&mut world, 20.0, 100.0);
+        let sword = item(&mut world, "sword", 1.0);
+
+        move_into(&mut world, sword, None, Some(backpack)).unwrap();
+        assert!(contains(&world, backpack, sword));
+        assert_eq!(world.get::<ParentContainer>(sword), Some(&ParentContainer(backpack)));
+
+        let moved = move_into(&mut world, sword, Some(backpack), Some(chest)).unwrap();
+        assert_eq!(moved, ItemMoved {
+            item: sword,
+            from: Some(backpack),
+            to: Some(chest),
+            quantity: 1,
+            merged_into: Vec::new(),
+        });
+        assert!(!contains(&world, backpack, sword));
+        assert!(contains(&world, chest, sword));
+        assert_eq!(world.get::<ParentContainer>(sword), Some(&ParentContainer(chest)));
+
+        move_into(&mut world, sword, Some(chest), None).unwrap();
+        assert!(!contains(&world, chest, sword));
+        assert!(world.get::<ParentContainer>(sword).is_none());
+    }
+
+    #[test]
+    fn invalid_transfers_are_rejected() {
+        let mut world = World::new();
+        let backpack = container(&mut world, 1.0, 100.0);
+        let pouch = container(&mut world, 0.5, 100.0);
+        let bag = container(&mut world, 0.1, 100.0);
+        let sword = item(&mut world, "sword", 1.0);
+        move_into(&mut world, pouch, None, Some(backpack)).unwrap();
+
+        assert_eq!(move_into(&mut world, sword, None, None), Err(TransferError::NothingToMove));
+        assert_eq!(
+            move_into(&mut world, sword, None, Some(sword)),
+            Err(TransferError::MissingContainer(sword))
+        );
+        assert_eq!(
+            move_into(&mut world, backpack, None, Some(pouch)),
+            Err(TransferError::WouldContainItself { item: backpack, target: pouch })
+        );
+        assert_eq!(
+            move_into(&mut world, pouch, None, Some(bag)),
+            Err(TransferError::ItemNotInSource { item: pouch, source: None })
+        );
+        assert_eq!(
+            move_into(&mut world, sword, Some(pouch), Some(bag)),
+            Err(TransferError::ItemNotInSource { item: sword, source: Some(pouch) })
+        );
+        assert!(world.get::<ParentContainer>(sword).is_none());
+        assert_eq!(world.get::<ParentContainer>(pouch), Some(&ParentContainer(backpack)));
+    }
+
+    #[test]
+    fn weight_is_cached_up_the_whole_chain() {
+        let mut world = World::new();
+        let backpack = container(&mut world, 2.0, 50.0);
+        let pouch = container(&mut world, 0.5, 10.0);
+        let chest = container(&mut world, 20.0, 100.0);
+        let rock = item(&mut world, "rock", 6.0);
+
+        move_into(&mut world, pouch, None, Some(backpack)).unwrap();
+        move_into(&mut world, rock, None, Some(pouch)).unwrap();
+        assert_eq!(container_chain(&world, pouch), [pouch, backpack]);
+        assert_eq!(weight_of(&world, pouch), 6.0);
+        assert_eq!(weight_of(&world, backpack), 6.5);
+
+        // The pouch takes its contents along, so the weight moves with it.
+        move_into(&mut world, pouch, Some(backpack), Some(chest)).unwrap();
+        assert_eq!(container_chain(&world, pouch), [pouch, chest]);
+        assert_eq!(weight_of(&world, backpack), 0.0);
+        assert_eq!(weight_of(&world, chest), 6.5);
+        assert_eq!(total_weight(&world, chest), 26.5);
+
+        move_into(&mut world, rock, Some(pouch), None).unwrap();
+        assert_eq!(weight_of(&world, pouch), 0.0);
+        assert_eq!(weight_of(&world, chest), 0.5);
+    }
+
+    #[test]
+    fn every_container_up_the_chain_needs_room() {
+        let mut world = World::new();
+        let backpack = container(&mut world, 1.0, 5.0);
+        let pouch = container(&mut world, 0.5, 100.0);
+        let anvil = item(&mut world, "anvil", 10.0);
+        move_into(&mut world, pouch, None, Some(backpack)).unwrap();
+
+        // The pouch itself has room, the backpack holding it doesn't.
+        let result = move_into(&mut world, anvil, None, Some(pouch));
+        assert!(
+            matches!(result, Err(TransferError::OverCapacity { container, .. }) if container == backpack)
+        );
+        assert!(world.get::<ParentContainer>(anvil).is_none());
+        assert_eq!(weight_of(&world, pouch), 0.0);
+        assert_eq!(weight_of(&world, backpack), 0.5);
+
+        // Exactly at the limit is fine.
+        let brick = item(&mut world, "brick", 4.5);
+        move_into(&mut world, brick, None, Some(pouch)).unwrap();
+        assert_eq!(weight_of(&world, backpack), 5.0);
+        assert_eq!(world.get::<Inventory>(backpack).unwrap().remaining_weight(), 0.0);
+    }
+
+    #[test]
+    fn stacks_merge_on_entry_and_overflow_into_a_new_stack() {
+        let mut world = World::new();
+        let bag = container(&mut world, 0.0, 100.0);
+        let arrows = stack(&mut world, "arrow", 0.1, 15, 20);
+        let more_arrows = stack(&mut world, "arrow", 0.1, 10, 20);
+        let bolts = stack(&mut world, "bolt", 0.1, 5, 20);
+
+        move_into(&mut world, arrows, None, Some(bag)).unwrap();
+        move_into(&mut world, bolts, None, Some(bag)).unwrap();
+        let moved = move_into(&mut world, more_arrows, None, Some(bag)).unwrap();
+        assert_eq!(moved.merged_into, [arrows]);
+        assert_eq!(world.get::<Quantity>(arrows), Some(&Quantity(20)));
+        assert_eq!(world.get::<Quantity>(more_arrows), Some(&Quantity(5)));
+        assert_eq!(world.get::<Quantity>(bolts), Some(&Quantity(5)));
+        assert_eq!(world.get::<Inventory>(bag).unwrap().items.len(), 3);
+        assert!((weight_of(&world, bag) - 3.0).abs() < 1e-5);
+
+        // Fits completely, so the moved stack is gone.
+        let loose = stack(&mut world, "bolt", 0.1, 3, 20);
+        let moved = move_into(&mut world, loose, None, Some(bag)).unwrap();
+        assert_eq!(moved.merged_into, [bolts]);
+        assert!(world.get_entity(loose).is_err());
+        assert_eq!(world.get::<Quantity>(bolts), Some(&Quantity(8)));
+    }
+
+    #[test]
+    fn partial_moves_split_the_stack() {
+        let mut world = World::new();
+        let bag = container(&mut world, 0.0, 100.0);
+        let coins = stack(&mut world, "coin", 0.01, 50, 100);
+
+        let moved = move_item(&mut world, MoveItemRequest {
+            item: coins,
+            from: None,
+            to: Some(bag),
+            quantity: Some(20),
+        }).unwrap();
+        assert_ne!(moved.item, coins);
+        assert_eq!(moved.quantity, 20);
+        assert_eq!(world.get::<Quantity>(coins), Some(&Quantity(30)));
+        assert_eq!(world.get::<Name>(moved.item), world.get::<Name>(coins));
+        assert!(contains(&world, bag, moved.item));
+        assert!((weight_of(&world, bag) - 0.2).abs() < 1e-5);
+
+        assert_eq!(
+            move_item(&mut world, MoveItemRequest {
+                item: coins,
+                from: None,
+                to: Some(bag),
+                quantity: Some(31),
+            }),
+            Err(TransferError::InvalidQuantity { item: coins, requested: 31, available: 30 })
+        );
+    }
+
+    #[test]
+    fn split_and_merge_by_hand() {
+        let mut world = World::new();
+        let bag = container(&mut world, 0.0, 100.0);
+        let potions = stack(&mut world, "potion", 0.25, 5, 5);
+        let sword = stack(&mut world, "sword", 1.0, 1, 1);
+        move_into(&mut world, potions, None, Some(bag)).unwrap();
+        let weight = weight_of(&world, bag);
+
+        let split = split_stack(&mut world, SplitStackRequest { item: potions, quantity: 2 }).unwrap();
+        assert_eq!(world.get::<Quantity>(potions), Some(&Quantity(3)));
+        assert_eq!(world.get::<Quantity>(split.new_stack), Some(&Quantity(2)));
+        assert!(contains(&world, bag, split.new_stack));
+        assert_eq!(weight_of(&world, bag), weight);
+
+        let merged = merge_stacks(&mut world, MergeStacksRequest {
+            source: split.new_stack,
+            target: potions,
+        }).unwrap();
+        assert_eq!(merged.quantity, 2);
+        assert!(world.get_entity(split.new_stack).is_err());
+        assert!(!contains(&world, bag, split.new_stack));
+        assert_eq!(world.get::<Quantity>(potions), Some(&Quantity(5)));
+
+        assert!(!can_stack(&world, potions, sword));
+        assert_eq!(
+            merge_stacks(&mut world, MergeStacksRequest { source: sword, target: potions }),
+            Err(TransferError::NotStackable { item: sword, other: potions })
+        );
+        let loose = stack(&mut world, "potion", 0.25, 1, 5);
+        assert_eq!(
+            merge_stacks(&mut world, MergeStacksRequest { source: loose, target: potions }),
+            Err(TransferError::DifferentContainers { item: loose, other: potions })
+        );
+    }
+}
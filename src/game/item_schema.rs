@@ -0,0 +1,232 @@
+// Describes which item properties exist and what they may contain, so typos and
+// wrong types in the item definition files are caught at load instead of in gameplay.
+
+#[cfg(feature = "server")]
+use bevy::prelude::Resource;
+use bevy::asset::ron;
+use serde::Deserialize;
+use rustc_hash::{ FxHashMap, FxHashSet };
+use std::fmt;
+use std::path::Path;
+
+use crate::utils::common::{ PropertyKind, PropertyValue, Tags };
+use crate::game::items::ItemProperties;
+
+// Where the property schema lives. Deliberately outside of the item definitions directory.
+pub const PROPERTY_SCHEMA_PATH: &str = "assets/item_properties.ron";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PropertySchema {
+    pub kind: PropertyKind,
+    // Inclusive bounds, only checked for Int and Float properties.
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+    // Any item with one of these tags has to define the property.
+    #[serde(default)]
+    pub required_by: FxHashSet<String>,
+}
+
+// Every property key an item is allowed to use, keyed by its name.
+#[cfg_attr(feature = "server", derive(Resource))]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct PropertySchemaRegistry(pub FxHashMap<String, PropertySchema>);
+
+// Ways an item's properties can disagree with the schema.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    UnknownProperty {
+        key: String,
+    },
+    WrongKind {
+        key: String,
+        expected: PropertyKind,
+        found: PropertyKind,
+    },
+    OutOfRange {
+        key: String,
+        value: f64,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    MissingRequired {
+        key: String,
+        tag: String,
+    },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::UnknownProperty { key } => {
+                write!(f, "unknown property \"{}\"", key)
+            }
+            SchemaError::WrongKind { key, expected, found } => {
+                write!(f, "property \"{}\" should be {:?} but is {:?}", key, expected, found)
+            }
+            SchemaError::OutOfRange { key, value, min, max } => {
+                let min = min.map_or("-inf".to_string(), |min| min.to_string());
+                let max = max.map_or("inf".to_string(), |max| max.to_string());
+                write!(f, "property \"{}\" is {} which is outside [{}, {}]", key, value, min, max)
+            }
+            SchemaError::MissingRequired { key, tag } => {
+                write!(f, "property \"{}\" is required by tag \"{}\" but missing", key, tag)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl PropertySchemaRegistry {
+    // Reads the registry from a RON file, returning a readable message on failure.
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs
+            ::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        ron::de
+            ::from_str(&contents)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))
+    }
+
+    // Checks a set of properties against the schema, returning every problem rather than just the first.
+    pub fn validate(&self, tags: &Tags, properties: &ItemProperties) -> Vec<SchemaError> {
+        let mut errors = Vec::new();
+
+        for (key, value) in &properties.0 {
+            let Some(schema) = self.0.get(key) else {
+                errors.push(SchemaError::UnknownProperty { key: key.clone() });
+                continue;
+            };
+
+            if value.kind() != schema.kind {
+                errors.push(SchemaError::WrongKind {
+                    key: key.clone(),
+                    expected: schema.kind,
+                    found: value.kind(),
+                });
+                continue;
+            }
+
+            let number = match value {
+                PropertyValue::Int(val) => Some(*val as f64),
+                PropertyValue::Float(val) => Some(*val as f64),
+                _ => None,
+            };
+            if let Some(number) = number {
+                let too_low = schema.min.is_some_and(|min| number < min);
+                let too_high = schema.max.is_some_and(|max| number > max);
+                if too_low || too_high {
+                    errors.push(SchemaError::OutOfRange {
+                        key: key.clone(),
+                        value: number,
+                        min: schema.min,
+                        max: schema.max,
+                    });
+                }
+            }
+        }
+
+        for (key, schema) in &self.0 {
+            if properties.0.contains_key(key) {
+                continue;
+            }
+            if let Some(tag) = schema.required_by.iter().find(|tag| tags.0.contains(*tag)) {
+                errors.push(SchemaError::MissingRequired { key: key.clone(), tag: tag.clone() });
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> PropertySchemaRegistry {
+        ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(
+                r#"{
+                    "Damage": (kind: Int, min: 0.0, max: 100.0, required_by: ["Weapon"]),
+                    "Weight": (kind: Float, min: 0.5),
+                    "Label": (kind: Text),
+                }"#
+            )
+            .unwrap()
+    }
+
+    fn tags(tags: &[&str]) -> Tags {
+        Tags(tags.iter().map(|tag| tag.to_string()).collect())
+    }
+
+    fn properties(entries: &[(&str, PropertyValue)]) -> ItemProperties {
+        ItemProperties(
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect()
+        )
+    }
+
+    #[test]
+    fn valid_properties_pass() {
+        let errors = schema().validate(
+            &tags(&["Weapon"]),
+            &properties(
+                &[
+                    ("Damage", PropertyValue::Int(100)),
+                    ("Weight", PropertyValue::Float(0.5)),
+                    ("Label", PropertyValue::Text("Sharp".into())),
+                ]
+            )
+        );
+        assert_eq!(errors, []);
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut errors = schema().validate(
+            &tags(&["Weapon"]),
+            &properties(
+                &[
+                    ("Dmage", PropertyValue::Int(10)),
+                    ("Label", PropertyValue::Int(3)),
+                    ("Weight", PropertyValue::Float(0.25)),
+                ]
+            )
+        );
+        errors.sort_by_key(|err| err.to_string());
+
+        assert_eq!(errors, [
+            SchemaError::MissingRequired { key: "Damage".into(), tag: "Weapon".into() },
+            SchemaError::WrongKind {
+                key: "Label".into(),
+                expected: PropertyKind::Text,
+                found: PropertyKind::Int,
+            },
+            SchemaError::OutOfRange { key: "Weight".into(), value: 0.25, min: Some(0.5), max: None },
+            SchemaError::UnknownProperty { key: "Dmage".into() },
+        ]);
+    }
+
+    #[test]
+    fn bounds_are_inclusive() {
+        let schema = schema();
+        let damage = |value| schema.validate(&tags(&[]), &properties(&[("Damage", PropertyValue::Int(value))]));
+        assert_eq!(damage(0), []);
+        assert_eq!(damage(100), []);
+        assert!(matches!(damage(-1).as_slice(), [SchemaError::OutOfRange { .. }]));
+        assert!(matches!(damage(101).as_slice(), [SchemaError::OutOfRange { .. }]));
+    }
+
+    #[test]
+    fn shipped_schema_parses() {
+        let schema = PropertySchemaRegistry::from_file(Path::new(PROPERTY_SCHEMA_PATH)).unwrap();
+        assert_eq!(schema.0["Damage"].kind, PropertyKind::Int);
+        assert!(schema.0["Damage"].required_by.contains("Weapon"));
+    }
+}
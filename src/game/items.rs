@@ -1,20 +1,56 @@
 #[cfg(feature = "server")]
 use bevy::{
     app::{ PreStartup, Startup },
-    prelude::{ Commands, Res, ResMut, Resource, EntityCommands },
+    prelude::{ Commands, Res, ResMut, Resource, EntityCommands, not, resource_exists },
 };
 use bevy::app::PostStartup;
-use bevy::prelude::{ Plugin, Component, Reflect, Entity, Name, App, info, warn, Query, With };
+use bevy::asset::ron;
+use bevy::prelude::{
+    Plugin,
+    Component,
+    Reflect,
+    Entity,
+    Name,
+    App,
+    IntoSystemConfigs,
+    info,
+    warn,
+    error,
+    Query,
+    With,
+};
 use bevy::utils::Instant;
-use serde::Deserialize;
+use serde::de::{ Deserializer, MapAccess, Visitor };
+use bevy::ecs::entity::{ EntityMapper, MapEntities };
+use serde::{ Deserialize, Serialize };
 use rustc_hash::{ FxHashMap, FxHashSet };
+use std::fmt;
+use std::path::{ Path, PathBuf };
+#[cfg(feature = "server")]
+use bevy::math::Vec3A;
 #[cfg(feature = "server")]
-use bevy_rand::prelude::{ GlobalEntropy, WyRand, ForkableRng };
+use bevy_rand::prelude::{ GlobalEntropy, Entropy, WyRand, ForkableRng };
 #[cfg(feature = "server")]
 use rand_core::RngCore;
 
-use crate::utils::common::{ PropertyValue, Tags, Weight, DisplayName, Icon, choose_random };
-use crate::{ fxhashset, fxhashmap };
+use crate::utils::common::{
+    PropertyKind,
+    PropertyValue,
+    Tags,
+    Weight,
+    DisplayName,
+    Icon,
+};
+#[cfg(feature = "server")]
+use crate::game::inventory::{ InventoryPlugin, MoveItemRequest };
+#[cfg(feature = "server")]
+use crate::game::loot::{ LootTables, initialize_loot_tables, roll_loot_table };
+#[cfg(feature = "server")]
+use crate::game::save::WorldRestored;
+#[cfg(feature = "server")]
+use crate::network::protocol::PlayerPosition;
+use crate::game::item_schema::{ PropertySchemaRegistry, SchemaError, PROPERTY_SCHEMA_PATH };
+use crate::fxhashset;
 
 pub struct ItemsPlugin;
 
@@ -26,44 +62,174 @@ impl Plugin for ItemsPlugin {
             items: Default::default(),
         });
         #[cfg(feature = "server")]
-        app.add_systems(PreStartup, initialize_item_storage);
+        app.insert_resource(PropertySchemaRegistry::default());
         #[cfg(feature = "server")]
-        app.add_systems(Startup, (spawn_sword, generate_container_items));
+        app.add_plugins(InventoryPlugin);
+        #[cfg(feature = "server")]
+        app.insert_resource(LootTables::default());
+        #[cfg(feature = "server")]
+        app.add_systems(PreStartup, (initialize_item_storage, initialize_loot_tables).chain());
+        // A restored world already has its items, generating more would pile up a new set every restart.
+        #[cfg(feature = "server")]
+        app.add_systems(
+            Startup,
+            (spawn_sword, generate_container_items).run_if(not(resource_exists::<WorldRestored>))
+        );
         app.add_systems(PostStartup, fetch_item_info);
     }
 }
 
-#[derive(Component, Debug, Reflect)]
-#[require(Name, DisplayName, Weight, Icon, Tags, ItemProperties)]
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize, Reflect)]
+#[require(Name, DisplayName, Weight, Icon, Tags, ItemProperties, Quantity, MaxStack)]
 pub struct Item;
 
-#[derive(Component, Debug, Clone, Deserialize)]
-pub struct ItemProperties(pub FxHashMap<String, PropertyValue>);
+// How many of an item this entity stands for. Weight is per unit, so a stack weighs Weight * Quantity.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Reflect)]
+pub struct Quantity(pub u32);
 
-impl Default for ItemProperties {
+impl Default for Quantity {
     fn default() -> Self {
-        Self(Default::default())
+        Self(1)
     }
 }
 
-#[derive(Component, Debug, Clone)]
+// How many units fit in a single stack. 1 means the item never stacks.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Reflect)]
+pub struct MaxStack(pub u32);
+
+impl Default for MaxStack {
+    fn default() -> Self {
+        Self(1)
+    }
+}
+
+#[derive(Component, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ItemProperties(pub FxHashMap<String, PropertyValue>);
+
+// Returned by the typed getters on ItemProperties.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyError {
+    Missing {
+        key: String,
+    },
+    WrongKind {
+        key: String,
+        expected: PropertyKind,
+        found: PropertyKind,
+    },
+}
+
+impl fmt::Display for PropertyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyError::Missing { key } => write!(f, "item has no property \"{}\"", key),
+            PropertyError::WrongKind { key, expected, found } => {
+                write!(f, "property \"{}\" is {:?}, not {:?}", key, found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PropertyError {}
+
+impl ItemProperties {
+    fn get(&self, key: &str, expected: PropertyKind) -> Result<&PropertyValue, PropertyError> {
+        let value = self.0.get(key).ok_or_else(|| PropertyError::Missing { key: key.to_string() })?;
+        if value.kind() != expected {
+            return Err(PropertyError::WrongKind {
+                key: key.to_string(),
+                expected,
+                found: value.kind(),
+            });
+        }
+        Ok(value)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<bool, PropertyError> {
+        match self.get(key, PropertyKind::Bool)? {
+            PropertyValue::Bool(val) => Ok(*val),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<i32, PropertyError> {
+        match self.get(key, PropertyKind::Int)? {
+            PropertyValue::Int(val) => Ok(*val),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_float(&self, key: &str) -> Result<f32, PropertyError> {
+        match self.get(key, PropertyKind::Float)? {
+            PropertyValue::Float(val) => Ok(*val),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_text(&self, key: &str) -> Result<&str, PropertyError> {
+        match self.get(key, PropertyKind::Text)? {
+            PropertyValue::Text(val) => Ok(val.as_str()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// Only ever change `items` through the transfer API in game::inventory, otherwise
+// ParentContainer, the inventory contents and the cached weight will drift apart.
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Inventory {
     pub weight_limit: f32,
     pub items: FxHashSet<Entity>,
+    // Total weight of everything inside, including the contents of nested containers.
+    // Kept up to date incrementally by the transfer API so nothing has to sum it per query.
+    pub current_weight: f32,
 }
 
+// No limit unless one is set explicitly.
 impl Default for Inventory {
     fn default() -> Self {
-        Self { weight_limit: 0.0, items: Default::default() }
+        Self { weight_limit: f32::INFINITY, items: Default::default(), current_weight: 0.0 }
     }
 }
 
-#[derive(Component, Debug, Reflect)]
+impl Inventory {
+    pub fn remaining_weight(&self) -> f32 {
+        (self.weight_limit - self.current_weight).max(0.0)
+    }
+
+    // How full the inventory is, 1.0 being at the limit. Meant for driving encumbrance.
+    pub fn load_ratio(&self) -> f32 {
+        if self.weight_limit.is_finite() && self.weight_limit > 0.0 {
+            self.current_weight / self.weight_limit
+        } else {
+            0.0
+        }
+    }
+}
+
+// Needed so the entities in `items` point at the right thing once they're replicated to a client.
+impl MapEntities for Inventory {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        self.items = self.items
+            .drain()
+            .map(|item| entity_mapper.map_entity(item))
+            .collect();
+    }
+}
+
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize, Reflect)]
 #[require(Inventory)]
 pub struct Container;
 
-#[derive(Component, Debug, Reflect)]
-pub struct ParentContainer(Entity);
+#[derive(Component, Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Reflect)]
+pub struct ParentContainer(pub Entity);
+
+impl MapEntities for ParentContainer {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        self.0 = entity_mapper.map_entity(self.0);
+    }
+}
 
 // This will be the spawn dictionary. Everything that can be spawned in is defined here
 #[cfg(feature = "server")]
@@ -72,69 +238,478 @@ pub struct ItemStorage {
     pub items: FxHashMap<Name, RawItemData>,
 }
 
-// Primarily to clean things up and deserialize from files.
+// Fully resolved item data, i.e. with everything inherited from its parents already applied.
 #[cfg(feature = "server")]
 #[derive(Debug, Clone, Deserialize)]
 pub struct RawItemData {
+    // The template this item was derived from, if any. Kept around so e.g. "is this a sword" can be answered.
+    #[serde(default, alias = "extends")]
+    pub parent: Option<String>,
     pub display_name: DisplayName,
     pub weight: Weight,
+    #[serde(default)]
     pub icon: Icon,
+    #[serde(default)]
+    pub tags: Tags,
+    #[serde(default)]
+    pub properties: ItemProperties,
+    #[serde(default = "default_max_stack")]
+    pub max_stack: u32,
+    // How much weight the item can hold, if it's a bag or anything else that holds items.
+    #[serde(default)]
+    pub capacity: Option<f32>,
+}
+
+#[cfg(feature = "server")]
+fn default_max_stack() -> u32 {
+    1
+}
+
+// What an item definition file actually contains. Anything left out is inherited from `parent`,
+// so only items without a parent need to define everything themselves.
+// Tags and properties are merged with the parent's rather than replacing them.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ItemTemplate {
+    #[serde(default, alias = "extends")]
+    pub parent: Option<String>,
+    // Abstract templates only exist to be inherited from, and never end up in the spawn dictionary.
+    #[serde(default, rename = "abstract")]
+    pub is_abstract: bool,
+    pub display_name: Option<DisplayName>,
+    pub weight: Option<Weight>,
+    pub icon: Option<Icon>,
+    pub max_stack: Option<u32>,
+    pub capacity: Option<f32>,
+    #[serde(default)]
     pub tags: Tags,
+    #[serde(default)]
+    pub remove_tags: Tags,
+    #[serde(default)]
     pub properties: ItemProperties,
+    #[serde(default)]
+    pub remove_properties: FxHashSet<String>,
 }
 
-// Placeholder to just define a couple of things to insert into the dictionary. Will essentially be the same after though.
+// Every .ron and .json file in here (and its subdirectories) is read into the spawn dictionary.
 #[cfg(feature = "server")]
-fn initialize_item_storage(commands: Commands, mut item_storage: ResMut<ItemStorage>) {
-    // TODO: Add deserialization and reading from JSON
-
-    let items_data = vec![
-        (
-            "sword",
-            RawItemData {
-                display_name: DisplayName("Sword".to_string()),
-                weight: Weight(1.0),
-                icon: Icon("Icon_Sword".to_string()),
-                tags: Tags(fxhashset!["Weapon".to_string()]),
-                properties: ItemProperties({
-                    fxhashmap! { String::from("Damage") => PropertyValue::Int(30) }
-                }),
-            },
-        ),
-        (
-            "shield",
-            RawItemData {
-                display_name: DisplayName("Shield".to_string()),
-                weight: Weight(5.0),
-                icon: Icon("Icon_Shield".to_string()),
-                tags: Tags(fxhashset!["Armor".to_string()]),
-                properties: ItemProperties({
-                    fxhashmap! { String::from("Defense") => PropertyValue::Int(50) }
-                }),
-            },
-        ),
-        (
-            "potion",
-            RawItemData {
-                display_name: DisplayName("Potion".to_string()),
-                weight: Weight(0.25),
-                icon: Icon("Icon_Potion".to_string()),
-                tags: Tags(fxhashset!["Healing".to_string(), "Consumable".to_string()]),
-                properties: ItemProperties({
-                    fxhashmap! { String::from("Healing") => PropertyValue::Int(30) }
-                }),
-            },
-        )
-    ];
+pub const ITEM_DEFINITIONS_DIR: &str = "assets/items";
+
+// Anything that can go wrong with a single definition file. None of these stop the rest from loading.
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub enum ItemLoadError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    DuplicateId {
+        id: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    UnknownParent {
+        id: String,
+        parent: String,
+    },
+    BrokenParent {
+        id: String,
+        parent: String,
+    },
+    InheritanceCycle {
+        chain: Vec<String>,
+    },
+    MissingField {
+        id: String,
+        field: &'static str,
+    },
+    InvalidProperties {
+        id: String,
+        errors: Vec<SchemaError>,
+    },
+}
+
+#[cfg(feature = "server")]
+impl fmt::Display for ItemLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemLoadError::Io { path, source } => {
+                write!(f, "Failed to read {}: {}", path.display(), source)
+            }
+            ItemLoadError::Parse { path, message } => {
+                write!(f, "Failed to parse {}: {}", path.display(), message)
+            }
+            ItemLoadError::DuplicateId { id, first, second } => {
+                write!(
+                    f,
+                    "Item id \"{}\" in {} is already defined in {}, skipping it",
+                    id,
+                    second.display(),
+                    first.display()
+                )
+            }
+            ItemLoadError::UnknownParent { id, parent } => {
+                write!(f, "Item \"{}\" extends \"{}\", which doesn't exist", id, parent)
+            }
+            ItemLoadError::BrokenParent { id, parent } => {
+                write!(f, "Item \"{}\" skipped because its parent \"{}\" failed to load", id, parent)
+            }
+            ItemLoadError::InheritanceCycle { chain } => {
+                write!(f, "Item inheritance cycle: {}", chain.join(" -> "))
+            }
+            ItemLoadError::MissingField { id, field } => {
+                write!(f, "Item \"{}\" has no {} and no parent to inherit it from", id, field)
+            }
+            ItemLoadError::InvalidProperties { id, errors } => {
+                let errors: Vec<String> = errors
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect();
+                write!(f, "Item \"{}\" skipped: {}", id, errors.join("; "))
+            }
+        }
+    }
+}
 
+#[cfg(feature = "server")]
+impl std::error::Error for ItemLoadError {}
+
+// A definition file is a map of item ids to their data. Deserializing straight into a hashmap
+// would silently drop repeated ids, so the entries are kept in order and checked afterwards.
+#[cfg(feature = "server")]
+struct ItemDefinitionFile(Vec<(String, ItemTemplate)>);
+
+#[cfg(feature = "server")]
+impl<'de> Deserialize<'de> for ItemDefinitionFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FileVisitor;
+
+        impl<'de> Visitor<'de> for FileVisitor {
+            type Value = ItemDefinitionFile;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of item ids to item definitions")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(ItemDefinitionFile(entries))
+            }
+        }
+
+        deserializer.deserialize_map(FileVisitor)
+    }
+}
+
+// Result of reading the whole definitions directory.
+#[cfg(feature = "server")]
+#[derive(Default)]
+pub struct LoadedItemDefinitions {
+    pub items: FxHashMap<Name, RawItemData>,
+    pub files_loaded: usize,
+    pub errors: Vec<ItemLoadError>,
+}
+
+// Reads a single definition file, picking the format from its extension.
+// Returns None for files that aren't item definitions at all.
+#[cfg(feature = "server")]
+fn read_item_file(path: &Path) -> Option<Result<ItemDefinitionFile, ItemLoadError>> {
+    let extension = path.extension().and_then(|ext| ext.to_str())?;
+    if extension != "ron" && extension != "json" {
+        return None;
+    }
+
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(source) => {
+            return Some(Err(ItemLoadError::Io { path: path.to_path_buf(), source }));
+        }
+    };
+
+    let parsed = if extension == "ron" {
+        // Templates leave most fields optional, so let the files write `weight: 1.0` instead of `Some(1.0)`.
+        ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(&contents)
+            .map_err(|err| err.to_string())
+    } else {
+        serde_json::from_str(&contents).map_err(|err| err.to_string())
+    };
+
+    Some(parsed.map_err(|message| ItemLoadError::Parse { path: path.to_path_buf(), message }))
+}
+
+// Collects every file under `dir`, including subdirectories.
+#[cfg(feature = "server")]
+fn collect_item_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), ItemLoadError> {
+    let entries = std::fs::read_dir(dir).map_err(|source| ItemLoadError::Io {
+        path: dir.to_path_buf(),
+        source,
+    })?;
+
+    for entry in entries {
+        let path = entry
+            .map_err(|source| ItemLoadError::Io { path: dir.to_path_buf(), source })?
+            .path();
+        if path.is_dir() {
+            collect_item_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Flattens a template onto its (already resolved) parent.
+#[cfg(feature = "server")]
+fn apply_template(
+    id: &str,
+    template: &ItemTemplate,
+    parent: Option<&RawItemData>
+) -> Result<RawItemData, ItemLoadError> {
+    let display_name = template.display_name
+        .clone()
+        .or_else(|| parent.map(|p| p.display_name.clone()))
+        .ok_or(ItemLoadError::MissingField { id: id.to_string(), field: "display_name" })?;
+    let weight = template.weight
+        .clone()
+        .or_else(|| parent.map(|p| p.weight.clone()))
+        .ok_or(ItemLoadError::MissingField { id: id.to_string(), field: "weight" })?;
+    let icon = template.icon
+        .clone()
+        .or_else(|| parent.map(|p| p.icon.clone()))
+        .unwrap_or_default();
+    let max_stack = template.max_stack
+        .or_else(|| parent.map(|p| p.max_stack))
+        .unwrap_or_else(default_max_stack)
+        .max(1);
+    let capacity = template.capacity.or_else(|| parent.and_then(|p| p.capacity));
+
+    let mut tags = parent.map(|p| p.tags.clone()).unwrap_or_default();
+    tags.0.retain(|tag| !template.remove_tags.0.contains(tag));
+    tags.0.extend(template.tags.0.iter().cloned());
+
+    let mut properties = parent.map(|p| p.properties.clone()).unwrap_or_default();
+    properties.0.retain(|key, _| !template.remove_properties.contains(key));
+    properties.0.extend(template.properties.0.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    Ok(RawItemData {
+        parent: template.parent.clone(),
+        display_name,
+        weight,
+        icon,
+        tags,
+        properties,
+        max_stack,
+        capacity,
+    })
+}
+
+// Resolves a single template and, recursively, everything it inherits from.
+// `stack` holds the chain currently being resolved, which is how cycles are caught.
+// Failures are stored as None so every broken item is only reported once.
+#[cfg(feature = "server")]
+fn resolve_template(
+    id: &str,
+    templates: &FxHashMap<String, ItemTemplate>,
+    resolved: &mut FxHashMap<String, Option<RawItemData>>,
+    stack: &mut Vec<String>,
+    errors: &mut Vec<ItemLoadError>
+) {
+    if resolved.contains_key(id) {
+        return;
+    }
+    if let Some(start) = stack.iter().position(|entry| entry == id) {
+        let mut chain = stack[start..].to_vec();
+        chain.push(id.to_string());
+        errors.push(ItemLoadError::InheritanceCycle { chain });
+        // Everything in the loop is broken, mark it so nothing gets reported twice.
+        for entry in &stack[start..] {
+            resolved.insert(entry.clone(), None);
+        }
+        return;
+    }
+
+    let template = &templates[id];
+    let parent = match &template.parent {
+        None => None,
+        Some(parent_id) => {
+            if !templates.contains_key(parent_id) {
+                errors.push(ItemLoadError::UnknownParent {
+                    id: id.to_string(),
+                    parent: parent_id.clone(),
+                });
+                resolved.insert(id.to_string(), None);
+                return;
+            }
+
+            stack.push(id.to_string());
+            resolve_template(parent_id, templates, resolved, stack, errors);
+            stack.pop();
+
+            // A cycle through this item may have already marked it while resolving the parent.
+            if resolved.contains_key(id) {
+                return;
+            }
+            match resolved.get(parent_id) {
+                Some(Some(parent)) => Some(parent.clone()),
+                _ => {
+                    errors.push(ItemLoadError::BrokenParent {
+                        id: id.to_string(),
+                        parent: parent_id.clone(),
+                    });
+                    resolved.insert(id.to_string(), None);
+                    return;
+                }
+            }
+        }
+    };
+
+    match apply_template(id, template, parent.as_ref()) {
+        Ok(data) => {
+            resolved.insert(id.to_string(), Some(data));
+        }
+        Err(err) => {
+            errors.push(err);
+            resolved.insert(id.to_string(), None);
+        }
+    }
+}
+
+// Turns the templates read from disk into the flat spawn dictionary, dropping abstract templates.
+#[cfg(feature = "server")]
+pub fn resolve_item_templates(
+    templates: &FxHashMap<String, ItemTemplate>,
+    errors: &mut Vec<ItemLoadError>
+) -> FxHashMap<Name, RawItemData> {
+    // Sorted so errors come out in the same order every run.
+    let mut ids: Vec<&String> = templates.keys().collect();
+    ids.sort();
+
+    let mut resolved = FxHashMap::default();
+    let mut stack = Vec::new();
+    for id in ids {
+        resolve_template(id, templates, &mut resolved, &mut stack, errors);
+    }
+
+    resolved
+        .into_iter()
+        .filter(|(id, _)| !templates[id].is_abstract)
+        .filter_map(|(id, data)| data.map(|data| (Name::new(id), data)))
+        .collect()
+}
+
+// Loads every item definition under `dir`. Broken files, duplicate ids and items that
+// don't match the property schema are reported in `errors` and skipped, so one bad file
+// doesn't take the rest of the items down with it. Without a schema nothing is validated.
+#[cfg(feature = "server")]
+pub fn load_item_definitions(
+    dir: &Path,
+    schema: Option<&PropertySchemaRegistry>
+) -> LoadedItemDefinitions {
+    let mut loaded = LoadedItemDefinitions::default();
+
+    let mut files = Vec::new();
+    if let Err(err) = collect_item_files(dir, &mut files) {
+        loaded.errors.push(err);
+        return loaded;
+    }
+    // Sorted so load order, and with it which duplicate wins, is stable between runs.
+    files.sort();
+
+    let mut templates: FxHashMap<String, ItemTemplate> = FxHashMap::default();
+    let mut sources: FxHashMap<String, PathBuf> = FxHashMap::default();
+    for path in files {
+        let file = match read_item_file(&path) {
+            Some(Ok(file)) => file,
+            Some(Err(err)) => {
+                loaded.errors.push(err);
+                continue;
+            }
+            None => {
+                continue;
+            }
+        };
+        loaded.files_loaded += 1;
+
+        for (id, template) in file.0 {
+            if let Some(first) = sources.get(&id) {
+                loaded.errors.push(ItemLoadError::DuplicateId {
+                    id,
+                    first: first.clone(),
+                    second: path.clone(),
+                });
+                continue;
+            }
+            sources.insert(id.clone(), path.clone());
+            templates.insert(id, template);
+        }
+    }
+
+    loaded.items = resolve_item_templates(&templates, &mut loaded.errors);
+
+    if let Some(schema) = schema {
+        let mut ids: Vec<Name> = loaded.items.keys().cloned().collect();
+        ids.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        for id in ids {
+            let data = &loaded.items[&id];
+            let errors = schema.validate(&data.tags, &data.properties);
+            if !errors.is_empty() {
+                loaded.items.remove(&id);
+                loaded.errors.push(ItemLoadError::InvalidProperties {
+                    id: id.to_string(),
+                    errors,
+                });
+            }
+        }
+    }
+
+    loaded
+}
+
+// Fills the spawn dictionary from the definition files on disk.
+#[cfg(feature = "server")]
+pub(crate) fn initialize_item_storage(
+    mut item_storage: ResMut<ItemStorage>,
+    mut schema: ResMut<PropertySchemaRegistry>
+) {
     let start = Instant::now();
-    for (name, data) in items_data {
-        let item_name = Name::new(name);
-        item_storage.items.insert(item_name, data);
+
+    // Without a schema we'd reject every property, so skip validation instead of dropping all items.
+    let schema = match PropertySchemaRegistry::from_file(Path::new(PROPERTY_SCHEMA_PATH)) {
+        Ok(loaded) => {
+            *schema = loaded;
+            Some(&*schema)
+        }
+        Err(err) => {
+            error!("{}. Item properties will not be validated.", err);
+            None
+        }
+    };
+    let loaded = load_item_definitions(Path::new(ITEM_DEFINITIONS_DIR), schema);
+
+    for err in &loaded.errors {
+        error!("{}", err);
     }
 
+    item_storage.items = loaded.items;
+
     let duration = start.elapsed();
-    info!("Item dictionary initialized with {} items in {:?}.", item_storage.items.len(), duration);
+    info!(
+        "Item dictionary initialized with {} items from {} files in {:?} ({} errors).",
+        item_storage.items.len(),
+        loaded.files_loaded,
+        duration,
+        loaded.errors.len()
+    );
 }
 
 // Will spawn an item using its id (bevy Name) and the spawn dictionary.
@@ -143,6 +718,17 @@ fn spawn_item(
     commands: &mut Commands,
     item_storage: &Res<ItemStorage>,
     name: &str
+) -> Option<Entity> {
+    spawn_item_stack(commands, item_storage, name, 1)
+}
+
+// Same as spawn_item, but for a whole stack. Quantity is clamped to the item's max stack size.
+#[cfg(feature = "server")]
+pub(crate) fn spawn_item_stack(
+    commands: &mut Commands,
+    item_storage: &Res<ItemStorage>,
+    name: &str,
+    quantity: u32
 ) -> Option<Entity> {
     let item_name = Name::new(name.to_string());
     if let Some(item) = item_storage.items.get(&item_name) {
@@ -156,8 +742,13 @@ fn spawn_item(
                 item.icon.clone(),
                 item.tags.clone(),
                 item.properties.clone(),
+                MaxStack(item.max_stack),
+                Quantity(quantity.clamp(1, item.max_stack)),
             ))
             .id();
+        if let Some(weight_limit) = item.capacity {
+            commands.entity(entity).insert(Inventory { weight_limit, ..Default::default() });
+        }
         info!("Spawned item {} with id {}", item_name, entity);
         Some(entity)
     } else {
@@ -165,70 +756,52 @@ fn spawn_item(
     }
 }
 
-// Really basic, just spawns an entity and gives it the container component.
+// Spawns a container standing at `position` and fills it by rolling the given loot table.
+// Rolled counts above an item's max stack size are spawned as several stacks.
 #[cfg(feature = "server")]
-fn spawn_container(commands: &mut Commands) -> Entity {
-    let entity = commands.spawn(Container).id();
-    info!("Spawned container with id {}", entity);
-    entity
-}
-
-// Still unfinished, transfers an item. Same function works for moving it into a container,
-// out of a container, or from one container to another. Certainly feels suboptimal atm.
-// TODO: Optimize, improve error handling, and clean up.
-#[cfg(feature = "server")]
-fn move_item(
+fn spawn_container(
     commands: &mut Commands,
-    item: Entity,
-    current_container: Option<Entity>,
-    target_container: Option<Entity>,
-    mut query_inventory: Query<&mut Inventory>,
-    mut query_parent: Query<&mut ParentContainer>
-) {
-    match (current_container, target_container) {
-        (Some(current), Some(target)) => {
-            // TODO: Replace these clones and handle things in a more rusty/optimal way.
-            let mut curr = query_inventory.get_mut(current).expect("Err").clone();
-            let mut tar = query_inventory.get_mut(target).expect("Err").clone();
-            if let Ok(mut parent) = query_parent.get_mut(item) {
-                *parent = ParentContainer(target);
-            }
-            curr.items.remove(&item);
-            tar.items.insert(item);
-            info!(
-                "Transferred item with id {:?} from container with id {:?} into container with id {:?}",
-                item,
-                current,
-                target
-            )
-        }
-        (Some(current), None) => {
-            if let Ok(curr) = query_inventory.get_mut(current) {
-                if let Ok(parent) = query_parent.get_mut(item) {
-                    commands.entity(item).remove::<ParentContainer>();
-                }
-            }
-            info!("Removed item with id {:?} from container with id {:?}", item, current);
-        }
-        (None, Some(target)) => {
-            if let Ok(mut tar) = query_inventory.get_mut(target) {
-                commands.entity(item).insert(ParentContainer(target));
-                tar.items.insert(item);
+    item_storage: &Res<ItemStorage>,
+    loot_tables: &LootTables,
+    loot_table: &str,
+    position: Vec3A,
+    rng: &mut Entropy<WyRand>
+) -> Entity {
+    let entity = commands.spawn((Container, Name::new("Container"), PlayerPosition(position))).id();
+    info!("Spawned container with id {} using loot table \"{}\"", entity, loot_table);
+
+    for (name, count) in roll_loot_table(loot_table, loot_tables, item_storage, rng) {
+        let Some(data) = item_storage.items.get(&name) else {
+            continue;
+        };
+        let mut remaining = count;
+        while remaining > 0 {
+            let quantity = remaining.min(data.max_stack);
+            remaining -= quantity;
+            if let Some(item) = spawn_item_stack(commands, item_storage, name.as_str(), quantity) {
+                commands.queue(MoveItemRequest {
+                    item,
+                    from: None,
+                    to: Some(entity),
+                    quantity: None,
+                });
             }
-            info!("Added item with id {:?} to container with id {:?}", item, target);
-        }
-        (None, None) => {
-            warn!("No containers specified for move operation");
         }
     }
+
+    entity
 }
 
+// Lies next to where new characters start.
 #[cfg(feature = "server")]
 fn spawn_sword(mut commands: Commands, item_storage: Res<ItemStorage>) {
-    spawn_item(&mut commands, &item_storage, "sword");
+    if let Some(sword) = spawn_item(&mut commands, &item_storage, "sword") {
+        commands.entity(sword).insert(PlayerPosition(Vec3A::new(2.0, 0.0, 0.0)));
+    }
 }
 
 // Queries all items's data atm, more of a debugging tool. Will be shifted to be able to query specific items.
+#[allow(clippy::type_complexity)]
 fn fetch_item_info(
     query: Query<(&Name, &DisplayName, &Weight, &Icon, &Tags, &ItemProperties), With<Item>>
 ) {
@@ -256,27 +829,140 @@ fn fetch_item_info(
     }
 }
 
-// Placeholder/testing function to spanw a container and a random entity and insert it.
+// Placeholder/testing function to spawn a container and fill it from a loot table.
 #[cfg(feature = "server")]
 fn generate_container_items(
     mut commands: Commands,
     item_storage: Res<ItemStorage>,
+    loot_tables: Res<LootTables>,
     mut global_entropy: GlobalEntropy<WyRand>
 ) {
-    let container_entity: Entity = commands.spawn(()).id();
-    let item_keys = item_storage.items.keys();
     let mut rng = global_entropy.fork_rng();
+    let position = Vec3A::new(-2.0, 0.0, 0.0);
+    spawn_container(&mut commands, &item_storage, &loot_tables, "kitchen", position, &mut rng);
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::utils::scratch::ScratchDir;
 
-    if let Some(name) = choose_random(item_keys, &mut rng) {
-        let item = spawn_item(&mut commands, &item_storage, name.as_str()).expect(
-            "Failed to spawn item"
+    fn resolve(source: &str) -> (FxHashMap<Name, RawItemData>, Vec<ItemLoadError>) {
+        let templates: FxHashMap<String, ItemTemplate> = ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(source)
+            .unwrap();
+        let mut errors = Vec::new();
+        let items = resolve_item_templates(&templates, &mut errors);
+        (items, errors)
+    }
+
+    fn sorted_tags(data: &RawItemData) -> Vec<&str> {
+        let mut tags: Vec<&str> = data.tags.0
+            .iter()
+            .map(|tag| tag.as_str())
+            .collect();
+        tags.sort();
+        tags
+    }
+
+    #[test]
+    fn templates_inherit_override_and_remove() {
+        let (items, errors) = resolve(
+            r#"{
+                "weapon": (
+                    abstract: true,
+                    display_name: "Weapon",
+                    weight: 1.0,
+                    max_stack: 2,
+                    tags: ["Weapon", "Sharp"],
+                    properties: { "Damage": Int(10), "Durability": Int(100) },
+                ),
+                "sword": (parent: "weapon", display_name: "Sword", tags: ["Blade"], properties: { "Damage": Int(30) }),
+                "club": (
+                    extends: "weapon",
+                    display_name: "Club",
+                    weight: 2.5,
+                    remove_tags: ["Sharp"],
+                    remove_properties: ["Durability"],
+                ),
+            }"#
         );
+        assert!(errors.is_empty(), "{:?}", errors);
+        // Abstract templates are only there to be inherited from.
+        assert!(!items.contains_key(&Name::new("weapon")));
+
+        let sword = &items[&Name::new("sword")];
+        assert_eq!(sword.parent.as_deref(), Some("weapon"));
+        assert_eq!(sword.display_name.0, "Sword");
+        assert_eq!(sword.weight.0, 1.0);
+        assert_eq!(sword.max_stack, 2);
+        assert_eq!(sorted_tags(sword), ["Blade", "Sharp", "Weapon"]);
+        assert_eq!(sword.properties.get_int("Damage"), Ok(30));
+        assert_eq!(sword.properties.get_int("Durability"), Ok(100));
+
+        let club = &items[&Name::new("club")];
+        assert_eq!(club.weight.0, 2.5);
+        assert_eq!(sorted_tags(club), ["Weapon"]);
+        assert_eq!(club.properties.get_int("Damage"), Ok(10));
+        assert!(!club.properties.0.contains_key("Durability"));
+    }
 
-        let inventory = Inventory { items: fxhashset![item], ..Default::default() };
+    #[test]
+    fn broken_templates_are_reported_once_and_skipped() {
+        let (items, errors) = resolve(
+            r#"{
+                "a": (parent: "b", display_name: "A", weight: 1.0),
+                "b": (parent: "a", display_name: "B", weight: 1.0),
+                "c": (parent: "a", display_name: "C", weight: 1.0),
+                "d": (parent: "missing", display_name: "D", weight: 1.0),
+                "e": (parent: "d", display_name: "E", weight: 1.0),
+                "f": (display_name: "F"),
+                "g": (display_name: "G", weight: 1.0),
+            }"#
+        );
+        assert_eq!(items.len(), 1);
+        assert!(items.contains_key(&Name::new("g")));
+
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert!(
+            matches!(&errors[0], ItemLoadError::InheritanceCycle { chain } if chain == &["a", "b", "a"])
+        );
+        assert!(matches!(&errors[1], ItemLoadError::BrokenParent { id, parent } if id == "c" && parent == "a"));
+        assert!(matches!(&errors[2], ItemLoadError::UnknownParent { id, parent } if id == "d" && parent == "missing"));
+        assert!(matches!(&errors[3], ItemLoadError::BrokenParent { id, parent } if id == "e" && parent == "d"));
+        assert!(matches!(&errors[4], ItemLoadError::MissingField { id, field: "weight" } if id == "f"));
+    }
+
+    #[test]
+    fn definitions_load_from_ron_and_json() {
+        let dir = ScratchDir::new("items");
+        std::fs::create_dir_all(dir.join("weapons")).unwrap();
+        std::fs
+            ::write(dir.join("weapons/swords.ron"), r#"{ "sword": (display_name: "Sword", weight: 1.0) }"#)
+            .unwrap();
+        std::fs
+            ::write(
+                dir.join("food.json"),
+                r#"{ "apple": { "display_name": "Apple", "weight": 0.2 }, "sword": { "parent": "apple" } }"#
+            )
+            .unwrap();
+        std::fs::write(dir.join("broken.ron"), "{ \"torch\": (").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not an item").unwrap();
 
-        commands.entity(container_entity).insert((Container, inventory, Name::new("Container")));
+        let loaded = load_item_definitions(&dir, None);
 
-        commands.entity(item).insert(ParentContainer(container_entity));
-        info!("Spawned container with id {} and inserted item with id {}", container_entity, item)
+        assert_eq!(loaded.files_loaded, 2);
+        let mut ids: Vec<&str> = loaded.items
+            .keys()
+            .map(|id| id.as_str())
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["apple", "sword"]);
+        // Files are read in path order, so the sword in food.json comes first and the .ron one is the duplicate.
+        assert_eq!(loaded.items[&Name::new("sword")].parent.as_deref(), Some("apple"));
+        assert_eq!(loaded.errors.len(), 2, "{:?}", loaded.errors);
+        assert!(matches!(&loaded.errors[0], ItemLoadError::Parse { path, .. } if path.ends_with("broken.ron")));
+        assert!(matches!(&loaded.errors[1], ItemLoadError::DuplicateId { id, .. } if id == "sword"));
     }
 }
@@ -0,0 +1,498 @@
+// Data-driven loot tables, used to fill containers when they spawn.
+// Tables live in assets/loot as RON maps of table id to LootTable, and can point at items,
+// at every item with a given tag, or at other tables.
+
+use bevy::asset::ron;
+use bevy::prelude::{ Name, Res, ResMut, Resource, error, info };
+use bevy::utils::Instant;
+use bevy_rand::prelude::{ Entropy, WyRand };
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use std::fmt;
+use std::path::{ Path, PathBuf };
+
+use crate::game::items::ItemStorage;
+use crate::utils::common::{ choose_random, choose_weighted, random_range };
+
+pub const LOOT_TABLES_DIR: &str = "assets/loot";
+
+// Nested tables deeper than this are assumed to be a mistake. Cycles are rejected at load anyway.
+const MAX_TABLE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct CountRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl CountRange {
+    pub const ONE: CountRange = CountRange { min: 1, max: 1 };
+
+    fn one() -> Self {
+        Self::ONE
+    }
+}
+
+// Rarity tiers only decide the default weight of an entry, an explicit weight always wins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Rarity {
+    #[default]
+    Common,
+    Uncommon,
+    Rare,
+    VeryRare,
+    Legendary,
+}
+
+impl Rarity {
+    pub fn weight(self) -> u32 {
+        match self {
+            Rarity::Common => 100,
+            Rarity::Uncommon => 40,
+            Rarity::Rare => 12,
+            Rarity::VeryRare => 4,
+            Rarity::Legendary => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub enum LootKind {
+    // A single item id from the spawn dictionary
+    Item(String),
+    // Any item carrying this tag, picked uniformly
+    Tag(String),
+    // Roll another table
+    Table(String),
+    // An empty roll, so tables can have a chance of giving nothing
+    Nothing,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LootEntry {
+    pub kind: LootKind,
+    #[serde(default)]
+    pub rarity: Rarity,
+    #[serde(default)]
+    pub weight: Option<u32>,
+    // How many of the item to give, or how many times to roll a nested table.
+    #[serde(default = "CountRange::one")]
+    pub count: CountRange,
+}
+
+impl LootEntry {
+    pub fn effective_weight(&self) -> u32 {
+        self.weight.unwrap_or(self.rarity.weight())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LootTable {
+    // How many entries get picked each time the table is rolled.
+    #[serde(default = "CountRange::one")]
+    pub rolls: CountRange,
+    pub entries: Vec<LootEntry>,
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct LootTables(pub FxHashMap<String, LootTable>);
+
+#[derive(Debug)]
+pub enum LootLoadError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    DuplicateId {
+        id: String,
+        path: PathBuf,
+    },
+    UnknownItem {
+        table: String,
+        item: String,
+    },
+    UnknownTable {
+        table: String,
+        nested: String,
+    },
+    EmptyTag {
+        table: String,
+        tag: String,
+    },
+    InvalidRange {
+        table: String,
+    },
+    Cycle {
+        chain: Vec<String>,
+    },
+}
+
+impl fmt::Display for LootLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LootLoadError::Io { path, source } => {
+                write!(f, "Failed to read {}: {}", path.display(), source)
+            }
+            LootLoadError::Parse { path, message } => {
+                write!(f, "Failed to parse {}: {}", path.display(), message)
+            }
+            LootLoadError::DuplicateId { id, path } => {
+                write!(f, "Loot table \"{}\" in {} is already defined, skipping it", id, path.display())
+            }
+            LootLoadError::UnknownItem { table, item } => {
+                write!(f, "Loot table \"{}\" refers to unknown item \"{}\"", table, item)
+            }
+            LootLoadError::UnknownTable { table, nested } => {
+                write!(f, "Loot table \"{}\" refers to unknown table \"{}\"", table, nested)
+            }
+            LootLoadError::EmptyTag { table, tag } => {
+                write!(f, "Loot table \"{}\" uses tag \"{}\" which no item has", table, tag)
+            }
+            LootLoadError::InvalidRange { table } => {
+                write!(f, "Loot table \"{}\" has a range with min above max", table)
+            }
+            LootLoadError::Cycle { chain } => {
+                write!(f, "Loot table cycle: {}", chain.join(" -> "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for LootLoadError {}
+
+// Reads every .ron file directly inside `dir`. Files that fail to parse are reported and skipped.
+fn read_loot_tables(dir: &Path, errors: &mut Vec<LootLoadError>) -> FxHashMap<String, LootTable> {
+    let mut tables = FxHashMap::default();
+
+    let mut files: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+            .collect(),
+        Err(source) => {
+            errors.push(LootLoadError::Io { path: dir.to_path_buf(), source });
+            return tables;
+        }
+    };
+    files.sort();
+
+    for path in files {
+        let parsed = std::fs
+            ::read_to_string(&path)
+            .map_err(|source| LootLoadError::Io { path: path.clone(), source })
+            .and_then(|contents| {
+                ron::de
+                    ::from_str::<FxHashMap<String, LootTable>>(&contents)
+                    .map_err(|err| LootLoadError::Parse {
+                        path: path.clone(),
+                        message: err.to_string(),
+                    })
+            });
+
+        match parsed {
+            Ok(file) => {
+                for (id, table) in file {
+                    if tables.contains_key(&id) {
+                        errors.push(LootLoadError::DuplicateId { id, path: path.clone() });
+                        continue;
+                    }
+                    tables.insert(id, table);
+                }
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    tables
+}
+
+// Checks a single table's entries against the items and the other tables.
+fn validate_table(
+    id: &str,
+    table: &LootTable,
+    tables: &FxHashMap<String, LootTable>,
+    item_storage: &ItemStorage
+) -> Vec<LootLoadError> {
+    let mut errors = Vec::new();
+    if table.rolls.min > table.rolls.max {
+        errors.push(LootLoadError::InvalidRange { table: id.to_string() });
+    }
+
+    for entry in &table.entries {
+        if entry.count.min > entry.count.max {
+            errors.push(LootLoadError::InvalidRange { table: id.to_string() });
+        }
+        match &entry.kind {
+            LootKind::Item(item) => {
+                if !item_storage.items.contains_key(&Name::new(item.clone())) {
+                    errors.push(LootLoadError::UnknownItem {
+                        table: id.to_string(),
+                        item: item.clone(),
+                    });
+                }
+            }
+            LootKind::Tag(tag) => {
+                if !item_storage.items.values().any(|data| data.tags.0.contains(tag)) {
+                    errors.push(LootLoadError::EmptyTag {
+                        table: id.to_string(),
+                        tag: tag.clone(),
+                    });
+                }
+            }
+            LootKind::Table(nested) => {
+                if !tables.contains_key(nested) {
+                    errors.push(LootLoadError::UnknownTable {
+                        table: id.to_string(),
+                        nested: nested.clone(),
+                    });
+                }
+            }
+            LootKind::Nothing => {}
+        }
+    }
+
+    errors
+}
+
+// Depth-first walk over nested tables, returning the first cycle found through `id`.
+fn find_cycle(
+    id: &str,
+    tables: &FxHashMap<String, LootTable>,
+    stack: &mut Vec<String>
+) -> Option<Vec<String>> {
+    if let Some(start) = stack.iter().position(|entry| entry == id) {
+        let mut chain = stack[start..].to_vec();
+        chain.push(id.to_string());
+        return Some(chain);
+    }
+    let table = tables.get(id)?;
+
+    stack.push(id.to_string());
+    for entry in &table.entries {
+        if let LootKind::Table(nested) = &entry.kind {
+            if let Some(chain) = find_cycle(nested, tables, stack) {
+                stack.pop();
+                return Some(chain);
+            }
+        }
+    }
+    stack.pop();
+    None
+}
+
+// Loads and validates every loot table. Invalid tables are dropped, along with any table that
+// nests one of them, so rolling never has to deal with missing references.
+pub fn load_loot_tables(dir: &Path, item_storage: &ItemStorage) -> (LootTables, Vec<LootLoadError>) {
+    let mut errors = Vec::new();
+    let mut tables = read_loot_tables(dir, &mut errors);
+
+    let mut ids: Vec<String> = tables.keys().cloned().collect();
+    ids.sort();
+
+    let mut invalid = Vec::new();
+    for id in &ids {
+        let table_errors = validate_table(id, &tables[id], &tables, item_storage);
+        if !table_errors.is_empty() {
+            errors.extend(table_errors);
+            invalid.push(id.clone());
+        } else if let Some(chain) = find_cycle(id, &tables, &mut Vec::new()) {
+            // Tables that only lead into a cycle get dropped below, once the cycle is gone.
+            if chain.contains(id) {
+                // Every table in the cycle finds it, only report it from the first one.
+                if chain.iter().min() == Some(id) {
+                    errors.push(LootLoadError::Cycle { chain });
+                }
+                invalid.push(id.clone());
+            }
+        }
+    }
+
+    // Removing a table can break the ones nesting it, so keep going until nothing changes.
+    while !invalid.is_empty() {
+        for id in invalid.drain(..) {
+            tables.remove(&id);
+        }
+        for (id, table) in &tables {
+            let nests_missing = table.entries.iter().any(|entry| {
+                matches!(&entry.kind, LootKind::Table(nested) if !tables.contains_key(nested))
+            });
+            if nests_missing {
+                invalid.push(id.clone());
+            }
+        }
+        for id in &invalid {
+            error!("Loot table \"{}\" dropped because a table it nests failed to load", id);
+        }
+    }
+
+    (LootTables(tables), errors)
+}
+
+// Rolls a table, adding the picked item ids and counts to `loot`.
+fn roll_into(
+    table_id: &str,
+    tables: &LootTables,
+    item_storage: &ItemStorage,
+    rng: &mut Entropy<WyRand>,
+    depth: usize,
+    loot: &mut Vec<(Name, u32)>
+) {
+    if depth > MAX_TABLE_DEPTH {
+        error!("Loot table \"{}\" nested deeper than {}, stopping", table_id, MAX_TABLE_DEPTH);
+        return;
+    }
+    let Some(table) = tables.0.get(table_id) else {
+        return;
+    };
+
+    let rolls = random_range(table.rolls.min, table.rolls.max, rng);
+    for _ in 0..rolls {
+        let choices = table.entries.iter().map(|entry| (entry, entry.effective_weight()));
+        let Some(entry) = choose_weighted(choices, rng) else {
+            continue;
+        };
+        let count = random_range(entry.count.min, entry.count.max, rng);
+
+        match &entry.kind {
+            LootKind::Item(item) => loot.push((Name::new(item.clone()), count)),
+            LootKind::Tag(tag) => {
+                // Sorted so a given seed always picks the same item.
+                let mut pool: Vec<&Name> = item_storage.items
+                    .iter()
+                    .filter(|(_, data)| data.tags.0.contains(tag))
+                    .map(|(name, _)| name)
+                    .collect();
+                pool.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+                if let Some(name) = choose_random(pool, rng) {
+                    loot.push((name.clone(), count));
+                }
+            }
+            LootKind::Table(nested) => {
+                for _ in 0..count {
+                    roll_into(nested, tables, item_storage, rng, depth + 1, loot);
+                }
+            }
+            LootKind::Nothing => {}
+        }
+    }
+}
+
+// Rolls a loot table and returns item ids with how many of each to spawn.
+// The same item can show up more than once, stacking takes care of that when they're inserted.
+pub fn roll_loot_table(
+    table_id: &str,
+    tables: &LootTables,
+    item_storage: &ItemStorage,
+    rng: &mut Entropy<WyRand>
+) -> Vec<(Name, u32)> {
+    let mut loot = Vec::new();
+    roll_into(table_id, tables, item_storage, rng, 0, &mut loot);
+    loot
+}
+
+pub(crate) fn initialize_loot_tables(
+    item_storage: Res<ItemStorage>,
+    mut loot_tables: ResMut<LootTables>
+) {
+    let start = Instant::now();
+    let (tables, errors) = load_loot_tables(Path::new(LOOT_TABLES_DIR), &item_storage);
+
+    for err in &errors {
+        error!("{}", err);
+    }
+
+    *loot_tables = tables;
+    info!(
+        "Loot tables initialized with {} tables in {:?} ({} errors).",
+        loot_tables.0.len(),
+        start.elapsed(),
+        errors.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::SeedableRng;
+
+    use crate::utils::scratch::ScratchDir;
+
+    fn entry(kind: LootKind, count: CountRange) -> LootEntry {
+        LootEntry { kind, rarity: Rarity::Common, weight: None, count }
+    }
+
+    fn table(rolls: CountRange, entries: Vec<LootEntry>) -> LootTable {
+        LootTable { rolls, entries }
+    }
+
+    // t0 nests t1 nests t2 and so on, with the last table in the chain dropping a single gem.
+    fn chain(length: usize) -> LootTables {
+        let mut tables = FxHashMap::default();
+        for depth in 0..length {
+            let kind = LootKind::Table(format!("t{}", depth + 1));
+            tables.insert(format!("t{}", depth), table(CountRange::ONE, vec![entry(kind, CountRange::ONE)]));
+        }
+        let gem = entry(LootKind::Item("gem".to_string()), CountRange::ONE);
+        tables.insert(format!("t{}", length), table(CountRange::ONE, vec![gem]));
+        LootTables(tables)
+    }
+
+    fn roll(table_id: &str, tables: &LootTables, seed: u64) -> Vec<(Name, u32)> {
+        let item_storage = ItemStorage { items: FxHashMap::default() };
+        roll_loot_table(table_id, tables, &item_storage, &mut Entropy::<WyRand>::seed_from_u64(seed))
+    }
+
+    #[test]
+    fn rolls_and_counts_stay_in_range() {
+        let nails = entry(LootKind::Item("nails".to_string()), CountRange { min: 5, max: 10 });
+        let mut tables = FxHashMap::default();
+        tables.insert("box".to_string(), table(CountRange { min: 1, max: 3 }, vec![nails]));
+        // Every roll of the outer table rolls the box twice.
+        let boxes = entry(LootKind::Table("box".to_string()), CountRange { min: 2, max: 2 });
+        tables.insert("crate".to_string(), table(CountRange::ONE, vec![boxes]));
+        let tables = LootTables(tables);
+
+        for seed in 0..200 {
+            let loot = roll("crate", &tables, seed);
+            assert!((2..=6).contains(&loot.len()), "{:?}", loot);
+            for (name, count) in loot {
+                assert_eq!(name.as_str(), "nails");
+                assert!((5..=10).contains(&count));
+            }
+        }
+        assert!(roll("missing", &tables, 0).is_empty());
+    }
+
+    #[test]
+    fn nesting_stops_past_the_max_depth() {
+        assert_eq!(roll("t0", &chain(MAX_TABLE_DEPTH), 0), [(Name::new("gem"), 1)]);
+        assert!(roll("t0", &chain(MAX_TABLE_DEPTH + 1), 0).is_empty());
+    }
+
+    #[test]
+    fn cycles_and_tables_nesting_them_are_dropped() {
+        let dir = ScratchDir::new("loot");
+        std::fs
+            ::write(
+                dir.join("tables.ron"),
+                r#"{
+                    "a": (entries: [(kind: Table("b"))]),
+                    "b": (entries: [(kind: Table("a")), (kind: Nothing)]),
+                    "outer": (entries: [(kind: Table("a"))]),
+                    "fine": (entries: [(kind: Nothing)]),
+                }"#
+            )
+            .unwrap();
+
+        let item_storage = ItemStorage { items: FxHashMap::default() };
+        let (tables, errors) = load_loot_tables(&dir, &item_storage);
+
+        assert_eq!(tables.0.keys().collect::<Vec<_>>(), ["fine"]);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(matches!(&errors[0], LootLoadError::Cycle { chain } if chain == &["a", "b", "a"]));
+    }
+}
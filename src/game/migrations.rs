@@ -0,0 +1,619 @@
+// Upgrades old save files to the current format, one version at a time.
+//
+// Migrations work on the untyped ron::Value of a save, before it's turned into WorldSave or CharacterSave,
+// so they can still read whatever the old layout looked like. When the layout changes, bump SAVE_VERSION
+// and add a step below that takes the previous version to it. The helpers at the bottom cover the usual
+// cases (an item id was renamed, a property changed type, a component went away).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use bevy::asset::ron::value::{ Map, Number };
+use bevy::asset::ron::Value;
+use bevy::prelude::Name;
+use rustc_hash::FxHashSet;
+
+use crate::game::accounts::CharacterSave;
+use crate::game::item_schema::{ PropertySchemaRegistry, PROPERTY_SCHEMA_PATH };
+use crate::game::items::{ load_item_definitions, ItemStorage, ITEM_DEFINITIONS_DIR };
+use crate::game::save::{
+    merged_properties,
+    read_save,
+    read_versioned,
+    write_save,
+    SavedProperty,
+    WorldSave,
+    SAVE_VERSION,
+};
+
+// Which kind of file a save is, since migrations usually touch the two differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveKind {
+    World,
+    Character,
+}
+
+impl SaveKind {
+    // Only world saves have entities, only characters have a position.
+    pub fn detect(value: &Value) -> Option<Self> {
+        let Value::Map(map) = value else {
+            return None;
+        };
+        if field(map, "entities").is_some() {
+            Some(SaveKind::World)
+        } else if field(map, "position").is_some() {
+            Some(SaveKind::Character)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    // Written by a newer build than this one.
+    Unsupported(u32),
+    Failed(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Unsupported(version) => {
+                write!(f, "save version {} is newer than this build's {}", version, SAVE_VERSION)
+            }
+            MigrationError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+pub struct Migration {
+    // The version this takes a save to, from the one right before it.
+    pub to: u32,
+    pub description: &'static str,
+    pub apply: fn(SaveKind, &mut Value) -> Result<(), String>,
+}
+
+// In order, with no gaps. The last one has to end at SAVE_VERSION.
+pub fn migrations() -> Vec<Migration> {
+    vec![Migration {
+        to: 2,
+        description: "split entity kinds into item and container components, untag item properties",
+        apply: split_entity_kinds,
+    }]
+}
+
+// Brings the save up to SAVE_VERSION and returns the version it started at.
+// Files from before saves were versioned count as version 1.
+pub fn migrate(kind: SaveKind, value: &mut Value) -> Result<u32, MigrationError> {
+    let Value::Map(map) = value else {
+        return Err(MigrationError::Failed("save is not a struct".to_string()));
+    };
+    let from = match field(map, "version") {
+        Some(Value::Number(Number::Integer(version))) => {
+            u32::try_from(*version).map_err(|_| {
+                MigrationError::Failed(format!("version {} is out of range", version))
+            })?
+        }
+        Some(_) => {
+            return Err(MigrationError::Failed("version is not a whole number".to_string()));
+        }
+        None => 1,
+    };
+    if from > SAVE_VERSION {
+        return Err(MigrationError::Unsupported(from));
+    }
+
+    for migration in migrations().into_iter().filter(|migration| migration.to > from) {
+        (migration.apply)(kind, value).map_err(|reason| {
+            MigrationError::Failed(
+                format!("migration to version {} ({}): {}", migration.to, migration.description, reason)
+            )
+        })?;
+        set_field(value, "version", Value::Number(Number::Integer(migration.to as i64)));
+    }
+    Ok(from)
+}
+
+// Version 1 wrote which kind an entity was as an enum, Item(id: .., ..) or Container(name: ..),
+// and properties as PropertyValue, e.g. Int(5). ron::Value reads both without the variant name,
+// so the kind has to be told apart by its fields and every property is left wrapped in a one-element tuple.
+fn split_entity_kinds(kind: SaveKind, value: &mut Value) -> Result<(), String> {
+    if kind == SaveKind::Character {
+        return Ok(());
+    }
+    for entity in entities_mut(value) {
+        let Some(Value::Map(mut saved_kind)) = entity.remove(&key("kind")) else {
+            return Err("entity without a kind".to_string());
+        };
+        if field(&saved_kind, "id").is_some() {
+            if let Some(Value::Map(properties)) = field_mut(&mut saved_kind, "properties") {
+                for property in properties.values_mut() {
+                    if let Value::Seq(wrapped) = property {
+                        if wrapped.len() == 1 {
+                            let unwrapped = wrapped.remove(0);
+                            *property = unwrapped;
+                        }
+                    }
+                }
+            }
+            entity.insert(key("item"), some(Value::Map(saved_kind)));
+        } else if field(&saved_kind, "name").is_some() {
+            entity.insert(key("container"), some(Value::Map(saved_kind)));
+        } else {
+            return Err("entity that is neither an item nor a container".to_string());
+        }
+    }
+    Ok(())
+}
+
+// Renames an item everywhere it shows up, in world and character saves alike.
+pub fn rename_item(kind: SaveKind, value: &mut Value, from: &str, to: &str) {
+    for_each_item(kind, value, &mut |item| {
+        if let Some(Value::String(id)) = field_mut(item, "id") {
+            if id == from {
+                *id = to.to_string();
+            }
+        }
+    });
+}
+
+// Runs every property called `name` through `convert`, e.g. for an Int that became a Float.
+// Properties it returns None for are dropped, the item definition's value takes over again on load.
+pub fn retype_property(kind: SaveKind, value: &mut Value, name: &str, convert: fn(Value) -> Option<Value>) {
+    for_each_item(kind, value, &mut |item| {
+        let Some(Value::Map(properties)) = field_mut(item, "properties") else {
+            return;
+        };
+        let Some(old) = properties.remove(&key(name)) else {
+            return;
+        };
+        if let Some(new) = convert(old) {
+            properties.insert(key(name), new);
+        }
+    });
+}
+
+// Drops a component from every entity in a world save, for when it doesn't exist anymore.
+// Serde ignores unknown fields anyway, this is for when something else has to change along with it.
+// Characters have no entities, so it leaves them alone.
+pub fn remove_component(value: &mut Value, component: &str) {
+    for entity in entities_mut(value) {
+        entity.remove(&key(component));
+    }
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_string())
+}
+
+fn some(value: Value) -> Value {
+    Value::Option(Some(Box::new(value)))
+}
+
+fn field<'a>(map: &'a Map, name: &str) -> Option<&'a Value> {
+    let key = key(name);
+    map.iter()
+        .find(|(field, _)| **field == key)
+        .map(|(_, value)| value)
+}
+
+fn field_mut<'a>(map: &'a mut Map, name: &str) -> Option<&'a mut Value> {
+    let key = key(name);
+    map.iter_mut()
+        .find(|(field, _)| **field == key)
+        .map(|(_, value)| value)
+}
+
+fn set_field(value: &mut Value, name: &str, new: Value) {
+    if let Value::Map(map) = value {
+        map.insert(key(name), new);
+    }
+}
+
+fn entities_mut(value: &mut Value) -> impl Iterator<Item = &mut Map> + '_ {
+    let entities = match value {
+        Value::Map(map) =>
+            match field_mut(map, "entities") {
+                Some(Value::Seq(entities)) => Some(entities),
+                _ => None,
+            }
+        _ => None,
+    };
+    entities
+        .into_iter()
+        .flatten()
+        .filter_map(|entity| match entity {
+            Value::Map(entity) => Some(entity),
+            _ => None,
+        })
+}
+
+// Only where items are kept: entities[].item in a world, inventory[] and the contents[] of each
+// stack, however deep, on a character.
+fn for_each_item(kind: SaveKind, value: &mut Value, f: &mut impl FnMut(&mut Map)) {
+    match kind {
+        SaveKind::World => {
+            for entity in entities_mut(value) {
+                if let Some(item) = field_mut(entity, "item").and_then(as_map_mut) {
+                    f(item);
+                }
+            }
+        }
+        SaveKind::Character => {
+            if let Some(Value::Seq(stacks)) = as_map_mut(value).and_then(|map| field_mut(map, "inventory")) {
+                for_each_stack(stacks, f);
+            }
+        }
+    }
+}
+
+fn for_each_stack(stacks: &mut [Value], f: &mut impl FnMut(&mut Map)) {
+    for stack in stacks.iter_mut().filter_map(as_map_mut) {
+        f(stack);
+        if let Some(Value::Seq(contents)) = field_mut(stack, "contents") {
+            for_each_stack(contents, f);
+        }
+    }
+}
+
+// A struct, or one in an Option.
+fn as_map_mut(value: &mut Value) -> Option<&mut Map> {
+    match value {
+        Value::Map(map) => Some(map),
+        Value::Option(Some(value)) => as_map_mut(value),
+        _ => None,
+    }
+}
+
+// Everything wrong with a save that would make it load differently than it was written.
+// Doesn't stop at the first problem, so one run shows all of them.
+pub fn validate_world(
+    save: &WorldSave,
+    item_storage: &ItemStorage,
+    schema: Option<&PropertySchemaRegistry>
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut ids = FxHashSet::default();
+    for entity in &save.entities {
+        if !ids.insert(entity.id) {
+            problems.push(format!("entity {} is in the save twice", entity.id));
+        }
+    }
+
+    for entity in &save.entities {
+        match (&entity.item, &entity.container) {
+            (Some(item), None) => {
+                for problem in item_problems(&item.id, item.quantity, &item.properties, item_storage, schema) {
+                    problems.push(format!("entity {}: {}", entity.id, problem));
+                }
+            }
+            (None, Some(_)) => {}
+            (Some(_), Some(_)) => {
+                problems.push(format!("entity {} is both an item and a container", entity.id));
+            }
+            (None, None) => {
+                problems.push(format!("entity {} is neither an item nor a container", entity.id));
+            }
+        }
+
+        if let Some(parent) = entity.parent {
+            let listed = save.entities
+                .iter()
+                .find(|other| other.id == parent)
+                .and_then(|other| other.inventory.as_ref())
+                .is_some_and(|inventory| inventory.items.contains(&entity.id));
+            if !listed {
+                problems.push(format!("entity {} says it's in {}, which doesn't hold it", entity.id, parent));
+            }
+        }
+        for item in entity.inventory.iter().flat_map(|inventory| &inventory.items) {
+            let held = save.entities
+                .iter()
+                .find(|other| other.id == *item)
+                .is_some_and(|other| other.parent == Some(entity.id));
+            if !held {
+                problems.push(format!("entity {} holds {}, which isn't in it", entity.id, item));
+            }
+        }
+    }
+    problems
+}
+
+pub fn validate_character(
+    save: &CharacterSave,
+    item_storage: &ItemStorage,
+    schema: Option<&PropertySchemaRegistry>
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut pending: Vec<_> = save.inventory.iter().collect();
+    while let Some(item) = pending.pop() {
+        for problem in item_problems(&item.id, item.quantity, &item.properties, item_storage, schema) {
+            problems.push(format!("carried {}", problem));
+        }
+        pending.extend(&item.contents);
+    }
+    problems
+}
+
+// The same checks for an item wherever it's saved, so world and character saves can't drift apart.
+fn item_problems(
+    id: &str,
+    quantity: u32,
+    properties: &BTreeMap<String, SavedProperty>,
+    item_storage: &ItemStorage,
+    schema: Option<&PropertySchemaRegistry>
+) -> Vec<String> {
+    let Some(data) = item_storage.items.get(&Name::new(id.to_string())) else {
+        return vec![format!("item \"{}\" doesn't exist", id)];
+    };
+    let mut problems = Vec::new();
+    if quantity == 0 || quantity > data.max_stack {
+        problems.push(format!("item \"{}\" has {}, but stacks to {}", id, quantity, data.max_stack));
+    }
+    if let Some(schema) = schema {
+        for err in schema.validate(&data.tags, &merged_properties(item_storage, id, properties)) {
+            problems.push(format!("item \"{}\": {}", id, err));
+        }
+    }
+    problems
+}
+
+// Entry point for --migrate-save. Like --auth-only there's no bevy app, so this reports to stderr.
+// The old file is kept next to the new one as <name>.v<version>.bak.
+pub fn run_save_migration(path: &Path, dry_run: bool) {
+    if let Err(err) = migrate_save_file(path, dry_run) {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    }
+}
+
+fn migrate_save_file(path: &Path, dry_run: bool) -> Result<(), String> {
+    let value = read_save::<Value>(path)
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| "no such file".to_string())?;
+    let kind = SaveKind::detect(&value).ok_or_else(|| {
+        "doesn't look like a world or character save".to_string()
+    })?;
+
+    // Same as the server would load them, so the check matches what would actually happen.
+    let schema = match PropertySchemaRegistry::from_file(Path::new(PROPERTY_SCHEMA_PATH)) {
+        Ok(schema) => Some(schema),
+        Err(err) => {
+            eprintln!("{}. Item properties will not be validated.", err);
+            None
+        }
+    };
+    let loaded = load_item_definitions(Path::new(ITEM_DEFINITIONS_DIR), schema.as_ref());
+    for err in &loaded.errors {
+        eprintln!("{}", err);
+    }
+    let item_storage = ItemStorage { items: loaded.items };
+
+    let (from, problems) = match kind {
+        SaveKind::World => {
+            let (save, from) = read_versioned::<WorldSave>(path, kind)
+                .map_err(|err| err.to_string())?
+                .ok_or_else(|| "no such file".to_string())?;
+            let problems = validate_world(&save, &item_storage, schema.as_ref());
+            if !dry_run && from != SAVE_VERSION {
+                backup_and_write(path, from, &save)?;
+            }
+            (from, problems)
+        }
+        SaveKind::Character => {
+            let (save, from) = read_versioned::<CharacterSave>(path, kind)
+                .map_err(|err| err.to_string())?
+                .ok_or_else(|| "no such file".to_string())?;
+            let problems = validate_character(&save, &item_storage, schema.as_ref());
+            if !dry_run && from != SAVE_VERSION {
+                backup_and_write(path, from, &save)?;
+            }
+            (from, problems)
+        }
+    };
+
+    for migration in migrations().iter().filter(|migration| migration.to > from) {
+        println!("  {} -> {}: {}", migration.to - 1, migration.to, migration.description);
+    }
+    match (from == SAVE_VERSION, dry_run) {
+        (true, _) => println!("{:?} save is already at version {}", kind, SAVE_VERSION),
+        (false, true) => {
+            println!("{:?} save would be migrated from version {} to {}", kind, from, SAVE_VERSION)
+        }
+        (false, false) => {
+            println!("{:?} save migrated from version {} to {}", kind, from, SAVE_VERSION)
+        }
+    }
+
+    if problems.is_empty() {
+        println!("No problems found");
+        Ok(())
+    } else {
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        Err(format!("{} problems found", problems.len()))
+    }
+}
+
+fn backup_and_write<T: serde::Serialize>(path: &Path, from: u32, save: &T) -> Result<(), String> {
+    let backup = path.with_extension(format!("v{}.bak", from));
+    std::fs
+        ::copy(path, &backup)
+        .map_err(|err| format!("Failed to back up to {}: {}", backup.display(), err))?;
+    write_save(path, save).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::save::{ load_world, SavedContainer, SavedEntity, SavedInventory, SavedItem };
+    use crate::utils::scratch::ScratchDir;
+
+    // Written by the build that introduced world saves, before entity kinds were split up.
+    const WORLD_V1: &str = include_str!("testdata/world_v1.ron");
+
+    fn item(id: u32, name: &str, quantity: u32, properties: &[(&str, SavedProperty)]) -> SavedEntity {
+        SavedEntity {
+            id,
+            item: Some(SavedItem {
+                id: name.to_string(),
+                quantity,
+                properties: properties
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.clone()))
+                    .collect(),
+            }),
+            container: None,
+            parent: Some(0),
+            inventory: None,
+            position: None,
+        }
+    }
+
+    #[test]
+    fn version_1_world_loads_as_the_current_version() {
+        let dir = ScratchDir::new("migrations");
+        let path = dir.join("world.ron");
+        std::fs::write(&path, WORLD_V1).unwrap();
+        let (save, from) = read_versioned::<WorldSave>(&path, SaveKind::World).unwrap().unwrap();
+        let loaded = load_world(&path).unwrap();
+
+        assert_eq!(from, 1);
+        assert_eq!(loaded.as_ref(), Some(&save));
+        assert_eq!(save, WorldSave {
+            version: SAVE_VERSION,
+            entities: vec![
+                SavedEntity {
+                    id: 0,
+                    item: None,
+                    container: Some(SavedContainer { name: "Container".to_string() }),
+                    parent: None,
+                    inventory: Some(SavedInventory { weight_limit: 100.0, items: vec![1, 2] }),
+                    position: None,
+                },
+                item(1, "sword", 1, &[("Damage", SavedProperty::Int(25))]),
+                item(2, "nails", 40, &[])
+            ],
+        });
+
+        let loaded = load_item_definitions(Path::new(ITEM_DEFINITIONS_DIR), None);
+        let item_storage = ItemStorage { items: loaded.items };
+        assert_eq!(validate_world(&save, &item_storage, None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn characters_are_checked_like_world_items() {
+        let loaded = load_item_definitions(Path::new(ITEM_DEFINITIONS_DIR), None);
+        let item_storage = ItemStorage { items: loaded.items };
+        let schema = PropertySchemaRegistry::from_file(Path::new(PROPERTY_SCHEMA_PATH)).unwrap();
+        let character: CharacterSave = bevy::asset::ron::from_str(
+            r#"(
+                version: 2,
+                position: (0.0, 0.0, 0.0),
+                inventory: [
+                    (id: "sword", quantity: 3, properties: { "Damage": "sharp" }),
+                    (id: "nails", quantity: 40),
+                ],
+            )"#
+        ).unwrap();
+
+        let problems = validate_character(&character, &item_storage, Some(&schema));
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("stacks to 1"), "{:?}", problems);
+        assert!(problems[1].contains("Damage"), "{:?}", problems);
+    }
+
+    // A sword in a chest in the world and in a backpack on a character, next to something with a
+    // text id that isn't an item and has to be left alone.
+    const WORLD: &str = r#"(
+        version: 2,
+        entities: [
+            (id: 0, container: Some((name: "Chest")), inventory: Some((weight_limit: 10.0, items: [1]))),
+            (id: 1, item: Some((id: "sword", quantity: 1, properties: { "Damage": 25 })), parent: Some(0)),
+        ],
+        notes: (id: "sword", properties: { "Damage": 25 }),
+    )"#;
+    const CHARACTER: &str = r#"(
+        version: 2,
+        position: (0.0, 0.0, 0.0),
+        inventory: [
+            (id: "backpack", quantity: 1, contents: [(id: "sword", quantity: 1, properties: { "Damage": 25 })]),
+        ],
+        notes: (id: "sword", properties: { "Damage": 25 }),
+    )"#;
+
+    fn parse(source: &str) -> Value {
+        bevy::asset::ron::from_str(source).unwrap()
+    }
+
+    fn notes(value: &Value) -> &Value {
+        let Value::Map(map) = value else {
+            panic!("not a struct");
+        };
+        field(map, "notes").unwrap()
+    }
+
+    fn float(value: Value) -> Option<Value> {
+        match value {
+            Value::Number(Number::Integer(int)) => Some(Value::Number(Number::from(int as f64))),
+            other => Some(other),
+        }
+    }
+
+    #[test]
+    fn items_are_renamed_where_items_are_kept() {
+        let mut world = parse(WORLD);
+        rename_item(SaveKind::World, &mut world, "sword", "blade");
+        assert_eq!(notes(&world), notes(&parse(WORLD)));
+        let world: WorldSave = world.into_rust().unwrap();
+        assert_eq!(world.entities[1].item.as_ref().unwrap().id, "blade");
+
+        let mut character = parse(CHARACTER);
+        rename_item(SaveKind::Character, &mut character, "sword", "blade");
+        assert_eq!(notes(&character), notes(&parse(CHARACTER)));
+        let character: CharacterSave = character.into_rust().unwrap();
+        assert_eq!(character.inventory[0].id, "backpack");
+        assert_eq!(character.inventory[0].contents[0].id, "blade");
+    }
+
+    #[test]
+    fn properties_are_retyped_or_dropped() {
+        let mut world = parse(WORLD);
+        retype_property(SaveKind::World, &mut world, "Damage", float);
+        assert_eq!(notes(&world), notes(&parse(WORLD)));
+        let world: WorldSave = world.into_rust().unwrap();
+        assert_eq!(world.entities[1].item.as_ref().unwrap().properties["Damage"], SavedProperty::Float(25.0));
+
+        let mut character = parse(CHARACTER);
+        retype_property(SaveKind::Character, &mut character, "Damage", |_| None);
+        assert_eq!(notes(&character), notes(&parse(CHARACTER)));
+        let character: CharacterSave = character.into_rust().unwrap();
+        assert!(character.inventory[0].contents[0].properties.is_empty());
+    }
+
+    #[test]
+    fn components_are_removed_from_world_entities_only() {
+        let mut world = parse(WORLD);
+        remove_component(&mut world, "inventory");
+        let world: WorldSave = world.into_rust().unwrap();
+        assert!(world.entities.iter().all(|entity| entity.inventory.is_none()));
+        assert_eq!(world.entities[0].container, Some(SavedContainer { name: "Chest".to_string() }));
+
+        let mut character = parse(CHARACTER);
+        remove_component(&mut character, "inventory");
+        assert_eq!(character, parse(CHARACTER));
+    }
+
+    #[test]
+    fn out_of_range_versions_are_refused() {
+        let mut value: Value = bevy::asset::ron::from_str("(version: -1, entities: [])").unwrap();
+        assert!(matches!(migrate(SaveKind::World, &mut value), Err(MigrationError::Failed(_))));
+        let mut value: Value = bevy::asset::ron::from_str("(version: 4294967297, entities: [])").unwrap();
+        assert!(matches!(migrate(SaveKind::World, &mut value), Err(MigrationError::Failed(_))));
+    }
+}
@@ -1,3 +1,14 @@
+#[cfg(feature = "server")]
+pub mod accounts;
 pub mod app;
+#[cfg(feature = "server")]
+pub mod inventory;
 pub mod items;
-pub mod player;
\ No newline at end of file
+pub mod item_schema;
+#[cfg(feature = "server")]
+pub mod loot;
+#[cfg(feature = "server")]
+pub mod migrations;
+pub mod player;
+#[cfg(feature = "server")]
+pub mod save;
\ No newline at end of file
@@ -1,6 +1,149 @@
-use bevy::prelude::{ Component, Reflect };
+use bevy::math::Vec3A;
+use bevy::prelude::{
+    App,
+    Component,
+    FixedUpdate,
+    Plugin,
+    Query,
+    Reflect,
+    Res,
+    Resource,
+    Time,
+    Timer,
+    Without,
+};
+use leafwing_input_manager::prelude::ActionState;
+use lightyear::prelude::client::Confirmed;
 
-use crate::network::protocol::PlayerId;
+use crate::network::protocol::{ PlayerActions, PlayerId, PlayerPosition };
 
 #[derive(Component, Debug, Clone)]
-pub struct Player;
\ No newline at end of file
+pub struct Player;
+
+// Players on the same team share team chat. Server-side only, nothing hands these out yet.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Team(pub u32);
+
+// A player whose client went away. The character stays where it was until the timer runs out,
+// so a quick reconnect picks it back up instead of starting over.
+#[derive(Component, Debug, Clone)]
+pub struct Parked(pub Timer);
+
+// How far a player walks in one second.
+pub const PLAYER_SPEED: f32 = 4.0;
+
+// Gets where a player is and where they want to go, and returns where they actually end up.
+// Runs on both the server and the predicting client, so it has to give the same answer on both.
+#[derive(Resource, Clone, Copy)]
+pub struct CollisionHook(pub fn(Vec3A, Vec3A) -> Vec3A);
+
+impl Default for CollisionHook {
+    // Nothing to collide with yet.
+    fn default() -> Self {
+        Self(|_, to| to)
+    }
+}
+
+// Turns the held direction keys into a unit vector, so going diagonally isn't faster.
+pub fn movement_direction(action: &ActionState<PlayerActions>) -> Vec3A {
+    let mut direction = Vec3A::ZERO;
+    if action.pressed(&PlayerActions::Up) {
+        direction.y += 1.0;
+    }
+    if action.pressed(&PlayerActions::Down) {
+        direction.y -= 1.0;
+    }
+    if action.pressed(&PlayerActions::Left) {
+        direction.x -= 1.0;
+    }
+    if action.pressed(&PlayerActions::Right) {
+        direction.x += 1.0;
+    }
+    direction.normalize_or_zero()
+}
+
+// Shared by server and client. Has to stay deterministic, the client replays it during rollback.
+pub fn shared_movement(
+    position: &mut PlayerPosition,
+    action: &ActionState<PlayerActions>,
+    delta: f32,
+    collision: &CollisionHook
+) {
+    let direction = movement_direction(action);
+    if direction == Vec3A::ZERO {
+        return;
+    }
+    let target = position.0 + direction * PLAYER_SPEED * delta;
+    position.0 = (collision.0)(position.0, target);
+}
+
+// The server moves the authoritative players, the client only its predicted one.
+// Confirmed entities just mirror the server and interpolated ones have no inputs.
+// Parked players would otherwise keep walking on whatever was last held down.
+#[allow(clippy::type_complexity)]
+fn move_players(
+    time: Res<Time>,
+    collision: Res<CollisionHook>,
+    mut players: Query<
+        (&mut PlayerPosition, &ActionState<PlayerActions>),
+        (Without<Confirmed>, Without<Parked>)
+    >
+) {
+    for (mut position, action) in players.iter_mut() {
+        shared_movement(&mut position, action, time.delta_secs(), &collision);
+    }
+}
+
+#[derive(Clone)]
+pub struct PlayerMovementPlugin;
+
+impl Plugin for PlayerMovementPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CollisionHook>();
+        // FixedUpdate ticks at FIXED_TIMESTEP_HZ, lightyear sets the timestep from the tick config.
+        app.add_systems(FixedUpdate, move_players);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holding(actions: &[PlayerActions]) -> ActionState<PlayerActions> {
+        let mut action = ActionState::default();
+        for held in actions {
+            action.press(held);
+        }
+        action
+    }
+
+    fn moved(actions: &[PlayerActions], delta: f32, collision: &CollisionHook) -> Vec3A {
+        let mut position = PlayerPosition::default();
+        shared_movement(&mut position, &holding(actions), delta, collision);
+        position.0
+    }
+
+    #[test]
+    fn diagonals_are_no_faster() {
+        let step = moved(&[PlayerActions::Up, PlayerActions::Right], 0.5, &CollisionHook::default());
+        assert!((step.length() - PLAYER_SPEED * 0.5).abs() < 1e-5, "moved {}", step.length());
+        assert!((step.x - step.y).abs() < 1e-6);
+    }
+
+    #[test]
+    fn opposite_keys_cancel_out() {
+        let none = CollisionHook::default();
+        assert_eq!(moved(&[PlayerActions::Up, PlayerActions::Down], 1.0, &none), Vec3A::ZERO);
+        assert_eq!(moved(&[PlayerActions::Left, PlayerActions::Right], 1.0, &none), Vec3A::ZERO);
+        let step = moved(&[PlayerActions::Left, PlayerActions::Right, PlayerActions::Up], 1.0, &none);
+        assert_eq!(step, Vec3A::new(0.0, PLAYER_SPEED, 0.0));
+    }
+
+    #[test]
+    fn collision_hook_has_the_last_word() {
+        // A wall at x = 1.
+        let wall = CollisionHook(|_, to| Vec3A::new(to.x.min(1.0), to.y, to.z));
+        assert_eq!(moved(&[PlayerActions::Right], 1.0, &wall), Vec3A::new(1.0, 0.0, 0.0));
+        assert_eq!(moved(&[PlayerActions::Up], 1.0, &wall), Vec3A::new(0.0, PLAYER_SPEED, 0.0));
+    }
+}
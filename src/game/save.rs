@@ -0,0 +1,767 @@
+// Saving and restoring the world.
+//
+// The world save holds every item and container that isn't carried by a player, along with how they nest.
+// Entity ids mean nothing after a restart, so each saved entity gets a number that is only valid inside
+// the save, and references between them are remapped to the freshly spawned entities on load.
+// Players are saved to their character files instead (see game::accounts), they come back when they log in.
+//
+// Saved on an interval, on shutdown, and restored at PreStartup before anything gets generated.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+use bevy::app::{ AppExit, TerminalCtrlCHandlerPlugin };
+use bevy::asset::ron;
+use bevy::ecs::system::SystemParam;
+use bevy::math::Vec3A;
+use bevy::prelude::{
+    error,
+    info,
+    warn,
+    App,
+    Commands,
+    Entity,
+    EventReader,
+    Has,
+    IntoSystemConfigs,
+    Last,
+    Name,
+    Or,
+    Plugin,
+    PreStartup,
+    Query,
+    Res,
+    ResMut,
+    Resource,
+    Time,
+    Timer,
+    TimerMode,
+    Update,
+    With,
+};
+use rustc_hash::{ FxHashMap, FxHashSet };
+use serde::{ Deserialize, Serialize };
+
+use crate::game::accounts::{ player_id, save_character, CharacterQueries };
+use crate::game::inventory::InsertItem;
+use crate::game::migrations::{ migrate, MigrationError, SaveKind };
+use crate::game::items::{
+    initialize_item_storage,
+    spawn_item_stack,
+    Container,
+    Inventory,
+    Item,
+    ItemProperties,
+    ItemStorage,
+    ParentContainer,
+    Quantity,
+};
+use crate::game::player::Player;
+use crate::network::protocol::{ PlayerId, PlayerPosition };
+use crate::utils::common::PropertyValue;
+use crate::utils::settings::Settings;
+
+const WORLD_FILE: &str = "world.ron";
+
+// Shared by the world and character files. Bump it whenever the layout of either changes,
+// and add a migration in game::migrations that brings the previous version up to it.
+pub const SAVE_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: ron::error::SpannedError,
+    },
+    Serialize {
+        path: PathBuf,
+        source: ron::Error,
+    },
+    UnsupportedVersion {
+        path: PathBuf,
+        version: u32,
+    },
+    Migration {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io { path, source } => {
+                write!(f, "Failed to access {}: {}", path.display(), source)
+            }
+            SaveError::Parse { path, source } => {
+                write!(
+                    f,
+                    "Failed to parse {} at line {}, column {}: {}",
+                    path.display(),
+                    source.position.line,
+                    source.position.col,
+                    source.code
+                )
+            }
+            SaveError::Serialize { path, source } => {
+                write!(f, "Failed to serialize {}: {}", path.display(), source)
+            }
+            SaveError::UnsupportedVersion { path, version } => {
+                write!(
+                    f,
+                    "{} is save version {}, this build only reads up to version {}",
+                    path.display(),
+                    version,
+                    SAVE_VERSION
+                )
+            }
+            SaveError::Migration { path, reason } => {
+                write!(f, "Failed to migrate {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+// Reads a RON save, treating a missing file as "nothing saved yet".
+pub fn read_save<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>, SaveError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(source) => {
+            return Err(SaveError::Io { path: path.to_path_buf(), source });
+        }
+    };
+    ron::de
+        ::from_str(&contents)
+        .map(Some)
+        .map_err(|source| SaveError::Parse { path: path.to_path_buf(), source })
+}
+
+// Writes to a temporary file first and renames it over the old one,
+// so a crash halfway through never leaves a truncated save behind.
+pub fn write_save<T: Serialize>(path: &Path, value: &T) -> Result<(), SaveError> {
+    let contents = ron::ser
+        ::to_string_pretty(value, ron::ser::PrettyConfig::default())
+        .map_err(|source| SaveError::Serialize { path: path.to_path_buf(), source })?;
+    let io_err = |source| SaveError::Io { path: path.to_path_buf(), source };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(io_err)?;
+    }
+    let tmp = path.with_extension("ron.tmp");
+    std::fs::write(&tmp, contents).map_err(io_err)?;
+    std::fs::rename(&tmp, path).map_err(io_err)
+}
+
+// Reads a versioned save, upgrading it to the current version on the way in.
+// Returns the version it was stored as next to the save, None if there is no file.
+pub fn read_versioned<T: for<'de> Deserialize<'de>>(
+    path: &Path,
+    kind: SaveKind
+) -> Result<Option<(T, u32)>, SaveError> {
+    let Some(mut value) = read_save::<ron::Value>(path)? else {
+        return Ok(None);
+    };
+    let from = migrate(kind, &mut value).map_err(|err| match err {
+        MigrationError::Unsupported(version) => SaveError::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+        },
+        MigrationError::Failed(reason) => SaveError::Migration { path: path.to_path_buf(), reason },
+    })?;
+    let save = value
+        .into_rust()
+        .map_err(|err| SaveError::Migration { path: path.to_path_buf(), reason: err.to_string() })?;
+    Ok(Some((save, from)))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldSave {
+    pub version: u32,
+    pub entities: Vec<SavedEntity>,
+}
+
+// Every component is its own optional field and nothing is an enum with named variants,
+// so migrations can work on the untyped ron::Value without losing anything (it drops variant names).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedEntity {
+    // Only means something inside this save, see the top of the file.
+    pub id: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item: Option<SavedItem>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<SavedContainer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inventory: Option<SavedInventory>,
+    // Only for what stands in the world itself, anything inside something else is wherever that is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<(f32, f32, f32)>,
+}
+
+// Respawned from its definition, so only what can differ between two copies of it is kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedItem {
+    pub id: String,
+    pub quantity: u32,
+    // Sorted so saves don't reshuffle between runs.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, SavedProperty>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedContainer {
+    pub name: String,
+}
+
+// PropertyValue without the variant names, which type it is follows from how the value is written.
+// Int comes before Float so whole numbers without a decimal point stay ints.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SavedProperty {
+    Bool(bool),
+    Int(i32),
+    Float(f32),
+    Text(String),
+}
+
+impl From<PropertyValue> for SavedProperty {
+    fn from(value: PropertyValue) -> Self {
+        match value {
+            PropertyValue::Bool(val) => SavedProperty::Bool(val),
+            PropertyValue::Int(val) => SavedProperty::Int(val),
+            PropertyValue::Float(val) => SavedProperty::Float(val),
+            PropertyValue::Text(val) => SavedProperty::Text(val),
+        }
+    }
+}
+
+impl From<SavedProperty> for PropertyValue {
+    fn from(value: SavedProperty) -> Self {
+        match value {
+            SavedProperty::Bool(val) => PropertyValue::Bool(val),
+            SavedProperty::Int(val) => PropertyValue::Int(val),
+            SavedProperty::Float(val) => PropertyValue::Float(val),
+            SavedProperty::Text(val) => PropertyValue::Text(val),
+        }
+    }
+}
+
+// The current weight isn't kept, it's worked out again from the contents on load.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedInventory {
+    pub weight_limit: f32,
+    pub items: Vec<u32>,
+}
+
+pub fn world_path(save_dir: &Path) -> PathBuf {
+    save_dir.join(WORLD_FILE)
+}
+
+// Older saves are upgraded in memory, the file itself catches up with the next save.
+pub fn load_world(path: &Path) -> Result<Option<WorldSave>, SaveError> {
+    let Some((save, from)) = read_versioned::<WorldSave>(path, SaveKind::World)? else {
+        return Ok(None);
+    };
+    if from != SAVE_VERSION {
+        info!("Migrated {} from save version {} to {}", path.display(), from, SAVE_VERSION);
+    }
+    Ok(Some(save))
+}
+
+// Present once a save has been restored, so the placeholder world generation knows to stay out of it.
+#[derive(Resource)]
+pub struct WorldRestored;
+
+#[derive(Resource)]
+pub struct WorldPersistence {
+    pub path: PathBuf,
+    // None when autosaving is turned off.
+    pub autosave: Option<Timer>,
+    // Off when the save on disk couldn't be read, so it isn't overwritten before someone has looked at it.
+    pub enabled: bool,
+}
+
+impl WorldPersistence {
+    fn new(settings: &Settings, enabled: bool) -> Self {
+        let interval = settings.server.autosave_secs;
+        Self {
+            path: world_path(&settings.server.save_dir),
+            autosave: (interval > 0.0).then(|| Timer::from_seconds(interval, TimerMode::Repeating)),
+            enabled,
+        }
+    }
+}
+
+// Everything that ends up in the world save.
+#[derive(SystemParam)]
+#[allow(clippy::type_complexity)]
+pub struct WorldQueries<'w, 's> {
+    pub entities: Query<
+        'w,
+        's,
+        (
+            Entity,
+            Option<&'static Name>,
+            Option<&'static Quantity>,
+            Option<&'static ItemProperties>,
+            Option<&'static Inventory>,
+            Option<&'static ParentContainer>,
+            Option<&'static PlayerPosition>,
+            Has<Item>,
+        ),
+        Or<(With<Item>, With<Container>)>
+    >,
+    pub parents: Query<'w, 's, &'static ParentContainer>,
+    pub players: Query<'w, 's, (), With<Player>>,
+    pub item_storage: Res<'w, ItemStorage>,
+}
+
+impl WorldQueries<'_, '_> {
+    // Whatever a player carries goes into their character file instead.
+    fn held_by_player(&self, entity: Entity) -> bool {
+        let mut outermost = entity;
+        while let Ok(parent) = self.parents.get(outermost) {
+            outermost = parent.0;
+            if outermost == entity {
+                break;
+            }
+        }
+        self.players.contains(outermost)
+    }
+
+    pub fn snapshot(&self) -> WorldSave {
+        let mut saved: Vec<Entity> = self.entities
+            .iter()
+            .map(|(entity, ..)| entity)
+            .filter(|entity| !self.held_by_player(*entity))
+            .collect();
+        saved.sort();
+        let ids: FxHashMap<Entity, u32> = saved
+            .iter()
+            .enumerate()
+            .map(|(id, entity)| (*entity, id as u32))
+            .collect();
+
+        let entities = saved
+            .iter()
+            .filter_map(|entity| {
+                let (_, name, quantity, properties, inventory, parent, position, is_item) = self.entities
+                    .get(*entity)
+                    .ok()?;
+                let name = name.map_or("Container", |name| name.as_str()).to_string();
+                let (item, container) = if is_item {
+                    let properties = properties
+                        .map(|properties| saved_properties(&self.item_storage, &name, properties))
+                        .unwrap_or_default();
+                    let item = SavedItem {
+                        id: name,
+                        quantity: quantity.map_or(1, |quantity| quantity.0),
+                        properties,
+                    };
+                    (Some(item), None)
+                } else {
+                    (None, Some(SavedContainer { name }))
+                };
+                let inventory = inventory.map(|inventory| {
+                    let mut items: Vec<u32> = inventory.items
+                        .iter()
+                        .filter_map(|item| ids.get(item).copied())
+                        .collect();
+                    items.sort();
+                    SavedInventory { weight_limit: inventory.weight_limit, items }
+                });
+                Some(SavedEntity {
+                    id: ids[entity],
+                    item,
+                    container,
+                    parent: parent.and_then(|parent| ids.get(&parent.0).copied()),
+                    inventory,
+                    position: position.map(|position| (position.x, position.y, position.z)),
+                })
+            })
+            .collect();
+
+        WorldSave { version: SAVE_VERSION, entities }
+    }
+}
+
+// Only what differs from the definition, anything else follows the definition if it changes later.
+pub fn saved_properties(
+    item_storage: &ItemStorage,
+    id: &str,
+    properties: &ItemProperties
+) -> BTreeMap<String, SavedProperty> {
+    let defaults = item_storage.items.get(&Name::new(id.to_string())).map(|data| &data.properties.0);
+    properties.0
+        .iter()
+        .filter(|(key, value)| defaults.and_then(|defaults| defaults.get(*key)) != Some(*value))
+        .map(|(key, value)| (key.clone(), value.clone().into()))
+        .collect()
+}
+
+// On top of the definition's, so properties added to it since the save still show up.
+pub fn merged_properties(
+    item_storage: &ItemStorage,
+    id: &str,
+    saved: &BTreeMap<String, SavedProperty>
+) -> ItemProperties {
+    let mut merged = item_storage.items
+        .get(&Name::new(id.to_string()))
+        .map(|data| data.properties.clone())
+        .unwrap_or_default();
+    merged.0.extend(saved.iter().map(|(key, value)| (key.clone(), value.clone().into())));
+    merged
+}
+
+// Where a restored entity goes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placement {
+    Inside(u32),
+    Ground((f32, f32, f32)),
+}
+
+// Inside its parent, or if that one wasn't restored, the closest holder further out that was.
+// With none left it lies where the outermost one stood.
+fn placement(
+    entity: &SavedEntity,
+    saved: &FxHashMap<u32, &SavedEntity>,
+    spawned: &FxHashMap<u32, Entity>
+) -> Placement {
+    let mut current = entity;
+    // A chain of dropped holders can't be longer than the save, anything longer is going in circles.
+    for _ in 0..=saved.len() {
+        let Some(parent) = current.parent else {
+            break;
+        };
+        if spawned.contains_key(&parent) {
+            return Placement::Inside(parent);
+        }
+        let Some(next) = saved.get(&parent) else {
+            break;
+        };
+        current = next;
+    }
+    // Saves from before positions were kept put everything at the origin.
+    Placement::Ground(current.position.unwrap_or_default())
+}
+
+// Innermost first, so every container already weighs what it holds by the time it's moved itself.
+fn insert_contents(
+    commands: &mut Commands,
+    id: u32,
+    contents: &FxHashMap<u32, Vec<u32>>,
+    spawned: &FxHashMap<u32, Entity>,
+    placed: &mut FxHashSet<u32>
+) {
+    placed.insert(id);
+    for item in contents.get(&id).into_iter().flatten() {
+        insert_contents(commands, *item, contents, spawned, placed);
+        commands.queue(InsertItem { item: spawned[item], container: spawned[&id] });
+    }
+}
+
+// Spawns everything in the save and puts it back together through the transfer API,
+// so weights and parents are worked out the same way as for any other move.
+// Returns the ids of items that no longer exist in the definitions. Those get dropped,
+// and whatever was inside them goes to the closest holder that's still around, see placement.
+pub fn spawn_world(
+    commands: &mut Commands,
+    item_storage: &Res<ItemStorage>,
+    save: &WorldSave
+) -> Vec<String> {
+    let mut missing = Vec::new();
+    let mut spawned: FxHashMap<u32, Entity> = FxHashMap::default();
+
+    // Everything is spawned before anything goes anywhere, so references can point either way in the file.
+    for saved in &save.entities {
+        let entity = match (&saved.item, &saved.container) {
+            (Some(item), _) => {
+                let Some(entity) = spawn_item_stack(commands, item_storage, &item.id, item.quantity) else {
+                    missing.push(item.id.clone());
+                    continue;
+                };
+                if !item.properties.is_empty() {
+                    let properties = merged_properties(item_storage, &item.id, &item.properties);
+                    commands.entity(entity).insert(properties);
+                }
+                entity
+            }
+            (None, Some(container)) => {
+                commands.spawn((Container, Name::new(container.name.clone()))).id()
+            }
+            // A migration took away whatever it was.
+            (None, None) => {
+                warn!("Saved entity {} is neither an item nor a container, skipping it", saved.id);
+                continue;
+            }
+        };
+        // Empty for now, it's filled again below.
+        if let Some(inventory) = &saved.inventory {
+            commands.entity(entity).insert(Inventory { weight_limit: inventory.weight_limit, ..Default::default() });
+        }
+        spawned.insert(saved.id, entity);
+    }
+
+    let saved: FxHashMap<u32, &SavedEntity> = save.entities
+        .iter()
+        .map(|entity| (entity.id, entity))
+        .collect();
+    let mut contents: FxHashMap<u32, Vec<u32>> = FxHashMap::default();
+    let mut on_ground = Vec::new();
+    for entity in save.entities.iter().filter(|entity| spawned.contains_key(&entity.id)) {
+        let placement = placement(entity, &saved, &spawned);
+        if let Some(parent) = entity.parent.filter(|parent| !spawned.contains_key(parent)) {
+            warn!("Saved entity {} was inside {}, which wasn't restored. Placing it {:?}", entity.id, parent, placement);
+        }
+        match placement {
+            Placement::Inside(parent) => contents.entry(parent).or_default().push(entity.id),
+            Placement::Ground(position) => on_ground.push((entity.id, position)),
+        }
+    }
+
+    let mut placed = FxHashSet::default();
+    for (id, (x, y, z)) in on_ground {
+        commands.entity(spawned[&id]).insert(PlayerPosition(Vec3A::new(x, y, z)));
+        insert_contents(commands, id, &contents, &spawned, &mut placed);
+    }
+    // Only a broken save can have things inside each other in a circle, none of them end up anywhere.
+    for entity in save.entities.iter().filter(|entity| !placed.contains(&entity.id)) {
+        if let Some(&stray) = spawned.get(&entity.id) {
+            warn!("Saved entity {} is inside itself, dropping it", entity.id);
+            commands.entity(stray).despawn();
+        }
+    }
+
+    missing
+}
+
+// Writes the world file and every player's character file.
+fn save_world(
+    settings: &Settings,
+    persistence: &WorldPersistence,
+    world: &WorldQueries,
+    characters: &CharacterQueries,
+    players: &Query<(Entity, &PlayerId)>
+) {
+    if !persistence.enabled {
+        warn!("Not saving the world, {} couldn't be read at startup", persistence.path.display());
+        return;
+    }
+    let save = world.snapshot();
+    match write_save(&persistence.path, &save) {
+        Ok(()) => info!("Saved {} entities to {}", save.entities.len(), persistence.path.display()),
+        Err(err) => error!("{}", err),
+    }
+    for (entity, PlayerId(client_id)) in players.iter() {
+        let Some(character) = characters.snapshot(entity) else {
+            continue;
+        };
+        if let Err(err) = save_character(&settings.server.save_dir, player_id(*client_id), &character) {
+            error!("{}", err);
+        }
+    }
+}
+
+// Runs after the item definitions are loaded, since saved items are respawned from them.
+fn restore_world(mut commands: Commands, settings: Res<Settings>, item_storage: Res<ItemStorage>) {
+    let path = world_path(&settings.server.save_dir);
+    match load_world(&path) {
+        Ok(Some(save)) => {
+            let missing = spawn_world(&mut commands, &item_storage, &save);
+            if !missing.is_empty() {
+                warn!("Dropped saved items that no longer exist: {:?}", missing);
+            }
+            info!("Restored {} entities from {}", save.entities.len(), path.display());
+            commands.insert_resource(WorldRestored);
+            commands.insert_resource(WorldPersistence::new(&settings, true));
+        }
+        Ok(None) => {
+            info!("No world save at {}, starting a new world", path.display());
+            commands.insert_resource(WorldPersistence::new(&settings, true));
+        }
+        Err(err) => {
+            error!("{}. Starting a new world, saving is off until the file is fixed or removed.", err);
+            commands.insert_resource(WorldPersistence::new(&settings, false));
+        }
+    }
+}
+
+fn autosave_world(
+    time: Res<Time>,
+    settings: Res<Settings>,
+    mut persistence: ResMut<WorldPersistence>,
+    world: WorldQueries,
+    characters: CharacterQueries,
+    players: Query<(Entity, &PlayerId)>
+) {
+    let Some(timer) = persistence.autosave.as_mut() else {
+        return;
+    };
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    save_world(&settings, &persistence, &world, &characters, &players);
+}
+
+// AppExit is sent during Update, so by Last the world has had its final frame.
+fn save_on_exit(
+    mut exits: EventReader<AppExit>,
+    settings: Res<Settings>,
+    persistence: Res<WorldPersistence>,
+    world: WorldQueries,
+    characters: CharacterQueries,
+    players: Query<(Entity, &PlayerId)>
+) {
+    if exits.read().last().is_none() {
+        return;
+    }
+    save_world(&settings, &persistence, &world, &characters, &players);
+}
+
+pub struct WorldSavePlugin;
+
+impl Plugin for WorldSavePlugin {
+    fn build(&self, app: &mut App) {
+        // A headless server has no window to close, Ctrl+C is how it gets stopped.
+        // Without this it would just die there and skip the shutdown save.
+        if !app.is_plugin_added::<TerminalCtrlCHandlerPlugin>() {
+            app.add_plugins(TerminalCtrlCHandlerPlugin);
+        }
+        app.add_systems(PreStartup, restore_world.after(initialize_item_storage));
+        app.add_systems(Update, autosave_world);
+        app.add_systems(Last, save_on_exit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::{ RunSystemOnce, SystemState };
+    use bevy::prelude::{ Events, World };
+
+    use crate::game::inventory::ItemMoved;
+    use crate::game::items::{ load_item_definitions, ITEM_DEFINITIONS_DIR };
+    use crate::utils::scratch::ScratchDir;
+
+    fn world() -> World {
+        let mut world = World::new();
+        let loaded = load_item_definitions(Path::new(ITEM_DEFINITIONS_DIR), None);
+        world.insert_resource(ItemStorage { items: loaded.items });
+        world.init_resource::<Events<ItemMoved>>();
+        world
+    }
+
+    fn restore(world: &mut World, save: WorldSave) -> Vec<String> {
+        world
+            .run_system_once(move |mut commands: Commands, item_storage: Res<ItemStorage>| {
+                spawn_world(&mut commands, &item_storage, &save)
+            })
+            .unwrap()
+    }
+
+    fn snapshot(world: &mut World) -> WorldSave {
+        let mut state = SystemState::<WorldQueries>::new(world);
+        state.get(world).snapshot()
+    }
+
+    fn item(id: u32, name: &str, quantity: u32) -> SavedEntity {
+        SavedEntity {
+            id,
+            item: Some(SavedItem { id: name.to_string(), quantity, properties: BTreeMap::new() }),
+            container: None,
+            parent: None,
+            inventory: None,
+            position: None,
+        }
+    }
+
+    fn inventory(weight_limit: f32, items: Vec<u32>) -> Option<SavedInventory> {
+        Some(SavedInventory { weight_limit, items })
+    }
+
+    #[test]
+    fn world_survives_a_save_and_load() {
+        let mut sword = SavedEntity { parent: Some(1), ..item(2, "sword", 1) };
+        sword.item.as_mut().unwrap().properties.insert("Damage".to_string(), SavedProperty::Int(25));
+        let save = WorldSave {
+            version: SAVE_VERSION,
+            entities: vec![
+                SavedEntity {
+                    item: None,
+                    container: Some(SavedContainer { name: "Container".to_string() }),
+                    inventory: inventory(100.0, vec![1]),
+                    position: Some((3.0, 0.0, -1.0)),
+                    ..item(0, "", 0)
+                },
+                SavedEntity { parent: Some(0), inventory: inventory(15.0, vec![2, 3]), ..item(1, "backpack", 1) },
+                sword,
+                SavedEntity { parent: Some(1), ..item(3, "nails", 40) }
+            ],
+        };
+
+        let mut world = world();
+        assert!(restore(&mut world, save.clone()).is_empty());
+        let restored = snapshot(&mut world);
+        assert_eq!(restored, save);
+
+        // Worked out by the transfer API on the way in, the file doesn't have them.
+        let mut inventories = world.query::<(&Name, &Inventory)>();
+        for (name, inventory) in inventories.iter(&world) {
+            let expected = if name.as_str() == "backpack" { 1.4 } else { 2.9 };
+            assert!((inventory.current_weight - expected).abs() < 1e-4, "{} weighs {}", name, inventory.current_weight);
+        }
+
+        let dir = ScratchDir::new("save");
+        let path = world_path(&dir);
+        write_save(&path, &restored).unwrap();
+        let loaded = load_world(&path).unwrap();
+        assert_eq!(loaded, Some(save));
+    }
+
+    #[test]
+    fn contents_of_dropped_items_move_outwards() {
+        let save = WorldSave {
+            version: SAVE_VERSION,
+            entities: vec![
+                SavedEntity {
+                    inventory: inventory(15.0, vec![1]),
+                    position: Some((1.0, 0.0, 1.0)),
+                    ..item(0, "backpack", 1)
+                },
+                SavedEntity { parent: Some(0), inventory: inventory(5.0, vec![2]), ..item(1, "removed_bag", 1) },
+                SavedEntity { parent: Some(1), ..item(2, "sword", 1) },
+                SavedEntity {
+                    inventory: inventory(5.0, vec![4]),
+                    position: Some((4.0, 0.0, 2.0)),
+                    ..item(3, "removed_crate", 1)
+                },
+                SavedEntity { parent: Some(3), ..item(4, "nails", 10) }
+            ],
+        };
+
+        let mut world = world();
+        assert_eq!(restore(&mut world, save), vec!["removed_bag".to_string(), "removed_crate".to_string()]);
+        let restored = snapshot(&mut world);
+        let find = |name: &str| {
+            restored.entities
+                .iter()
+                .find(|entity| entity.item.as_ref().is_some_and(|item| item.id == name))
+                .unwrap()
+        };
+        // The sword goes into the backpack the dropped bag was in, the nails lie where the crate stood.
+        assert_eq!(find("sword").parent, Some(find("backpack").id));
+        assert_eq!(find("nails").parent, None);
+        assert_eq!(find("nails").position, Some((4.0, 0.0, 2.0)));
+    }
+}
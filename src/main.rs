@@ -22,10 +22,15 @@ scale of the project right now, but they include, in order of priority:
 #![allow(dead_code)]
 
 #[cfg(feature = "client")]
-use crate::network::client::ClientNetworkingPlugin;
+use crate::network::client::{ ClientNetworkingPlugin, Login };
 #[cfg(feature = "server")]
-use crate::network::server::ServerNetworkingPlugin;
+use crate::network::server::{ run_auth_service, ServerNetworkingPlugin };
+#[cfg(feature = "server")]
+use crate::game::migrations::run_save_migration;
+#[cfg(feature = "server")]
+use crate::network::auth::run_hash_password;
 use crate::network::protocol::ProtocolPlugin;
+use crate::network::traffic::TrafficPlugin;
 #[cfg(feature = "gui")]
 use crate::render::ui::UiRenderPlugin;
 use bevy::prelude::*;
@@ -40,6 +45,7 @@ use lightyear::prelude::*;
 
 use crate::utils::settings::*;
 use crate::game::app::{ Cli, Apps };
+use crate::game::player::PlayerMovementPlugin;
 
 mod utils;
 mod network;
@@ -49,13 +55,38 @@ mod render;
 
 fn main() {
     let cli = cli();
-    #[allow(unused_mut)]
-    let mut settings = get_settings();
+    #[cfg(feature = "server")]
+    if cli.hash_password {
+        run_hash_password();
+        return;
+    }
+    // Logging isn't set up until the app is built, so report bad settings on stderr.
+    let settings = match get_settings(&cli) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "server")]
+    if cli.auth_only {
+        run_auth_service(&settings);
+        return;
+    }
+    #[cfg(feature = "server")]
+    if let Some(path) = &cli.migrate_save {
+        run_save_migration(path, cli.dry_run);
+        return;
+    }
+    #[cfg(feature = "client")]
+    let login = Login::new(&cli, &settings);
     let mut apps = Apps::new(settings, cli, env!("CARGO_PKG_NAME").to_string()).unwrap();
     apps.add_lightyear_plugins();
     apps.add_user_shared_plugin(ProtocolPlugin);
+    apps.add_user_shared_plugin(TrafficPlugin);
+    apps.add_user_shared_plugin(PlayerMovementPlugin);
     #[cfg(feature = "client")]
-    apps.add_user_client_plugin(ClientNetworkingPlugin);
+    apps.add_user_client_plugin(ClientNetworkingPlugin { login });
     #[cfg(feature = "server")]
     apps.add_user_server_plugin(ServerNetworkingPlugin);
     #[cfg(feature = "gui")]
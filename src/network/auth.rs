@@ -0,0 +1,548 @@
+// Connect-token authentication. A small TCP service next to the game server checks a username/password,
+// or registers a new account with an invite code, and hands back a netcode ConnectToken carrying the
+// account's player id, so the private key never has to leave the server.
+// Runs in-process with the server, or on its own with --auth-only.
+//
+// Every message is a big-endian u32 length followed by that many bytes of JSON.
+// Clients send their ProtocolVersion and compression along, anyone on a different protocol, or
+// compressing differently from the port they asked for, is turned away before they get a token,
+// since the game server would just ignore them.
+//
+// There is no TLS, passwords cross the connection in plain text. Never expose the service to the
+// internet: keep it on loopback (the default, see AuthSettings::address) or a network you trust,
+// and have remote players reach it through a VPN or an encrypted tunnel.
+
+use std::fmt;
+use std::io::{ self, Read, Write };
+use std::net::{ SocketAddr, TcpListener, TcpStream };
+#[cfg(feature = "server")]
+use std::ops::RangeInclusive;
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::sync::atomic::{ AtomicUsize, Ordering };
+#[cfg(feature = "server")]
+use std::sync::{ Arc, OnceLock };
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+use argon2::password_hash::{ PasswordHash, PasswordHasher, PasswordVerifier, SaltString };
+#[cfg(feature = "server")]
+use argon2::Argon2;
+
+use bevy::asset::ron;
+use bevy::prelude::{ info, warn };
+use lightyear::connection::netcode::{ ConnectToken, Key, CONNECT_TOKEN_BYTES };
+use lightyear::prelude::CompressionConfig;
+use rustc_hash::{ FxHashMap, FxHashSet };
+use serde::de::DeserializeOwned;
+#[cfg(feature = "server")]
+use rand_core::OsRng;
+use serde::{ Deserialize, Serialize };
+
+use crate::network::version::ProtocolVersion;
+#[cfg(feature = "server")]
+use crate::game::accounts::Accounts;
+
+// Nothing we send comes anywhere close to this, anything bigger is garbage.
+const MAX_MESSAGE_BYTES: u32 = 16 * 1024;
+
+// How long either side waits on the other before giving up.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Usernames show up in chat, where /w takes the first word after it, and in the logs.
+#[cfg(feature = "server")]
+const USERNAME_CHARS: RangeInclusive<usize> = 3..=20;
+
+// Logins beyond this many at once are turned away. Each one gets a thread and hashing is slow on purpose.
+#[cfg(feature = "server")]
+const MAX_CONCURRENT_LOGINS: usize = 16;
+
+// Deliberately no Debug, so a password can't end up in the logs.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    Password {
+        username: String,
+        password: String,
+    },
+    // Registers a new account with the given password.
+    Invite {
+        username: String,
+        password: String,
+        code: String,
+    },
+}
+
+impl Credentials {
+    pub fn username(&self) -> &str {
+        match self {
+            Credentials::Password { username, .. } | Credentials::Invite { username, .. } => username,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub credentials: Credentials,
+    // The game server port the client is going to connect to, which depends on its transport.
+    pub game_port: u16,
+    // Missing from clients older than the check, which then fail it like any other mismatch.
+    #[serde(default)]
+    pub version: ProtocolVersion,
+    // Has to match the transport on game_port. Older clients don't send it and count as uncompressed.
+    #[serde(default)]
+    pub compression: CompressionConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum AuthResponse {
+    Token(Vec<u8>),
+    Rejected(String),
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(io::Error),
+    Malformed(String),
+    Rejected(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Io(err) => write!(f, "auth connection failed: {}", err),
+            AuthError::Malformed(reason) => write!(f, "malformed auth message: {}", reason),
+            AuthError::Rejected(reason) => write!(f, "login rejected: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<io::Error> for AuthError {
+    fn from(err: io::Error) -> Self {
+        AuthError::Io(err)
+    }
+}
+
+fn write_message<T: Serialize>(stream: &mut TcpStream, message: &T) -> Result<(), AuthError> {
+    let bytes = serde_json::to_vec(message).map_err(|err| AuthError::Malformed(err.to_string()))?;
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    Ok(())
+}
+
+fn read_message<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<T, AuthError> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_MESSAGE_BYTES {
+        return Err(AuthError::Malformed(format!("message of {} bytes is too large", len)));
+    }
+    let mut bytes = vec![0u8; len as usize];
+    stream.read_exact(&mut bytes)?;
+    serde_json::from_slice(&bytes).map_err(|err| AuthError::Malformed(err.to_string()))
+}
+
+// Blocking, so run it off the main thread.
+#[cfg(feature = "client")]
+pub fn fetch_token(
+    auth_addr: SocketAddr,
+    credentials: Credentials,
+    game_port: u16,
+    compression: CompressionConfig
+) -> Result<ConnectToken, AuthError> {
+    let mut stream = TcpStream::connect_timeout(&auth_addr, IO_TIMEOUT)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let version = ProtocolVersion::current();
+    write_message(&mut stream, &(AuthRequest { credentials, game_port, version, compression }))?;
+    match read_message(&mut stream)? {
+        AuthResponse::Token(bytes) =>
+            ConnectToken::try_from_bytes(&bytes).map_err(|err| AuthError::Malformed(err.to_string())),
+        AuthResponse::Rejected(reason) => Err(AuthError::Rejected(reason)),
+    }
+}
+
+// Letters, digits, - and _ only, so a name can't hold whitespace, control characters or newlines.
+// Checked when registering, the users in the credentials file are up to whoever wrote it.
+#[cfg(feature = "server")]
+fn check_username(username: &str) -> Result<(), String> {
+    if !USERNAME_CHARS.contains(&username.chars().count()) {
+        return Err(
+            format!("usernames are {} to {} characters long", USERNAME_CHARS.start(), USERNAME_CHARS.end())
+        );
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("usernames can only have letters, digits, - and _".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn compression_name(compression: CompressionConfig) -> &'static str {
+    match compression {
+        CompressionConfig::None => "no",
+        CompressionConfig::Lz4 => "lz4",
+        CompressionConfig::Zstd { .. } => "zstd",
+    }
+}
+
+// Passwords are stored as argon2id PHC strings, each with its own random salt.
+// Hashes for the credentials file come from --hash-password.
+#[cfg(feature = "server")]
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+// Anything that isn't a PHC string, like the sha256 hex older versions stored, never matches.
+#[cfg(feature = "server")]
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        warn!("Stored password hash isn't a PHC string, that account has to be registered again");
+        return false;
+    };
+    Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+}
+
+// Checked against when the username doesn't exist, so that takes as long as a wrong password.
+#[cfg(feature = "server")]
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("").unwrap_or_default())
+}
+
+// Entry point for --hash-password. Reads the password from stdin so it stays out of the shell history.
+#[cfg(feature = "server")]
+pub fn run_hash_password() {
+    let mut password = String::new();
+    let result = io
+        ::stdin()
+        .read_line(&mut password)
+        .map_err(|err| err.to_string())
+        .and_then(|_| {
+            hash_password(password.trim_end_matches(['\r', '\n'])).map_err(|err| err.to_string())
+        });
+    match result {
+        Ok(hash) => println!("{}", hash),
+        Err(err) => {
+            eprintln!("Failed to hash password: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Read from the file in AuthSettings::credentials. Accounts themselves live in the account store,
+// the users here are just seeded into it the first time they log in.
+#[cfg(feature = "server")]
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CredentialStore {
+    // Username to password hash, see hash_password.
+    #[serde(default)]
+    pub users: FxHashMap<String, String>,
+    // Anyone holding one of these can register an account under a username nobody has yet.
+    #[serde(default)]
+    pub invite_codes: FxHashSet<String>,
+}
+
+#[cfg(feature = "server")]
+impl CredentialStore {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs
+            ::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        ron::de
+            ::from_str(&contents)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))
+    }
+}
+
+#[cfg(feature = "server")]
+pub struct AuthService {
+    pub credentials: CredentialStore,
+    pub accounts: Accounts,
+    pub version: ProtocolVersion,
+    // The compression on each of the game server's ports.
+    pub transports: FxHashMap<u16, CompressionConfig>,
+    pub private_key: Key,
+    pub token_expire_secs: i32,
+}
+
+#[cfg(feature = "server")]
+impl AuthService {
+    // Blocks forever. Every login is handled on its own thread, so a slow client doesn't hold up the rest.
+    pub fn run(self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Auth service listening on {}", addr);
+        let service = Arc::new(self);
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Auth connection failed: {}", err);
+                    continue;
+                }
+            };
+            let peer = stream.peer_addr().ok();
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_LOGINS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                warn!("Too many logins at once, dropped the connection from {:?}", peer);
+                continue;
+            }
+            let service = service.clone();
+            let active = active.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = service.handle(stream) {
+                    warn!("Auth request from {:?} failed: {}", peer, err);
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        Ok(())
+    }
+
+    // Runs the service on its own thread next to the game server.
+    pub fn spawn(self, addr: SocketAddr) {
+        std::thread::spawn(move || {
+            if let Err(err) = self.run(addr) {
+                warn!("Auth service stopped: {}", err);
+            }
+        });
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> Result<(), AuthError> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let request: AuthRequest = read_message(&mut stream)?;
+        let username = request.credentials.username().to_string();
+        let checked = self
+            .check_version(&request.version)
+            .and_then(|_| self.check_compression(request.game_port, request.compression));
+        let response = match checked.and_then(|_| self.login(&request.credentials)) {
+            Ok(player_id) => {
+                // Point the client at the address it reached us on, that's the one it can see.
+                let game_addr = SocketAddr::new(stream.local_addr()?.ip(), request.game_port);
+                match self.issue_token(game_addr, player_id) {
+                    Ok(token) => {
+                        info!("Issued connect token for {:?} (player {})", username, player_id);
+                        AuthResponse::Token(token)
+                    }
+                    Err(err) => {
+                        warn!("Failed to issue connect token for {:?}: {}", username, err);
+                        AuthResponse::Rejected("server error".to_string())
+                    }
+                }
+            }
+            Err(reason) => {
+                // Debug, so a name that never got registered can't put a newline in the log.
+                info!("Rejected login for {:?}: {}", username, reason);
+                AuthResponse::Rejected(reason)
+            }
+        };
+        write_message(&mut stream, &response)
+    }
+
+    fn check_version(&self, client: &ProtocolVersion) -> Result<(), String> {
+        if client.id == self.version.id {
+            return Ok(());
+        }
+        if client.game == self.version.game {
+            // Same version number, different protocol. Two builds from different commits.
+            return Err(format!("client build {} doesn't match the server's {}", client, self.version));
+        }
+        Err(
+            format!(
+                "the server runs version {}, this client is {}. Update to play",
+                self.version.game,
+                client.game
+            )
+        )
+    }
+
+    // Only the kind has to match, the zstd level only matters to whoever compresses.
+    fn check_compression(&self, game_port: u16, client: CompressionConfig) -> Result<(), String> {
+        let Some(server) = self.transports.get(&game_port) else {
+            return Err(format!("the server doesn't listen on port {}", game_port));
+        };
+        if compression_name(*server) == compression_name(client) {
+            return Ok(());
+        }
+        Err(
+            format!(
+                "port {} uses {} compression, this client uses {}. Change compression in the client settings to match",
+                game_port,
+                compression_name(*server),
+                compression_name(client)
+            )
+        )
+    }
+
+    // Checks the credentials and returns the account's player id, creating the account if needed.
+    // Deliberately doesn't say whether the user or the password was wrong.
+    // Hashing happens outside the lock, it's slow on purpose and would hold up every other login.
+    fn login(&self, credentials: &Credentials) -> Result<u64, String> {
+        let wrong = || "wrong username or password".to_string();
+        let server_error = |err: &dyn fmt::Display| {
+            warn!("{}", err);
+            "server error".to_string()
+        };
+
+        let (book, player_id) = match credentials {
+            Credentials::Password { username, password } => {
+                let stored = self.accounts
+                    .lock()
+                    .store.accounts.get(username)
+                    .map(|account| account.password_hash.clone());
+                let hash = stored.or_else(|| self.credentials.users.get(username).cloned());
+                let Some(hash) = hash else {
+                    verify_password(password, dummy_hash());
+                    return Err(wrong());
+                };
+                if !verify_password(password, &hash) {
+                    return Err(wrong());
+                }
+                let mut book = self.accounts.lock();
+                let player_id = match book.store.accounts.get(username) {
+                    Some(account) => account.player_id,
+                    // A seeded user logging in for the first time.
+                    None => book.store.create(username, hash),
+                };
+                (book, player_id)
+            }
+            Credentials::Invite { username, password, code } => {
+                if !self.credentials.invite_codes.contains(code) {
+                    return Err("unknown invite code".to_string());
+                }
+                check_username(username)?;
+                let hash = hash_password(password).map_err(|err| server_error(&err))?;
+                let mut book = self.accounts.lock();
+                let taken =
+                    book.store.accounts.contains_key(username) ||
+                    self.credentials.users.contains_key(username);
+                if taken {
+                    return Err("username is taken".to_string());
+                }
+                let player_id = book.store.create(username, hash);
+                (book, player_id)
+            }
+        };
+
+        if book.online.contains(&player_id) {
+            return Err("already logged in".to_string());
+        }
+        // Losing a freshly created account would hand its id to someone else after a restart.
+        book.save().map_err(|err| server_error(&err))?;
+        Ok(player_id)
+    }
+
+    fn issue_token(&self, game_addr: SocketAddr, player_id: u64) -> Result<Vec<u8>, String> {
+        let token = ConnectToken::build(game_addr, self.version.id, player_id, self.private_key)
+            .expire_seconds(self.token_expire_secs)
+            .generate()
+            .map_err(|err| err.to_string())?;
+        let bytes: [u8; CONNECT_TOKEN_BYTES] = token
+            .try_into_bytes()
+            .map_err(|err| err.to_string())?;
+        Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::utils::scratch::ScratchDir;
+
+    const PORT: u16 = 5000;
+
+    fn service(dir: &ScratchDir) -> AuthService {
+        let credentials = CredentialStore {
+            users: [("alice".to_string(), hash_password("secret").unwrap())].into_iter().collect(),
+            invite_codes: ["welcome".to_string()].into_iter().collect(),
+        };
+        AuthService {
+            credentials,
+            accounts: Accounts::load(dir).unwrap(),
+            version: ProtocolVersion::current(),
+            transports: [(PORT, CompressionConfig::Lz4)].into_iter().collect(),
+            private_key: [7; 32],
+            token_expire_secs: 30,
+        }
+    }
+
+    fn password(username: &str, password: &str) -> Credentials {
+        Credentials::Password { username: username.to_string(), password: password.to_string() }
+    }
+
+    fn invite(username: &str, code: &str) -> Credentials {
+        Credentials::Invite { username: username.to_string(), password: "hunter2".to_string(), code: code.to_string() }
+    }
+
+    #[test]
+    fn wrong_password_and_unknown_user_look_the_same() {
+        let dir = ScratchDir::new("auth-wrong");
+        let service = service(&dir);
+        assert!(service.login(&password("alice", "secret")).is_ok());
+        let wrong_password = service.login(&password("alice", "guess")).unwrap_err();
+        let unknown_user = service.login(&password("mallory", "secret")).unwrap_err();
+        assert_eq!(wrong_password, unknown_user);
+    }
+
+    #[test]
+    fn invites_need_a_known_code_and_a_free_name() {
+        let dir = ScratchDir::new("auth-invite");
+        let service = service(&dir);
+        assert_eq!(service.login(&invite("bob", "guessed")), Err("unknown invite code".to_string()));
+        // Seeded users count as taken even before they first log in.
+        assert_eq!(service.login(&invite("alice", "welcome")), Err("username is taken".to_string()));
+
+        let player_id = service.login(&invite("bob", "welcome")).unwrap();
+        assert_eq!(service.login(&invite("bob", "welcome")), Err("username is taken".to_string()));
+        assert_eq!(service.login(&password("bob", "hunter2")), Ok(player_id));
+    }
+
+    #[test]
+    fn usernames_are_checked_when_registering() {
+        let dir = ScratchDir::new("auth-names");
+        let service = service(&dir);
+        for name in ["", "ab", "two words", "line\nbreak", "tab\there", "bell\u{7}", "ünïcode", &"x".repeat(16 * 1024)] {
+            assert!(service.login(&invite(name, "welcome")).is_err(), "{:?} got registered", name);
+        }
+        assert!(service.accounts.lock().store.accounts.is_empty());
+        assert!(service.login(&invite("Night_Owl-3", "welcome")).is_ok());
+    }
+
+    #[test]
+    fn a_player_can_only_be_logged_in_once() {
+        let dir = ScratchDir::new("auth-online");
+        let service = service(&dir);
+        let player_id = service.login(&password("alice", "secret")).unwrap();
+        service.accounts.lock().online.insert(player_id);
+        assert_eq!(service.login(&password("alice", "secret")), Err("already logged in".to_string()));
+    }
+
+    #[test]
+    fn version_and_compression_have_to_match() {
+        let dir = ScratchDir::new("auth-match");
+        let service = service(&dir);
+        assert!(service.check_version(&ProtocolVersion::current()).is_ok());
+        let older = ProtocolVersion { game: "0.0.0".to_string(), id: 1 };
+        assert!(service.check_version(&older).unwrap_err().contains("Update to play"));
+        let other_build = ProtocolVersion { id: ProtocolVersion::current().id ^ 1, ..ProtocolVersion::current() };
+        assert!(service.check_version(&other_build).is_err());
+
+        assert!(service.check_compression(PORT, CompressionConfig::Lz4).is_ok());
+        assert!(service.check_compression(PORT, CompressionConfig::None).unwrap_err().contains("uses lz4 compression"));
+        assert!(service.check_compression(PORT + 1, CompressionConfig::Lz4).is_err());
+    }
+
+    #[test]
+    fn issued_tokens_parse() {
+        let dir = ScratchDir::new("auth-token");
+        let service = service(&dir);
+        let bytes = service.issue_token(SocketAddr::from(([127, 0, 0, 1], PORT)), 42).unwrap();
+        assert!(ConnectToken::try_from_bytes(&bytes).is_ok());
+    }
+}
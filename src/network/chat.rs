@@ -0,0 +1,550 @@
+// Text chat. Clients send a ChatRequest on the ChatChannel, the server cleans it up, works out who
+// gets to hear it and sends them a ChatMessage. Anything it turns down goes back to the sender
+// as a System message saying why, so nothing just silently disappears.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use bevy::asset::ron;
+use bevy::prelude::*;
+use lightyear::prelude::{ client, server, ClientId, NetworkTarget };
+use rustc_hash::{ FxHashMap, FxHashSet };
+use serde::{ Deserialize, Serialize };
+
+use crate::network::protocol::{ ChatChannel, ChatMessage, ChatRequest, ChatScope, ChatSender, PlayerList };
+use crate::network::rpc::AppRpcExt;
+#[cfg(feature = "client")]
+use crate::network::rpc::{ RpcReply, RpcSender };
+#[cfg(feature = "server")]
+use crate::game::accounts::{ player_id, Accounts };
+#[cfg(feature = "server")]
+use crate::game::player::{ Parked, Team };
+#[cfg(feature = "server")]
+use crate::network::protocol::{ PlayerId, PlayerPosition };
+#[cfg(feature = "server")]
+use crate::network::server::Global;
+#[cfg(feature = "server")]
+use crate::network::traffic::{ clients_reached, ChannelTraffic };
+#[cfg(feature = "server")]
+use crate::utils::settings::{ ChatSettings, Settings };
+
+// How many messages the client keeps around for the chat panel.
+pub const CHAT_LOG_LENGTH: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChatRejection {
+    Empty,
+    TooLong {
+        max: usize,
+    },
+    RateLimited,
+    UnknownRecipient(String),
+    NoTeam,
+}
+
+impl fmt::Display for ChatRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatRejection::Empty => write!(f, "Nothing to send."),
+            ChatRejection::TooLong { max } => write!(f, "Messages can be at most {} characters.", max),
+            ChatRejection::RateLimited => write!(f, "You're sending messages too fast."),
+            ChatRejection::UnknownRecipient(name) => write!(f, "{} isn't online.", name),
+            ChatRejection::NoTeam => write!(f, "You're not on a team."),
+        }
+    }
+}
+
+impl std::error::Error for ChatRejection {}
+
+// Why the PlayerList rpc turned someone down.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PlayerListError {
+    RateLimited,
+}
+
+impl fmt::Display for PlayerListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerListError::RateLimited => write!(f, "You're asking who's online too often."),
+        }
+    }
+}
+
+impl std::error::Error for PlayerListError {}
+
+// Words masked out of chat. Read from ChatSettings::filter.
+#[derive(Resource, Debug, Clone, Default, Deserialize)]
+pub struct ChatFilter {
+    #[serde(default)]
+    pub words: FxHashSet<String>,
+}
+
+impl ChatFilter {
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs
+            ::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let filter: ChatFilter = ron::de
+            ::from_str(&contents)
+            .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+        Ok(Self { words: filter.words.iter().map(|word| word.to_lowercase()).collect() })
+    }
+
+    // Replaces filtered words with as many asterisks. Only whole words count, so "class" stays intact.
+    pub fn mask(&self, text: &str) -> String {
+        let mut masked = String::with_capacity(text.len());
+        let mut word = String::new();
+        let flush = |word: &mut String, masked: &mut String| {
+            if self.words.contains(&word.to_lowercase()) {
+                masked.extend(std::iter::repeat_n('*', word.chars().count()));
+            } else {
+                masked.push_str(word);
+            }
+            word.clear();
+        };
+        for c in text.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut masked);
+                masked.push(c);
+            }
+        }
+        flush(&mut word, &mut masked);
+        masked
+    }
+}
+
+// Strips control characters (newlines included, a message is one line) and surrounding whitespace,
+// then checks the length and masks filtered words.
+#[cfg(feature = "server")]
+pub fn clean_message(
+    text: &str,
+    settings: &ChatSettings,
+    filter: &ChatFilter
+) -> Result<String, ChatRejection> {
+    let cleaned: String = text
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        return Err(ChatRejection::Empty);
+    }
+    if cleaned.chars().count() > settings.max_length {
+        return Err(ChatRejection::TooLong { max: settings.max_length });
+    }
+    Ok(filter.mask(cleaned))
+}
+
+// When each client's recent messages were sent, oldest first.
+#[cfg(feature = "server")]
+#[derive(Resource, Default)]
+pub struct ChatRateLimits(FxHashMap<ClientId, VecDeque<Duration>>);
+
+#[cfg(feature = "server")]
+impl ChatRateLimits {
+    // A sliding window, so a burst is fine as long as it stays under the limit.
+    // Every request the limit lets through counts towards it, even if it's turned down for something else.
+    pub fn allow(&mut self, client_id: ClientId, now: Duration, settings: &ChatSettings) -> bool {
+        let window = Duration::from_secs_f32(settings.rate_limit_secs);
+        let sent = self.0.entry(client_id).or_default();
+        while sent.front().is_some_and(|time| now.saturating_sub(*time) >= window) {
+            sent.pop_front();
+        }
+        if sent.len() >= (settings.rate_limit_messages as usize) {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+#[cfg(feature = "server")]
+fn sender_name(accounts: Option<&Accounts>, client_id: ClientId) -> String {
+    let id = player_id(client_id);
+    accounts
+        .and_then(|accounts| accounts.lock().store.username(id).map(str::to_string))
+        .unwrap_or_else(|| format!("Player {}", id))
+}
+
+// Who hears a message, or why nobody does.
+#[cfg(feature = "server")]
+fn recipients(
+    client_id: ClientId,
+    scope: &ChatScope,
+    settings: &ChatSettings,
+    accounts: Option<&Accounts>,
+    global: &Global,
+    players: &Query<(&PlayerId, &PlayerPosition, Option<&Team>), Without<Parked>>
+) -> Result<NetworkTarget, ChatRejection> {
+    let sender = global.client_id_to_entity_id
+        .get(&client_id)
+        .and_then(|entity| players.get(*entity).ok());
+
+    match scope {
+        ChatScope::Global => Ok(NetworkTarget::All),
+        // No character to stand next to, nobody to hear it but yourself.
+        ChatScope::Proximity => {
+            let Some((_, position, _)) = sender else {
+                return Ok(NetworkTarget::Single(client_id));
+            };
+            let radius = settings.proximity_radius;
+            let nearby = players
+                .iter()
+                .filter(|(_, other, _)| other.distance(position.0) <= radius)
+                .map(|(PlayerId(other), ..)| *other)
+                .collect();
+            Ok(NetworkTarget::Only(nearby))
+        }
+        ChatScope::Team => {
+            let Some((_, _, Some(team))) = sender else {
+                return Err(ChatRejection::NoTeam);
+            };
+            let teammates = players
+                .iter()
+                .filter(|(_, _, other)| *other == Some(team))
+                .map(|(PlayerId(other), ..)| *other)
+                .collect();
+            Ok(NetworkTarget::Only(teammates))
+        }
+        // The sender gets a copy too, so they can see what they sent.
+        ChatScope::Whisper(name) => {
+            let target = accounts
+                .and_then(|accounts| {
+                    accounts.lock().store.accounts.get(name).map(|account| account.player_id)
+                })
+                .and_then(|id| {
+                    players
+                        .iter()
+                        .map(|(PlayerId(other), ..)| *other)
+                        .find(|other| player_id(*other) == id)
+                })
+                .ok_or_else(|| ChatRejection::UnknownRecipient(name.clone()))?;
+            if target == client_id {
+                Ok(NetworkTarget::Single(client_id))
+            } else {
+                Ok(NetworkTarget::Only(vec![target, client_id]))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[allow(clippy::too_many_arguments)]
+fn handle_chat_requests(
+    time: Res<Time>,
+    settings: Res<Settings>,
+    filter: Res<ChatFilter>,
+    accounts: Option<Res<Accounts>>,
+    global: Res<Global>,
+    mut limits: ResMut<ChatRateLimits>,
+    mut requests: EventReader<server::MessageEvent<ChatRequest>>,
+    mut connection: ResMut<server::ConnectionManager>,
+    mut traffic: ResMut<ChannelTraffic>,
+    players: Query<(&PlayerId, &PlayerPosition, Option<&Team>), Without<Parked>>
+) {
+    let settings = &settings.server.chat;
+    let accounts = accounts.as_deref();
+    for request in requests.read() {
+        let client_id = *request.context();
+        let request = request.message();
+
+        // Before anything else, so a flood of bad requests costs no more than a flood of good ones.
+        let routed = if limits.allow(client_id, time.elapsed(), settings) {
+            clean_message(&request.text, settings, &filter).and_then(|text| {
+                let target = recipients(client_id, &request.scope, settings, accounts, &global, &players)?;
+                Ok((text, target))
+            })
+        } else {
+            Err(ChatRejection::RateLimited)
+        };
+
+        let (mut message, target) = match routed {
+            Ok((text, target)) => {
+                let name = sender_name(accounts, client_id);
+                // Whispers are private, only who talked to whom ends up in the log.
+                match &request.scope {
+                    ChatScope::Whisper(to) => info!("[chat whisper] {} -> {}", name, to),
+                    scope => info!("[chat {:?}] {}: {}", scope, name, text),
+                }
+                let sender = ChatSender::Player { client_id, name };
+                (ChatMessage { sender, scope: request.scope.clone(), text }, target)
+            }
+            Err(rejection) => {
+                let message = ChatMessage {
+                    sender: ChatSender::System,
+                    scope: request.scope.clone(),
+                    text: rejection.to_string(),
+                };
+                (message, NetworkTarget::Single(client_id))
+            }
+        };
+        let clients = clients_reached(&connection, &target);
+        match connection.send_message_to_target::<ChatChannel, _>(&mut message, target) {
+            Ok(()) => traffic.count_bytes::<ChatChannel, _>(&message, clients),
+            Err(err) => warn!("Failed to send chat message: {}", err),
+        }
+    }
+}
+
+// Handler for the PlayerList rpc.
+#[cfg(feature = "server")]
+fn list_players(
+    In((client_id, ())): In<(ClientId, ())>,
+    time: Res<Time>,
+    settings: Res<Settings>,
+    accounts: Option<Res<Accounts>>,
+    mut limits: ResMut<ChatRateLimits>,
+    players: Query<&PlayerId, Without<Parked>>
+) -> Result<Vec<String>, PlayerListError> {
+    if !limits.allow(client_id, time.elapsed(), &settings.server.chat) {
+        return Err(PlayerListError::RateLimited);
+    }
+    let accounts = accounts.as_deref();
+    let mut names: Vec<_> = players
+        .iter()
+        .map(|PlayerId(other)| sender_name(accounts, *other))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(feature = "server")]
+fn forget_rate_limits(
+    mut disconnections: EventReader<server::DisconnectEvent>,
+    mut limits: ResMut<ChatRateLimits>
+) {
+    for disconnection in disconnections.read() {
+        limits.0.remove(&disconnection.client_id);
+    }
+}
+
+// Chat still works without a filter, it just lets everything through.
+#[cfg(feature = "server")]
+fn load_chat_filter(mut commands: Commands, settings: Res<Settings>) {
+    let filter = match ChatFilter::from_file(&settings.server.chat.filter) {
+        Ok(filter) => filter,
+        Err(err) => {
+            warn!("{}. Chat will not be filtered.", err);
+            ChatFilter::default()
+        }
+    };
+    commands.insert_resource(filter);
+}
+
+#[cfg(feature = "server")]
+pub struct ChatServerPlugin;
+
+#[cfg(feature = "server")]
+impl Plugin for ChatServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatRateLimits>();
+        app.add_systems(Startup, load_chat_filter);
+        app.add_systems(Update, (handle_chat_requests, forget_rate_limits).chain());
+        app.add_rpc_handler::<PlayerList, _>(list_players);
+    }
+}
+
+// What the client has heard so far, newest last.
+#[cfg(feature = "client")]
+#[derive(Resource, Default)]
+pub struct ChatLog {
+    pub messages: VecDeque<ChatMessage>,
+}
+
+// Sent by the chat panel, forwarded to the server by send_chat.
+#[cfg(feature = "client")]
+#[derive(Event, Debug, Clone)]
+pub struct SendChat(pub ChatRequest);
+
+// Sent by the chat panel for "/who", answered with a System line listing who's online.
+#[cfg(feature = "client")]
+#[derive(Event, Debug, Clone)]
+pub struct AskWhoIsOnline;
+
+// Turns what was typed into a request. Plain text goes to everyone,
+// "/l" is local (proximity), "/t" is team and "/w name" whispers.
+#[cfg(feature = "client")]
+pub fn parse_chat_input(input: &str) -> Option<ChatRequest> {
+    let input = input.trim();
+    let (scope, text) = match input.split_once(' ') {
+        Some(("/l", text)) => (ChatScope::Proximity, text),
+        Some(("/t", text)) => (ChatScope::Team, text),
+        Some(("/w", rest)) => {
+            let (name, text) = rest.trim_start().split_once(' ')?;
+            (ChatScope::Whisper(name.to_string()), text)
+        }
+        _ => (ChatScope::Global, input),
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    Some(ChatRequest { scope, text: text.to_string() })
+}
+
+#[cfg(feature = "client")]
+impl ChatLog {
+    pub fn push(&mut self, message: ChatMessage) {
+        self.messages.push_back(message);
+        if self.messages.len() > CHAT_LOG_LENGTH {
+            self.messages.pop_front();
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+fn receive_chat(mut events: EventReader<client::MessageEvent<ChatMessage>>, mut log: ResMut<ChatLog>) {
+    for event in events.read() {
+        log.push(event.message().clone());
+    }
+}
+
+#[cfg(feature = "client")]
+fn ask_who_is_online(mut asks: EventReader<AskWhoIsOnline>, mut rpc: RpcSender<PlayerList>) {
+    for _ in asks.read() {
+        if let Err(err) = rpc.send(()) {
+            warn!("Failed to ask for the player list: {}", err);
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+fn show_player_list(mut replies: EventReader<RpcReply<PlayerList>>, mut log: ResMut<ChatLog>) {
+    for reply in replies.read() {
+        let text = match &reply.result {
+            Ok(names) if names.is_empty() => "Nobody is online.".to_string(),
+            Ok(names) => format!("Online: {}", names.join(", ")),
+            Err(err) => err.to_string(),
+        };
+        log.push(ChatMessage { sender: ChatSender::System, scope: ChatScope::Global, text });
+    }
+}
+
+#[cfg(feature = "client")]
+fn send_chat(
+    mut requests: EventReader<SendChat>,
+    mut connection: ResMut<client::ConnectionManager>
+) {
+    for SendChat(request) in requests.read() {
+        let mut request = request.clone();
+        if let Err(err) = connection.send_message::<ChatChannel, _>(&mut request) {
+            warn!("Failed to send chat message: {}", err);
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+pub struct ChatClientPlugin;
+
+#[cfg(feature = "client")]
+impl Plugin for ChatClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatLog>();
+        app.add_event::<SendChat>();
+        app.add_event::<AskWhoIsOnline>();
+        app.add_rpc_client::<PlayerList>();
+        app.add_systems(Update, (receive_chat, send_chat, ask_who_is_online, show_player_list));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(words: &[&str]) -> ChatFilter {
+        ChatFilter { words: words.iter().map(|word| word.to_string()).collect() }
+    }
+
+    #[test]
+    fn only_whole_filtered_words_are_masked() {
+        let filter = filter(&["heck", "darn"]);
+        assert_eq!(filter.mask("Heck, what the darn heckin' class"), "****, what the **** heckin' class");
+        assert_eq!(filter.mask("héck heck!"), "héck ****!");
+        assert_eq!(filter.mask(""), "");
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn messages_are_cleaned_before_they_are_checked() {
+        let settings = ChatSettings { max_length: 5, ..Default::default() };
+        let filter = filter(&["heck"]);
+        assert_eq!(clean_message("  he\nck\t ", &settings, &filter), Ok("****".to_string()));
+        assert_eq!(clean_message(" \r\n\t", &settings, &filter), Err(ChatRejection::Empty));
+        assert_eq!(clean_message("ééééé", &settings, &filter), Ok("ééééé".to_string()));
+        assert_eq!(clean_message("hello!", &settings, &filter), Err(ChatRejection::TooLong { max: 5 }));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn rate_limit_slides_and_is_per_client() {
+        let settings = ChatSettings { rate_limit_messages: 2, rate_limit_secs: 10.0, ..Default::default() };
+        let mut limits = ChatRateLimits::default();
+        let (alice, bob) = (ClientId::Netcode(1), ClientId::Netcode(2));
+        let secs = Duration::from_secs;
+
+        assert!(limits.allow(alice, secs(0), &settings));
+        assert!(limits.allow(alice, secs(5), &settings));
+        assert!(!limits.allow(alice, secs(9), &settings));
+        assert!(limits.allow(bob, secs(9), &settings));
+        // The first message has left the window, the turned down one never counted.
+        assert!(limits.allow(alice, secs(10), &settings));
+        assert!(!limits.allow(alice, secs(14), &settings));
+        assert!(limits.allow(alice, secs(15), &settings));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn team_chat_reaches_teammates_only() {
+        use bevy::ecs::system::SystemState;
+        use bevy::math::Vec3A;
+
+        let mut world = World::new();
+        let mut global = Global::default();
+        let mut join = |world: &mut World, id: u64, team: Option<u32>| {
+            let client_id = ClientId::Netcode(id);
+            let mut player = world.spawn((PlayerId(client_id), PlayerPosition(Vec3A::ZERO)));
+            if let Some(team) = team {
+                player.insert(Team(team));
+            }
+            global.client_id_to_entity_id.insert(client_id, player.id());
+            client_id
+        };
+        let alice = join(&mut world, 1, Some(7));
+        let bob = join(&mut world, 2, Some(7));
+        let carol = join(&mut world, 3, Some(8));
+        let dave = join(&mut world, 4, None);
+        let erin = join(&mut world, 5, Some(7));
+        let erins_player = global.client_id_to_entity_id[&erin];
+        world.entity_mut(erins_player).insert(Parked(Timer::default()));
+
+        let mut players =
+            SystemState::<Query<(&PlayerId, &PlayerPosition, Option<&Team>), Without<Parked>>>::new(&mut world);
+        let players = players.get(&world);
+        let settings = ChatSettings::default();
+        let route = |client_id| recipients(client_id, &ChatScope::Team, &settings, None, &global, &players);
+
+        let Ok(NetworkTarget::Only(heard)) = route(alice) else {
+            panic!("team chat should go to the team");
+        };
+        let heard: FxHashSet<_> = heard.into_iter().collect();
+        assert_eq!(heard, FxHashSet::from_iter([alice, bob]));
+        assert!(matches!(route(carol), Ok(NetworkTarget::Only(heard)) if heard == vec![carol]));
+        assert!(matches!(route(dave), Err(ChatRejection::NoTeam)));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn chat_input_picks_the_scope() {
+        let request = |scope, text: &str| Some(ChatRequest { scope, text: text.to_string() });
+        assert_eq!(parse_chat_input("  hello there "), request(ChatScope::Global, "hello there"));
+        assert_eq!(parse_chat_input("/l hi"), request(ChatScope::Proximity, "hi"));
+        assert_eq!(parse_chat_input("/w  bob psst, hey"), request(ChatScope::Whisper("bob".to_string()), "psst, hey"));
+        assert_eq!(parse_chat_input("/t hi"), request(ChatScope::Team, "hi"));
+        assert_eq!(parse_chat_input("/w bob"), None);
+        assert_eq!(parse_chat_input("   "), None);
+    }
+}
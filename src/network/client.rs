@@ -1,28 +1,104 @@
-use bevy::prelude::{ Commands, Camera2d, Startup, App, Plugin, default };
+use bevy::prelude::{
+    Commands,
+    Camera2d,
+    Startup,
+    Update,
+    App,
+    Plugin,
+    default,
+    Entity,
+    Query,
+    Res,
+    Added,
+    Or,
+    With,
+    KeyCode,
+    info,
+    error,
+    Event,
+    EventReader,
+    Resource,
+    ResMut,
+};
+use bevy::tasks::{ block_on, futures_lite::future, IoTaskPool, Task };
+use leafwing_input_manager::prelude::InputMap;
 use client::{ Authentication, ClientTransport, NetConfig, NetcodeConfig };
 use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
 
 use lightyear::client::plugin::ClientPlugins;
 use lightyear::prelude::client::*;
+use lightyear::prelude::CompressionConfig;
 pub use lightyear::prelude::*;
 use lightyear::shared::config::Mode;
 
 use crate::network::shared::{ shared_config, SharedNetworkingPlugin, SERVER_ADDR };
+use crate::network::protocol::{ PlayerActions, PlayerId };
+use crate::network::auth::{ fetch_token, AuthError, Credentials };
+use crate::network::chat::ChatClientPlugin;
+use crate::game::app::Cli;
+use crate::utils::settings::Settings;
 
-pub struct ClientNetworkingPlugin;
+pub struct ClientNetworkingPlugin {
+    pub login: Login,
+}
+
+// Where and as whom to log in before connecting.
+#[derive(Resource, Clone)]
+pub struct Login {
+    pub auth_addr: SocketAddr,
+    pub game_port: u16,
+    // The auth service checks it against the port's, see network::auth.
+    pub compression: CompressionConfig,
+    pub credentials: Option<Credentials>,
+}
+
+impl Login {
+    pub fn new(cli: &Cli, settings: &Settings) -> Self {
+        let client = &settings.client;
+        Self {
+            auth_addr: SocketAddr::new(IpAddr::V4(client.server_addr), client.auth_port),
+            game_port: client.server_port,
+            compression: client.compression,
+            credentials: cli.credentials(),
+        }
+    }
+}
+
+// Send this to connect. Fetches a connect token first unless we're the host, who needs none.
+#[derive(Event, Default)]
+pub struct LoginRequest;
+
+#[derive(Resource)]
+struct PendingLogin(Task<Result<ConnectToken, AuthError>>);
+
+// Why the last login didn't work, for the UI. Cleared when the next one starts.
+#[derive(Resource, Default, Debug, Clone)]
+pub struct LoginError(pub Option<String>);
 
 // Simply fetch client address
 const CLIENT_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4000);
 
+// How the local player is predicted. Rollbacks themselves are handled by lightyear,
+// this just decides how long we spend visually easing into the corrected position.
+pub fn prediction_config() -> PredictionConfig {
+    PredictionConfig {
+        correction_ticks_factor: 1.5,
+        ..default()
+    }
+}
+
+// Remote players are drawn this far in the past so there's always a newer snapshot to move towards.
+// Two send intervals means a single late or lost update doesn't make them stutter.
+pub fn interpolation_config() -> InterpolationConfig {
+    InterpolationConfig {
+        delay: InterpolationDelay::default().with_send_interval_ratio(2.0),
+    }
+}
+
 // Builds the client plugin for when app is run as client
 fn build_client_plugin() -> ClientPlugins {
     // Specifies how the client should connect to server
-    let auth = Authentication::Manual {
-        server_addr: SERVER_ADDR,
-        client_id: 0,
-        private_key: Key::default(),
-        protocol_id: 0,
-    };
+    let auth = Authentication::None;
 
     // Specifies transport type to use during communication
     let io = IoConfig {
@@ -55,12 +131,108 @@ fn build_client_plugin() -> ClientPlugins {
 impl Plugin for ClientNetworkingPlugin {
     fn build(&self, app: &mut App) {
         // Add client-specific systems/plugins
+        app.insert_resource(self.login.clone());
+        app.init_resource::<LoginError>();
+        app.add_event::<LoginRequest>();
         app.add_systems(Startup, connect_client);
+        app.add_systems(Update, (start_login, finish_login, add_input_map));
+        app.add_plugins(ChatClientPlugin);
     }
 }
 
 // Quick startup fn to connect the client using lightyear commands
 fn connect_client(mut commands: Commands) {
     commands.spawn(Camera2d);
-    commands.connect_client();
+    commands.send_event(LoginRequest);
+}
+
+// The auth round trip is blocking io, so it runs on the io pool and finish_login picks up the result.
+fn start_login(
+    mut commands: Commands,
+    mut requests: EventReader<LoginRequest>,
+    config: Res<ClientConfig>,
+    login: Res<Login>,
+    mut login_error: ResMut<LoginError>,
+    pending: Option<Res<PendingLogin>>
+) {
+    if requests.read().count() == 0 || pending.is_some() {
+        return;
+    }
+    login_error.0 = None;
+    if let NetConfig::Local { .. } = config.net {
+        commands.connect_client();
+        return;
+    }
+    let Some(credentials) = login.credentials.clone() else {
+        let reason = "Can't log in without credentials, pass --username and --password";
+        error!("{}", reason);
+        login_error.0 = Some(reason.to_string());
+        return;
+    };
+
+    info!("Logging in as {}", credentials.username());
+    let (auth_addr, game_port, compression) = (login.auth_addr, login.game_port, login.compression);
+    let task = IoTaskPool::get().spawn(async move {
+        fetch_token(auth_addr, credentials, game_port, compression)
+    });
+    commands.insert_resource(PendingLogin(task));
+}
+
+fn finish_login(
+    mut commands: Commands,
+    mut config: ResMut<ClientConfig>,
+    mut login_error: ResMut<LoginError>,
+    pending: Option<ResMut<PendingLogin>>
+) {
+    let Some(mut pending) = pending else {
+        return;
+    };
+    let Some(result) = block_on(future::poll_once(&mut pending.0)) else {
+        return;
+    };
+    commands.remove_resource::<PendingLogin>();
+
+    match result {
+        Ok(token) => {
+            if let NetConfig::Netcode { auth, .. } = &mut config.net {
+                *auth = Authentication::Token(token);
+            }
+            commands.connect_client();
+        }
+        Err(err) => {
+            error!("{}", err);
+            login_error.0 = Some(err.to_string());
+        }
+    }
+}
+
+// WASD and the arrow keys both walk.
+fn player_input_map() -> InputMap<PlayerActions> {
+    InputMap::new([
+        (PlayerActions::Up, KeyCode::KeyW),
+        (PlayerActions::Down, KeyCode::KeyS),
+        (PlayerActions::Left, KeyCode::KeyA),
+        (PlayerActions::Right, KeyCode::KeyD),
+    ])
+        .with(PlayerActions::Up, KeyCode::ArrowUp)
+        .with(PlayerActions::Down, KeyCode::ArrowDown)
+        .with(PlayerActions::Left, KeyCode::ArrowLeft)
+        .with(PlayerActions::Right, KeyCode::ArrowRight)
+}
+
+// Only our own player gets an input map, lightyear then sends its inputs to the server.
+// When hosting there is no predicted copy, so the server's entity is driven directly.
+#[allow(clippy::type_complexity)]
+fn add_input_map(
+    mut commands: Commands,
+    connection: Res<ClientConnection>,
+    players: Query<(Entity, &PlayerId), Or<(Added<Predicted>, Added<Replicating>)>>
+) {
+    let local_id = connection.id();
+    for (entity, player_id) in players.iter() {
+        if player_id.0 == local_id {
+            commands.entity(entity).insert(player_input_map());
+            info!("Input map added to local player");
+        }
+    }
 }
@@ -0,0 +1,121 @@
+// Spatial interest management. The world is cut into square cells, each cell is a lightyear room,
+// and every client is subscribed to the cells around its own player. Anything with a position
+// lives in the room of the cell it stands in, so clients only hear about what's near them.
+
+use bevy::math::IVec2;
+use bevy::prelude::{ Added, Changed, Commands, Component, Entity, Or, Query, Res, ResMut, With };
+use lightyear::prelude::server::{ RoomId, RoomManager };
+use lightyear::prelude::{ ClientId, Replicating };
+
+use crate::game::player::Parked;
+use crate::network::protocol::{ PlayerId, PlayerPosition };
+use crate::network::server::Global;
+use crate::utils::settings::Settings;
+
+// Cell rooms have bit 62 set, which keeps them clear of the shared rooms at the bottom
+// and the private rooms in the top half. Each coordinate gets 31 bits.
+const CELL_ROOM_FLAG: u64 = 1 << 62;
+const CELL_COORD_MASK: u64 = (1 << 31) - 1;
+
+// Server-side bookkeeping of which cell an entity was last put in.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GridCell(pub IVec2);
+
+pub fn cell_of(position: &PlayerPosition, cell_size: f32) -> IVec2 {
+    IVec2::new((position.x / cell_size).floor() as i32, (position.y / cell_size).floor() as i32)
+}
+
+pub fn cell_room(cell: IVec2) -> RoomId {
+    let x = (cell.x as u32 as u64) & CELL_COORD_MASK;
+    let y = (cell.y as u32 as u64) & CELL_COORD_MASK;
+    RoomId(CELL_ROOM_FLAG | (x << 31) | y)
+}
+
+// Every cell within the view radius of the center, as a square so corners aren't cut off.
+pub fn cells_in_view(center: IVec2, radius: i32) -> impl Iterator<Item = IVec2> {
+    (-radius..=radius).flat_map(move |x| {
+        (-radius..=radius).map(move |y| center + IVec2::new(x, y))
+    })
+}
+
+fn in_view(center: IVec2, cell: IVec2, radius: i32) -> bool {
+    let offset = (cell - center).abs();
+    offset.x <= radius && offset.y <= radius
+}
+
+pub fn subscribe(room_manager: &mut RoomManager, client_id: ClientId, center: IVec2, radius: i32) {
+    for cell in cells_in_view(center, radius) {
+        room_manager.add_client(client_id, cell_room(cell));
+    }
+}
+
+pub fn unsubscribe(room_manager: &mut RoomManager, client_id: ClientId, center: IVec2, radius: i32) {
+    for cell in cells_in_view(center, radius) {
+        room_manager.remove_client(client_id, cell_room(cell));
+    }
+}
+
+// Only touches the cells that actually entered or left the view, so walking across a cell border
+// costs one row of rooms instead of the whole square.
+fn move_subscription(
+    room_manager: &mut RoomManager,
+    client_id: ClientId,
+    from: IVec2,
+    to: IVec2,
+    radius: i32
+) {
+    for cell in cells_in_view(from, radius) {
+        if !in_view(to, cell, radius) {
+            room_manager.remove_client(client_id, cell_room(cell));
+        }
+    }
+    for cell in cells_in_view(to, radius) {
+        if !in_view(from, cell, radius) {
+            room_manager.add_client(client_id, cell_room(cell));
+        }
+    }
+}
+
+// Moves positioned entities between cell rooms, and drags their owner's subscriptions along for players.
+// Things that are given a position before they start replicating get picked up once they do.
+#[allow(clippy::type_complexity)]
+pub fn update_grid_cells(
+    settings: Res<Settings>,
+    mut global: ResMut<Global>,
+    mut room_manager: ResMut<RoomManager>,
+    mut commands: Commands,
+    moved: Query<
+        (Entity, &PlayerPosition, Option<&GridCell>, Option<&PlayerId>, Option<&Parked>),
+        (Or<(Changed<PlayerPosition>, Added<Replicating>)>, With<Replicating>)
+    >
+) {
+    let cell_size = settings.server.grid_cell_size;
+    let radius = settings.server.view_radius as i32;
+
+    for (entity, position, current, player_id, parked) in moved.iter() {
+        let cell = cell_of(position, cell_size);
+        let current = current.map(|current| current.0);
+        if current == Some(cell) {
+            continue;
+        }
+
+        if let Some(current) = current {
+            room_manager.remove_entity(entity, cell_room(current));
+        }
+        room_manager.add_entity(entity, cell_room(cell));
+        commands.entity(entity).insert(GridCell(cell));
+
+        // A parked player's client isn't subscribed to anything.
+        let Some(PlayerId(client_id)) = player_id else {
+            continue;
+        };
+        if parked.is_some() {
+            continue;
+        }
+        match current {
+            Some(current) => move_subscription(&mut room_manager, *client_id, current, cell, radius),
+            None => subscribe(&mut room_manager, *client_id, cell, radius),
+        }
+        global.client_id_to_room_id.insert(*client_id, cell_room(cell));
+    }
+}
@@ -2,5 +2,14 @@
 pub mod client;
 #[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "server")]
+pub mod grid;
+#[cfg(feature = "server")]
+pub mod priority;
 pub mod shared;
+pub mod auth;
+pub mod chat;
 pub mod protocol;
+pub mod rpc;
+pub mod traffic;
+pub mod version;
@@ -0,0 +1,184 @@
+// Replication priorities, so a client on a tight budget hears about what matters to it first.
+//
+// Lightyear gives every replication group a priority per client and only sends what fits under the
+// server's send_bandwidth_cap (ServerSettings), highest priority first. Whatever didn't fit keeps its
+// priority and gets its own added on top again next time, so starved updates climb until they go out.
+// All we do is pick the priorities: your own character first, then players, items and containers,
+// each less the further away they are from you. Items go by where their outermost holder is.
+// Containers are also only sent every CONTAINER_SEND_INTERVAL.
+//
+// Lightyear 0.18 has one PacketConfig limiter for the whole server, the same cap for every client and
+// no budget of their own. It doesn't count the updates it held back or dropped either, so there's
+// nothing to report about how a client is doing.
+
+use bevy::math::{ IVec2, Vec3A };
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use lightyear::prelude::server::{ ConnectionManager, RoomId, RoomManager };
+use lightyear::prelude::{ ClientId, ReplicationGroup, TimeManager };
+use lightyear::shared::replication::components::ReplicationGroupId;
+use rustc_hash::FxHashSet;
+
+use crate::game::items::{ Container, Item, ParentContainer };
+use crate::network::grid::{ cell_room, cells_in_view, GridCell };
+use crate::network::protocol::{ PlayerId, PlayerPosition };
+use crate::network::server::{ outermost_holder, private_room, Global };
+use crate::network::shared::SERVER_REPLICATION_INTERVAL;
+use crate::utils::settings::Settings;
+
+// Relative to the channel priorities in network::protocol, replication channels have 1.0.
+const OWN_PRIORITY: f32 = 2.0;
+const PLAYER_PRIORITY: f32 = 1.5;
+const ITEM_PRIORITY: f32 = 1.0;
+const CONTAINER_PRIORITY: f32 = 0.5;
+
+// What's in a chest can wait a little, it's only ever looked at up close.
+const CONTAINER_SEND_INTERVAL: Duration = Duration::from_millis(500);
+
+pub fn item_group() -> ReplicationGroup {
+    ReplicationGroup::new_from_entity().set_priority(ITEM_PRIORITY)
+}
+
+pub fn container_group() -> ReplicationGroup {
+    ReplicationGroup::new_from_entity()
+        .set_priority(CONTAINER_PRIORITY)
+        .set_send_frequency(CONTAINER_SEND_INTERVAL)
+}
+
+// Halves one grid cell away, a third two cells away and so on.
+fn distance_priority(base: f32, distance: f32, cell_size: f32) -> f32 {
+    base / (1.0 + distance / cell_size)
+}
+
+// Where something stands, or where whatever it's inside of stands. None while that isn't anywhere.
+fn position_of(entity: Entity, positions: &Query<&PlayerPosition>, parents: &Query<&ParentContainer>) -> Option<Vec3A> {
+    positions.get(outermost_holder(entity, parents)).ok().map(|position| position.0)
+}
+
+// Ticked like lightyear's own send timer, priorities only matter on the frames it sends.
+#[derive(Resource)]
+struct SendTimer(Timer);
+
+impl Default for SendTimer {
+    fn default() -> Self {
+        Self(Timer::new(SERVER_REPLICATION_INTERVAL, TimerMode::Repeating))
+    }
+}
+
+// Everything a client can see, see network::grid and network::server for the rooms.
+fn client_rooms(client_id: ClientId, center: Option<IVec2>, radius: i32) -> Vec<RoomId> {
+    let mut rooms = vec![private_room(client_id)];
+    if let Some(center) = center {
+        rooms.extend(cells_in_view(center, radius).map(cell_room));
+    }
+    rooms
+}
+
+// What a client should get first, before distance. None for anything that isn't replicated by priority.
+fn base_priority(entity: Entity, own: Entity, is_player: bool, is_item: Option<bool>) -> Option<f32> {
+    if entity == own {
+        Some(OWN_PRIORITY)
+    } else if is_player {
+        Some(PLAYER_PRIORITY)
+    } else {
+        is_item.map(|is_item| if is_item { ITEM_PRIORITY } else { CONTAINER_PRIORITY })
+    }
+}
+
+// Once per send: hands lightyear this client's priorities for everything it can see.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn prioritize_replication(
+    time_manager: Res<TimeManager>,
+    settings: Res<Settings>,
+    global: Res<Global>,
+    room_manager: Res<RoomManager>,
+    mut connection_manager: ResMut<ConnectionManager>,
+    mut timer: ResMut<SendTimer>,
+    players: Query<(&PlayerPosition, Option<&GridCell>), With<PlayerId>>,
+    things: Query<Has<Item>, (Or<(With<Item>, With<Container>)>, Without<PlayerId>)>,
+    positions: Query<&PlayerPosition>,
+    parents: Query<&ParentContainer>
+) {
+    if !timer.0.tick(time_manager.delta()).just_finished() {
+        return;
+    }
+    let cell_size = settings.server.grid_cell_size;
+    let radius = settings.server.view_radius as i32;
+
+    let connected: Vec<ClientId> = connection_manager.connected_clients().collect();
+    for client_id in connected {
+        let Some(&own) = global.client_id_to_entity_id.get(&client_id) else {
+            continue;
+        };
+        let Ok((own_position, own_cell)) = players.get(own) else {
+            continue;
+        };
+        let visible: FxHashSet<Entity> = client_rooms(client_id, own_cell.map(|cell| cell.0), radius)
+            .into_iter()
+            .filter_map(|room| room_manager.get_room(room))
+            .flat_map(|room| room.entities.iter().copied())
+            .collect();
+
+        // Items and containers are all in groups of their own, see item_group and container_group.
+        for entity in visible {
+            let Some(base) = base_priority(entity, own, players.contains(entity), things.get(entity).ok()) else {
+                continue;
+            };
+            let priority = match position_of(entity, &positions, &parents) {
+                Some(position) if entity != own => distance_priority(base, own_position.0.distance(position), cell_size),
+                _ => base,
+            };
+            // Only fails once the client is already gone.
+            let group = ReplicationGroupId(entity.to_bits());
+            let _ = connection_manager.update_priority(group, client_id, priority);
+        }
+    }
+}
+
+pub struct ReplicationPriorityPlugin;
+
+impl Plugin for ReplicationPriorityPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SendTimer>();
+        app.add_systems(PostUpdate, prioritize_replication);
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+
+    const CELL: f32 = 16.0;
+
+    #[test]
+    fn priority_decays_with_distance() {
+        assert_eq!(distance_priority(2.0, 0.0, CELL), 2.0);
+        assert_eq!(distance_priority(2.0, CELL, CELL), 1.0);
+        assert_eq!(distance_priority(3.0, 2.0 * CELL, CELL), 1.0);
+        let mut last = f32::INFINITY;
+        for distance in [0.0, 1.0, 10.0, 100.0, 1000.0] {
+            let priority = distance_priority(ITEM_PRIORITY, distance, CELL);
+            assert!(priority < last && priority > 0.0);
+            last = priority;
+        }
+    }
+
+    #[test]
+    fn own_character_then_players_then_items_then_containers() {
+        let (own, other) = (Entity::from_raw(1), Entity::from_raw(2));
+        let base = |entity, is_player, is_item| base_priority(entity, own, is_player, is_item).unwrap();
+        let ranked = [
+            base(own, true, None),
+            base(other, true, None),
+            base(other, false, Some(true)),
+            base(other, false, Some(false)),
+        ];
+        assert!(ranked.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", ranked);
+        assert_eq!(base_priority(other, own, false, None), None);
+
+        // At the same distance the order holds, even if it all goes down.
+        let near = ranked.map(|base| distance_priority(base, CELL, CELL));
+        assert!(near.windows(2).all(|pair| pair[0] > pair[1]), "{:?}", near);
+        assert!(near.iter().zip(&ranked).all(|(near, base)| near < base));
+    }
+}
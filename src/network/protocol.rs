@@ -5,6 +5,21 @@ use lightyear::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::ops::{Add, Mul};
 
+use crate::network::chat::PlayerListError;
+use crate::network::rpc::{ AppRpcExt, Rpc };
+use crate::network::version::AppProtocolManifestExt;
+use crate::register_described_component;
+use crate::game::items::{
+    Container,
+    Inventory,
+    Item,
+    ItemProperties,
+    MaxStack,
+    ParentContainer,
+    Quantity,
+};
+use crate::utils::common::{DisplayName, Icon, Tags, Weight};
+
 #[derive(Clone)]
 pub struct ProtocolPlugin;
 
@@ -14,11 +29,11 @@ pub struct ProtocolPlugin;
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PlayerId(pub ClientId);
 
-#[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq, Deref, DerefMut)]
-pub struct PlayerPosition(Vec3A); // Use Vec3A for better performance
+#[derive(Component, Serialize, Deserialize, Clone, Debug, Default, PartialEq, Deref, DerefMut)]
+pub struct PlayerPosition(pub Vec3A); // Use Vec3A for better performance
 
 #[derive(Component, Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct PlayerColor(Color);
+pub struct PlayerColor(pub Color);
 
 // Below implementations needed for the linear interpolation
 
@@ -39,10 +54,55 @@ impl Mul<f32> for &PlayerPosition {
 }
 
 // Message protocol.
-// Defines generic messages that can be sent over network.
+// Defines the messages that can be sent over network, each one goes on one of the channels below.
+
+// Chat. The client asks to say something, the server checks it and hands out ChatMessages.
+// Both go on the ChatChannel, see network::chat for how they're routed.
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ChatScope {
+    Global,
+    // Only players within the server's proximity radius of the sender.
+    Proximity,
+    Team,
+    // To one player, by username.
+    Whisper(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatRequest {
+    pub scope: ChatScope,
+    pub text: String,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct Message1(pub usize); // Generic usize used as a placeholder
+pub enum ChatSender {
+    // Notices from the server itself, like why a message was turned down.
+    System,
+    Player {
+        client_id: ClientId,
+        name: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatMessage {
+    pub sender: ChatSender,
+    pub scope: ChatScope,
+    pub text: String,
+}
+
+// Rpc protocol.
+// Request/response pairs on the RpcChannel, see network::rpc.
+
+// Usernames of everyone currently online. Counts against the asker's chat rate limit.
+pub struct PlayerList;
+
+impl Rpc for PlayerList {
+    type Request = ();
+    type Response = Vec<String>;
+    type Error = PlayerListError;
+}
 
 // Input protocol
 // Defines all inputs that can be sent over network.
@@ -58,35 +118,81 @@ pub enum PlayerActions {
 }
 
 // Channel protocol
-// Currently unnecesary, defines properties of message sending.
-// e.g. reliability, ordering, priority
+// Which channel a message goes on decides how it's delivered and what gets sent first once the
+// server's send_bandwidth_cap is reached. Priorities are relative to lightyear's own replication
+// channels, which sit at 1.0. Positions and inventories are replicated components, so they travel on
+// those and not on a channel of ours. The cap is for the connection as a whole, lightyear 0.18 has no
+// way to cap a single channel, so priority is the only say we have in what waits.
+
+// Chat has to arrive in the order it was said, and shouldn't queue up behind anything else.
+#[derive(Channel)]
+pub struct ChatChannel;
+
+// Every reply carries its RequestId, so there's no need to wait on the ones before it.
+#[derive(Channel)]
+pub struct RpcChannel;
 
+// Large transfers that can take their time, like whole maps or saves. Below replication, so they
+// only use what movement leaves over and never hold it up. Nothing sends on it yet.
 #[derive(Channel)]
-pub struct Channel1;
+pub struct BulkChannel;
 
 // Plugin Implementation. Puts it all together.
 impl Plugin for ProtocolPlugin {
     fn build(&self, app: &mut App) {
+        // Everything is described as it's registered, that's what the protocol id is made of.
+        // See network::version.
         // Messages
-        app.register_message::<Message1>(ChannelDirection::Bidirectional);
+        app.register_described_message::<ChatRequest>(ChannelDirection::ClientToServer);
+        app.register_described_message::<ChatMessage>(ChannelDirection::ServerToClient);
+        app.register_rpc::<PlayerList>();
         // Inputs
+        app.describe_input::<PlayerActions>();
         app.add_plugins(LeafwingInputPlugin::<PlayerActions>::default());
         // Components
-        app.register_component::<PlayerId>(ChannelDirection::ServerToClient)
+        register_described_component!(app, PlayerId, ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
 
-        app.register_component::<PlayerPosition>(ChannelDirection::ServerToClient)
+        register_described_component!(app, PlayerPosition, ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Full)
             .add_interpolation(ComponentSyncMode::Full)
-            .add_linear_interpolation_fn();
+            .add_linear_interpolation_fn()
+            // Smooths out the snap after a rollback instead of teleporting the local player.
+            .add_linear_correction_fn();
 
-        app.register_component::<PlayerColor>(ChannelDirection::ServerToClient)
+        register_described_component!(app, PlayerColor, ChannelDirection::ServerToClient)
             .add_prediction(ComponentSyncMode::Once)
             .add_interpolation(ComponentSyncMode::Once);
+
+        // Items and containers. Which clients actually get them is decided by the server's rooms.
+        register_described_component!(app, Item, ChannelDirection::ServerToClient);
+        register_described_component!(app, DisplayName, ChannelDirection::ServerToClient);
+        register_described_component!(app, Weight, ChannelDirection::ServerToClient);
+        register_described_component!(app, Icon, ChannelDirection::ServerToClient);
+        register_described_component!(app, Tags, ChannelDirection::ServerToClient);
+        register_described_component!(app, ItemProperties, ChannelDirection::ServerToClient);
+        register_described_component!(app, Quantity, ChannelDirection::ServerToClient);
+        register_described_component!(app, MaxStack, ChannelDirection::ServerToClient);
+        register_described_component!(app, Container, ChannelDirection::ServerToClient);
+        register_described_component!(app, Inventory, ChannelDirection::ServerToClient)
+            .add_map_entities();
+        register_described_component!(app, ParentContainer, ChannelDirection::ServerToClient)
+            .add_map_entities();
         // Channels
-        app.add_channel::<Channel1>(ChannelSettings {
+        app.add_described_channel::<ChatChannel>(ChannelSettings {
             mode: ChannelMode::OrderedReliable(ReliableSettings::default()),
+            priority: 2.0,
+            ..default()
+        });
+        app.add_described_channel::<RpcChannel>(ChannelSettings {
+            mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
+            priority: 2.0,
+            ..default()
+        });
+        app.add_described_channel::<BulkChannel>(ChannelSettings {
+            mode: ChannelMode::UnorderedReliable(ReliableSettings::default()),
+            priority: 0.5,
             ..default()
         });
     }
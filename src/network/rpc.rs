@@ -0,0 +1,447 @@
+// Request/response on top of lightyear messages, so gameplay code asks the server for something
+// and gets a typed answer back instead of pairing up messages by hand.
+//
+// An Rpc is registered once in the ProtocolPlugin. The server gives it a handler, which is an ordinary
+// system taking the request and returning a Result. The client sends through RpcSender and reads the
+// answer as an RpcReply event, matched up by the RequestId send handed out. A request that hasn't been
+// answered within the Rpc's timeout, or when the connection drops, gets an error reply instead.
+// So does one whose handler couldn't be run at all, the server answers it with RpcError::Internal.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use bevy::ecs::system::{ SystemId, SystemParam };
+use bevy::prelude::*;
+use lightyear::prelude::client::ClientError;
+use lightyear::prelude::{ client, server, ChannelDirection, ClientId };
+use rustc_hash::FxHashMap;
+use serde::de::DeserializeOwned;
+use serde::{ Deserialize, Serialize };
+
+use crate::network::protocol::RpcChannel;
+#[cfg(feature = "server")]
+use crate::network::traffic::ChannelTraffic;
+use crate::network::version::AppProtocolManifestExt;
+
+pub trait Rpc: Send + Sync + 'static {
+    type Request: Serialize + DeserializeOwned + Send + Sync + 'static;
+    type Response: Serialize + DeserializeOwned + Send + Sync + 'static;
+    // What the handler can fail with, beyond the request simply never getting answered.
+    type Error: Serialize + DeserializeOwned + fmt::Debug + Send + Sync + 'static;
+
+    // How long the client waits for the answer.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+// Unique per client across every Rpc, so replies can never be mixed up.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(pub u64);
+
+// Failed and Internal come from the server, the rest are worked out on the client.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RpcError<E> {
+    // The server didn't answer in time, or the answer got lost.
+    Timeout,
+    // The connection went away before the answer came back.
+    Disconnected,
+    // The handler turned the request down.
+    Failed(E),
+    // The server had no way to run the handler, see its log for why.
+    Internal,
+}
+
+impl<E: fmt::Display> fmt::Display for RpcError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "the server didn't answer in time"),
+            RpcError::Disconnected => write!(f, "disconnected before the server answered"),
+            RpcError::Failed(err) => write!(f, "{}", err),
+            RpcError::Internal => write!(f, "the server couldn't handle the request"),
+        }
+    }
+}
+
+// The trait's own bounds are all serde needs, so the derives get none of their own.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct RpcRequest<R: Rpc> {
+    pub id: RequestId,
+    pub request: R::Request,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct RpcResponse<R: Rpc> {
+    pub id: RequestId,
+    pub result: Result<R::Response, RpcError<R::Error>>,
+}
+
+// What the client gets back for every request it sent, one way or another.
+#[derive(Event)]
+pub struct RpcReply<R: Rpc> {
+    pub id: RequestId,
+    pub result: Result<R::Response, RpcError<R::Error>>,
+}
+
+#[cfg(feature = "server")]
+type HandlerId<R> = SystemId<
+    In<(ClientId, <R as Rpc>::Request)>,
+    Result<<R as Rpc>::Response, <R as Rpc>::Error>
+>;
+
+#[cfg(feature = "server")]
+#[derive(Resource)]
+struct RpcHandler<R: Rpc>(HandlerId<R>);
+
+// Runs the handler once per request. Exclusive so the handler can be any system at all.
+#[cfg(feature = "server")]
+fn dispatch_rpc_requests<R: Rpc>(world: &mut World) {
+    let requests: Vec<_> = world
+        .resource_mut::<Events<server::MessageEvent<RpcRequest<R>>>>()
+        .drain()
+        .collect();
+    if requests.is_empty() {
+        return;
+    }
+    let handler = world.resource::<RpcHandler<R>>().0;
+
+    for event in requests {
+        let client_id = event.context;
+        let RpcRequest { id, request } = event.message;
+        let result = match world.run_system_with_input(handler, (client_id, request)) {
+            Ok(result) => result.map_err(RpcError::Failed),
+            // Still answered, so the client hears about it now instead of waiting out the timeout.
+            Err(err) => {
+                error!("Rpc handler for {} failed to run: {}", std::any::type_name::<R>(), err);
+                Err(RpcError::Internal)
+            }
+        };
+        let mut response = RpcResponse::<R> { id, result };
+        let sent = world
+            .resource_mut::<server::ConnectionManager>()
+            .send_message::<RpcChannel, _>(client_id, &mut response);
+        if let Err(err) = sent {
+            warn!("Failed to answer rpc {:?} from {:?}: {}", id, client_id, err);
+        } else if let Some(mut traffic) = world.get_resource_mut::<ChannelTraffic>() {
+            // Apps without the TrafficPlugin just don't count.
+            traffic.count_bytes::<RpcChannel, _>(&response, 1);
+        }
+    }
+}
+
+// Handed out to every Rpc, see RequestId.
+#[cfg(feature = "client")]
+#[derive(Resource, Default)]
+struct RequestIds(u64);
+
+// When each unanswered request went out.
+#[cfg(feature = "client")]
+#[derive(Resource)]
+struct PendingRequests<R: Rpc> {
+    sent: FxHashMap<RequestId, Duration>,
+    marker: PhantomData<R>,
+}
+
+#[cfg(feature = "client")]
+impl<R: Rpc> Default for PendingRequests<R> {
+    fn default() -> Self {
+        Self { sent: Default::default(), marker: PhantomData }
+    }
+}
+
+#[cfg(feature = "client")]
+#[derive(SystemParam)]
+pub struct RpcSender<'w, 's, R: Rpc> {
+    ids: ResMut<'w, RequestIds>,
+    pending: ResMut<'w, PendingRequests<R>>,
+    connection: ResMut<'w, client::ConnectionManager>,
+    time: Res<'w, Time<Real>>,
+    marker: PhantomData<&'s ()>,
+}
+
+#[cfg(feature = "client")]
+impl<R: Rpc> RpcSender<'_, '_, R> {
+    // The answer shows up later as an RpcReply<R> with the same id.
+    pub fn send(&mut self, request: R::Request) -> Result<RequestId, ClientError> {
+        let id = RequestId(self.ids.0);
+        self.ids.0 = self.ids.0.wrapping_add(1);
+        let mut message = RpcRequest::<R> { id, request };
+        self.connection.send_message::<RpcChannel, _>(&mut message)?;
+        self.pending.sent.insert(id, self.time.elapsed());
+        Ok(id)
+    }
+}
+
+// Answers to requests we already gave up on are dropped, the caller has had its Timeout.
+#[cfg(feature = "client")]
+fn receive_rpc_responses<R: Rpc>(
+    mut responses: ResMut<Events<client::MessageEvent<RpcResponse<R>>>>,
+    mut pending: ResMut<PendingRequests<R>>,
+    mut replies: EventWriter<RpcReply<R>>
+) {
+    for event in responses.drain() {
+        let RpcResponse { id, result } = event.message;
+        if pending.sent.remove(&id).is_none() {
+            continue;
+        }
+        replies.send(RpcReply { id, result });
+    }
+}
+
+#[cfg(feature = "client")]
+fn expire_rpc_requests<R: Rpc>(
+    time: Res<Time<Real>>,
+    mut pending: ResMut<PendingRequests<R>>,
+    mut replies: EventWriter<RpcReply<R>>
+) {
+    let now = time.elapsed();
+    pending.sent.retain(|id, sent| {
+        if now.saturating_sub(*sent) < R::TIMEOUT {
+            return true;
+        }
+        replies.send(RpcReply { id: *id, result: Err(RpcError::Timeout) });
+        false
+    });
+}
+
+#[cfg(feature = "client")]
+fn fail_rpc_requests_on_disconnect<R: Rpc>(
+    mut disconnections: EventReader<client::DisconnectEvent>,
+    mut pending: ResMut<PendingRequests<R>>,
+    mut replies: EventWriter<RpcReply<R>>
+) {
+    if disconnections.read().count() == 0 {
+        return;
+    }
+    for (id, _) in pending.sent.drain() {
+        replies.send(RpcReply { id, result: Err(RpcError::Disconnected) });
+    }
+}
+
+pub trait AppRpcExt {
+    // Registers the request and response messages. Goes in the ProtocolPlugin, like every other message.
+    fn register_rpc<R: Rpc>(&mut self) -> &mut Self;
+
+    // The handler gets who's asking along with the request. Each Rpc has at most one.
+    #[cfg(feature = "server")]
+    fn add_rpc_handler<R: Rpc, M>(
+        &mut self,
+        handler: impl IntoSystem<In<(ClientId, R::Request)>, Result<R::Response, R::Error>, M> + 'static
+    ) -> &mut Self;
+
+    // Lets the client send R through RpcSender and get RpcReply events back.
+    #[cfg(feature = "client")]
+    fn add_rpc_client<R: Rpc>(&mut self) -> &mut Self;
+}
+
+impl AppRpcExt for App {
+    fn register_rpc<R: Rpc>(&mut self) -> &mut Self {
+        self.register_described_message::<RpcRequest<R>>(ChannelDirection::ClientToServer);
+        self.register_described_message::<RpcResponse<R>>(ChannelDirection::ServerToClient)
+    }
+
+    #[cfg(feature = "server")]
+    fn add_rpc_handler<R: Rpc, M>(
+        &mut self,
+        handler: impl IntoSystem<In<(ClientId, R::Request)>, Result<R::Response, R::Error>, M> + 'static
+    ) -> &mut Self {
+        let handler = self.world_mut().register_system(handler);
+        self.insert_resource(RpcHandler::<R>(handler));
+        self.add_systems(Update, dispatch_rpc_requests::<R>);
+        self
+    }
+
+    #[cfg(feature = "client")]
+    fn add_rpc_client<R: Rpc>(&mut self) -> &mut Self {
+        self.init_resource::<RequestIds>();
+        self.init_resource::<PendingRequests<R>>();
+        self.add_event::<RpcReply<R>>();
+        self.add_systems(
+            Update,
+            (
+                receive_rpc_responses::<R>,
+                fail_rpc_requests_on_disconnect::<R>,
+                expire_rpc_requests::<R>,
+            ).chain()
+        );
+        self
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::input::InputPlugin;
+    use bevy::state::app::StatesPlugin;
+    use bevy::time::TimeUpdateStrategy;
+    use lightyear::prelude::client::{ Authentication, ClientCommands, ClientConfig, ClientTransport };
+    use lightyear::prelude::server::{ NetcodeConfig, ServerCommands, ServerConfig, ServerTransport };
+    use lightyear::prelude::{ Mode, PingConfig };
+    use lightyear::transport::LOCAL_SOCKET;
+
+    use super::*;
+    use crate::network::protocol::ProtocolPlugin;
+    use crate::network::shared::shared_config;
+
+    // Doubles what it's sent, except zero, which it turns down.
+    struct Double;
+
+    impl Rpc for Double {
+        type Request = u32;
+        type Response = u32;
+        type Error = String;
+
+        const TIMEOUT: Duration = Duration::from_secs(1);
+    }
+
+    // Taken away to leave the handler unable to run.
+    #[derive(Resource)]
+    struct Doubling;
+
+    fn double(In((_, request)): In<(ClientId, u32)>, _: Res<Doubling>) -> Result<u32, String> {
+        match request {
+            0 => Err("nothing to double".to_string()),
+            request => Ok(request * 2),
+        }
+    }
+
+    // Pinged every frame, so the client syncs up without waiting on real time.
+    fn pings() -> PingConfig {
+        PingConfig { ping_interval: Duration::ZERO, ..default() }
+    }
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    type Reply = (RequestId, Result<u32, RpcError<String>>);
+
+    // A server and one client over in-memory channels, both stepped by hand.
+    struct Connected {
+        server: App,
+        client: App,
+    }
+
+    impl Connected {
+        fn new() -> Self {
+            // Both ends of a local channel go by this address.
+            let addr = LOCAL_SOCKET;
+            let (to_client, from_server) = crossbeam_channel::unbounded();
+            let (to_server, from_client) = crossbeam_channel::unbounded();
+            let key = [7; 32];
+            let shared = shared_config(Mode::Separate);
+
+            let mut server = App::new();
+            server.add_plugins((MinimalPlugins, StatesPlugin));
+            let io = server::IoConfig::from_transport(ServerTransport::Channels {
+                channels: vec![(addr, from_client, to_client)],
+            });
+            let net = server::NetConfig::Netcode { config: NetcodeConfig::default().with_key(key), io };
+            server.add_plugins(server::ServerPlugins::new(ServerConfig { shared, net: vec![net], ping: pings(), ..default() }));
+            server.add_plugins(ProtocolPlugin);
+            server.register_rpc::<Double>().add_rpc_handler::<Double, _>(double);
+            server.insert_resource(Doubling);
+
+            let mut client = App::new();
+            client.add_plugins((MinimalPlugins, StatesPlugin, InputPlugin));
+            let io = client::IoConfig::from_transport(ClientTransport::LocalChannel {
+                send: to_server,
+                recv: from_server,
+            });
+            let auth = Authentication::Manual { server_addr: addr, protocol_id: 0, private_key: key, client_id: 1 };
+            let net = client::NetConfig::Netcode { auth, config: default(), io };
+            client.add_plugins(client::ClientPlugins::new(ClientConfig { shared, net, ping: pings(), ..default() }));
+            client.add_plugins(ProtocolPlugin);
+            client.register_rpc::<Double>().add_rpc_client::<Double>();
+
+            for app in [&mut server, &mut client] {
+                app.insert_resource(TimeUpdateStrategy::ManualDuration(FRAME));
+                app.finish();
+                app.cleanup();
+            }
+            server.world_mut().run_system_once(|mut commands: Commands| commands.start_server()).unwrap();
+            client.world_mut().run_system_once(|mut commands: Commands| commands.connect_client()).unwrap();
+
+            let mut connected = Self { server, client };
+            for _ in 0..500 {
+                if connected.client.world().resource::<client::ConnectionManager>().is_synced() {
+                    return connected;
+                }
+                connected.step(1);
+            }
+            panic!("the client never connected");
+        }
+
+        fn send(&mut self, request: u32) -> RequestId {
+            self.client
+                .world_mut()
+                .run_system_once(move |mut sender: RpcSender<Double>| sender.send(request).unwrap())
+                .unwrap()
+        }
+
+        // Events only last two frames, so replies are picked up after every one.
+        fn step_client(&mut self, frames: u32) -> Vec<Reply> {
+            let mut replies = Vec::new();
+            for _ in 0..frames {
+                self.client.update();
+                let mut events = self.client.world_mut().resource_mut::<Events<RpcReply<Double>>>();
+                replies.extend(events.drain().map(|reply| (reply.id, reply.result)));
+            }
+            replies
+        }
+
+        fn step(&mut self, frames: u32) -> Vec<Reply> {
+            let mut replies = Vec::new();
+            for _ in 0..frames {
+                replies.extend(self.step_client(1));
+                self.server.update();
+            }
+            replies
+        }
+    }
+
+    #[test]
+    fn replies_match_their_requests() {
+        let mut connected = Connected::new();
+        let first = connected.send(21);
+        let second = connected.send(0);
+        let third = connected.send(4);
+
+        let mut replies = connected.step(50);
+        replies.sort_by_key(|(id, _)| id.0);
+        assert_eq!(replies, vec![
+            (first, Ok(42)),
+            (second, Err(RpcError::Failed("nothing to double".to_string()))),
+            (third, Ok(8)),
+        ]);
+    }
+
+    #[test]
+    fn handlers_that_cant_run_still_answer() {
+        let mut connected = Connected::new();
+        connected.server.world_mut().remove_resource::<Doubling>();
+        let id = connected.send(21);
+        assert_eq!(connected.step(50), vec![(id, Err(RpcError::Internal))]);
+    }
+
+    #[test]
+    fn unanswered_requests_time_out_once() {
+        let mut connected = Connected::new();
+        let id = connected.send(21);
+        // The server isn't stepped, so nothing comes back until well after the timeout.
+        let frames = (Double::TIMEOUT.as_millis() / FRAME.as_millis()) as u32;
+        assert_eq!(connected.step_client(frames - 1), vec![]);
+        assert_eq!(connected.step_client(1), vec![(id, Err(RpcError::Timeout))]);
+        assert_eq!(connected.step(50), vec![]);
+    }
+
+    #[test]
+    fn disconnecting_fails_whats_pending() {
+        let mut connected = Connected::new();
+        let id = connected.send(21);
+        connected.client
+            .world_mut()
+            .run_system_once(|mut commands: Commands| commands.disconnect_client())
+            .unwrap();
+        assert_eq!(connected.step_client(5), vec![(id, Err(RpcError::Disconnected))]);
+    }
+}
@@ -4,13 +4,31 @@ use bevy::prelude::{
     App,
     Plugin,
     default,
+    Component,
     Entity,
     Resource,
     ResMut,
     EventReader,
+    IntoSystemConfigs,
+    Query,
+    Added,
+    Has,
+    Or,
+    With,
+    Without,
     Update,
+    Changed,
     info,
+    Color,
+    DespawnRecursiveExt,
+    Res,
+    Time,
+    Timer,
+    TimerMode,
+    error,
+    warn,
 };
+use bevy::math::Vec3A;
 use bevy::state::app::StatesPlugin;
 use server::{ NetConfig, NetcodeConfig };
 use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
@@ -30,50 +48,359 @@ use crate::network::shared::{
     SERVER_ADDR,
     SERVER_REPLICATION_INTERVAL,
 };
-use crate::game::items::ItemsPlugin;
-use crate::game::player::Player;
-use crate::network::protocol::PlayerId;
+use crate::game::inventory::ItemMoved;
+use crate::game::items::{ Container, Inventory, Item, ItemStorage, ItemsPlugin, ParentContainer };
+use crate::game::accounts::{
+    load_character,
+    player_id,
+    save_character,
+    spawn_saved_items,
+    Accounts,
+    CharacterQueries,
+    CharacterSave,
+};
+use crate::game::player::{ Parked, Player };
+use crate::game::save::WorldSavePlugin;
+use crate::utils::settings::{ server_private_key, ServerTransports, Settings };
+use crate::network::auth::{ AuthService, CredentialStore };
+use crate::network::version::ProtocolVersion;
+use crate::network::protocol::{ PlayerColor, PlayerId, PlayerPosition };
+use crate::network::grid::{ self, cell_room, update_grid_cells, GridCell };
+use crate::network::chat::ChatServerPlugin;
+use crate::network::priority::{ container_group, item_group, ReplicationPriorityPlugin };
+
+// How much a freshly spawned player can carry.
+const PLAYER_WEIGHT_LIMIT: f32 = 25.0;
 
-// Defines a room. This is a (likely temporary) way to define when a player is spawned and should be replicated
-const PLAYER_ROOM: RoomId = RoomId(0);
+// Rooms only the owning client is in, e.g. for the contents of their own inventory.
+// Kept in the top half of the id space so they can never collide with the cell rooms.
+pub fn private_room(client_id: ClientId) -> RoomId {
+    RoomId((1 << 63) | client_id.to_bits())
+}
+
+// Server-side bookkeeping of which room an item or container is currently replicated through.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+struct ReplicatedRoom(RoomId);
 
 // Will be used frequently, eg interest management. Defines basic data.
 #[derive(Resource, Default)]
 pub struct Global {
     pub client_id_to_entity_id: FxHashMap<ClientId, Entity>,
+    // The cell room the client's player currently stands in.
     pub client_id_to_room_id: FxHashMap<ClientId, RoomId>,
 }
 
+// Spreads players around the color wheel by the golden angle so neighbouring ids look different.
+fn player_color(client_id: ClientId) -> PlayerColor {
+    let hue = ((client_id.to_bits() as f64) * 137.508) % 360.0;
+    PlayerColor(Color::hsl(hue as f32, 0.8, 0.6))
+}
+
 // Super important function.
 // Defines what to do when a connection is made. Currently includes only defining the client and stuff.
+#[allow(clippy::too_many_arguments)]
 fn handle_connections(
     mut global: ResMut<Global>,
     mut room_manager: ResMut<RoomManager>,
     mut connections: EventReader<ConnectEvent>,
-    mut commands: Commands
+    mut commands: Commands,
+    settings: Res<Settings>,
+    accounts: Option<Res<Accounts>>,
+    item_storage: Res<ItemStorage>,
+    parked: Query<Option<&GridCell>, With<Parked>>
 ) {
     for connection in connections.read() {
         let client_id = connection.client_id;
+        // An auth service started with --auth-only can't see who is playing here, and lightyear keeps
+        // a single connection per id, so a second session for a live character isn't supported.
+        if let Some(&entity) = global.client_id_to_entity_id.get(&client_id) {
+            if !parked.contains(entity) {
+                error!("Player {} connected twice, keeping the first session's character", player_id(client_id));
+                continue;
+            }
+        }
+        // The auth service turns away a second login while this is set.
+        if let Some(accounts) = &accounts {
+            accounts.lock().online.insert(player_id(client_id));
+        }
+
+        let entity = match global.client_id_to_entity_id.get(&client_id) {
+            // Came back in time, hand the old character back.
+            Some(&entity) if parked.contains(entity) => {
+                // Control was dropped with the old session, reinserting registers it again.
+                commands
+                    .entity(entity)
+                    .remove::<Parked>()
+                    .insert(player_control(client_id));
+                // The character hasn't moved, so update_grid_cells won't subscribe us again.
+                if let Ok(Some(cell)) = parked.get(entity) {
+                    let radius = settings.server.view_radius as i32;
+                    grid::subscribe(&mut room_manager, client_id, cell.0, radius);
+                    global.client_id_to_room_id.insert(client_id, cell_room(cell.0));
+                }
+                info!("Player Entity Resumed");
+                entity
+            }
+            // Its cell room and the subscriptions around it are set up once it has a GridCell.
+            _ => {
+                let character = match load_character(&settings.server.save_dir, player_id(client_id)) {
+                    Ok(character) => character,
+                    Err(err) => {
+                        error!("{}, starting a fresh character", err);
+                        None
+                    }
+                };
+                spawn_player(&mut commands, &item_storage, client_id, character)
+            }
+        };
+
+        global.client_id_to_entity_id.insert(client_id, entity);
+        room_manager.add_client(client_id, private_room(client_id));
+    }
+}
+
+// Persistent so lightyear doesn't despawn the character on disconnect, we decide that ourselves.
+fn player_control(client_id: ClientId) -> ControlledBy {
+    ControlledBy {
+        target: NetworkTarget::Single(client_id),
+        lifetime: Lifetime::Persistent,
+    }
+}
+
+// Picks up where the saved character left off, if there is one.
+fn spawn_player(
+    commands: &mut Commands,
+    item_storage: &Res<ItemStorage>,
+    client_id: ClientId,
+    character: Option<CharacterSave>
+) -> Entity {
+    let character = character.unwrap_or_default();
+    let (x, y, z) = character.position;
+
+    let replicate = Replicate {
+        sync: SyncTarget {
+            prediction: NetworkTarget::Single(client_id),
+            interpolation: NetworkTarget::AllExceptSingle(client_id),
+        },
+        controlled_by: player_control(client_id),
+        relevance_mode: InterestManagement,
+        ..default()
+    };
+
+    // Other players have no business knowing what's in this player's pockets.
+    let inventory = (
+        Inventory { weight_limit: PLAYER_WEIGHT_LIMIT, ..default() },
+        OverrideTargetComponent::<Inventory>::new(NetworkTarget::Single(client_id)),
+    );
+
+    let entity = commands
+        .spawn((
+            Player,
+            PlayerId(client_id),
+            PlayerPosition(Vec3A::new(x, y, z)),
+            player_color(client_id),
+            replicate,
+            inventory,
+        ))
+        .id();
+    let missing = spawn_saved_items(commands, item_storage, entity, &character.inventory);
+    if !missing.is_empty() {
+        warn!("Dropped saved items that no longer exist: {:?}", missing);
+    }
+    info!("Player Entity Spawned");
+    entity
+}
+
+// The client is gone either way, so it leaves its rooms right away and its character gets saved.
+// The character itself is parked for the grace period, or despawned if there is none.
+#[allow(clippy::too_many_arguments)]
+fn handle_disconnections(
+    settings: Res<Settings>,
+    accounts: Option<Res<Accounts>>,
+    mut global: ResMut<Global>,
+    mut room_manager: ResMut<RoomManager>,
+    mut disconnections: EventReader<DisconnectEvent>,
+    mut commands: Commands,
+    characters: CharacterQueries,
+    cells: Query<&GridCell>
+) {
+    let grace = settings.server.disconnect_grace_secs;
+    let radius = settings.server.view_radius as i32;
+    for disconnection in disconnections.read() {
+        let client_id = disconnection.client_id;
+        global.client_id_to_room_id.remove(&client_id);
+        room_manager.remove_client(client_id, private_room(client_id));
+
+        if let Some(accounts) = &accounts {
+            accounts.lock().online.remove(&player_id(client_id));
+        }
+
+        let Some(&entity) = global.client_id_to_entity_id.get(&client_id) else {
+            continue;
+        };
+        if let Ok(cell) = cells.get(entity) {
+            grid::unsubscribe(&mut room_manager, client_id, cell.0, radius);
+        }
+        // Saved now rather than on despawn, a parked character doesn't change anymore.
+        if let Some(character) = characters.snapshot(entity) {
+            let save_dir = &settings.server.save_dir;
+            if let Err(err) = save_character(save_dir, player_id(client_id), &character) {
+                error!("{}", err);
+            }
+        }
+        if grace > 0.0 {
+            commands.entity(entity).insert(Parked(Timer::from_seconds(grace, TimerMode::Once)));
+            info!("Player Entity Parked for {}s", grace);
+        } else {
+            global.client_id_to_entity_id.remove(&client_id);
+            despawn_player(&mut commands, entity, &characters.inventories);
+        }
+    }
+}
+
+// Nobody came back for these, so they go for good.
+fn expire_parked_players(
+    time: Res<Time>,
+    mut global: ResMut<Global>,
+    mut commands: Commands,
+    mut parked: Query<(Entity, &PlayerId, &mut Parked)>,
+    inventories: Query<&Inventory>
+) {
+    for (entity, player_id, mut parked) in parked.iter_mut() {
+        if !parked.0.tick(time.delta()).finished() {
+            continue;
+        }
+        global.client_id_to_entity_id.remove(&player_id.0);
+        despawn_player(&mut commands, entity, &inventories);
+    }
+}
+
+// Takes everything the player carries with them, otherwise those items would sit around unreachable.
+// Despawning replicated entities despawns them on every client too.
+fn despawn_player(commands: &mut Commands, entity: Entity, inventories: &Query<&Inventory>) {
+    let mut pending = vec![entity];
+    while let Some(entity) = pending.pop() {
+        if let Ok(inventory) = inventories.get(entity) {
+            pending.extend(inventory.items.iter().copied());
+        }
+        commands.entity(entity).despawn_recursive();
+    }
+    info!("Player Entity Despawned");
+}
 
-        let replicate = Replicate {
-            sync: SyncTarget {
-                prediction: NetworkTarget::Single(client_id),
-                interpolation: NetworkTarget::AllExceptSingle(client_id),
-            },
-            controlled_by: ControlledBy {
-                target: NetworkTarget::Single(client_id),
-                ..default()
-            },
+// Every item and container is replicated, but only to clients sharing a room with it.
+// Bags are items too and get carried around, only containers standing in the world are sent less often.
+#[allow(clippy::type_complexity)]
+fn replicate_items(
+    mut commands: Commands,
+    query: Query<(Entity, Has<Item>), (Or<(Added<Item>, Added<Container>)>, Without<Replicating>)>
+) {
+    for (entity, is_item) in query.iter() {
+        commands.entity(entity).insert(Replicate {
             relevance_mode: InterestManagement,
+            group: if is_item { item_group() } else { container_group() },
             ..default()
-        };
+        });
+    }
+}
 
-        let entity = commands.spawn((Player, PlayerId(client_id), replicate));
-        info!("Player Entity Spawned");
-        global.client_id_to_entity_id.insert(client_id, entity.id());
-        global.client_id_to_room_id.insert(client_id, PLAYER_ROOM);
-        room_manager.add_client(client_id, PLAYER_ROOM);
-        room_manager.add_entity(entity.id(), PLAYER_ROOM);
+// Whatever holds the entity at the outermost level, or the entity itself if nothing does.
+pub(crate) fn outermost_holder(entity: Entity, parents: &Query<&ParentContainer>) -> Entity {
+    let mut outermost = entity;
+    while let Ok(parent) = parents.get(outermost) {
+        outermost = parent.0;
+        if outermost == entity {
+            break;
+        }
+    }
+    outermost
+}
+
+// Anything inside something else is replicated wherever its outermost holder is. Items held by a
+// player, no matter how deeply nested, only go to that player, and the contents of a container
+// standing in the world go to the cell room it stands in.
+// None for what stands in the world itself, update_grid_cells takes care of those, and for
+// contents of a holder that isn't anywhere yet. They get picked up once it is.
+fn holder_room(
+    entity: Entity,
+    parents: &Query<&ParentContainer>,
+    players: &Query<&PlayerId>,
+    cells: &Query<&GridCell>
+) -> Option<RoomId> {
+    let outermost = outermost_holder(entity, parents);
+    if outermost == entity {
+        return None;
+    }
+    match players.get(outermost) {
+        Ok(PlayerId(client_id)) => Some(private_room(*client_id)),
+        Err(_) => cells.get(outermost).ok().map(|cell| cell_room(cell.0)),
+    }
+}
+
+// Puts new items and containers in the right room, and moves them (and whatever they hold)
+// between rooms as they get transferred or their holder walks into another cell.
+// Dropped items land where their holder stands, picked up ones stop standing anywhere.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn update_item_rooms(
+    mut commands: Commands,
+    mut room_manager: ResMut<RoomManager>,
+    mut moved: EventReader<ItemMoved>,
+    added: Query<Entity, Or<(Added<Item>, Added<Container>)>>,
+    relocated: Query<&Inventory, (Changed<GridCell>, Without<PlayerId>)>,
+    existing: Query<(), Or<(With<Item>, With<Container>)>>,
+    parents: Query<&ParentContainer>,
+    players: Query<&PlayerId>,
+    inventories: Query<&Inventory>,
+    positions: Query<&PlayerPosition>,
+    cells: Query<&GridCell>,
+    rooms: Query<&ReplicatedRoom>
+) {
+    let mut pending: Vec<Entity> = added.iter().collect();
+    for moved in moved.read() {
+        if let (Some(from), None) = (moved.from, moved.to) {
+            if let Ok(position) = positions.get(outermost_holder(from, &parents)) {
+                commands.entity(moved.item).insert(position.clone());
+            }
+        }
+        pending.push(moved.item);
+    }
+    for inventory in relocated.iter() {
+        pending.extend(inventory.items.iter().copied());
+    }
+
+    while let Some(entity) = pending.pop() {
+        // Stacks merged away entirely are already gone.
+        if existing.get(entity).is_err() {
+            continue;
+        }
+
+        let room = holder_room(entity, &parents, &players, &cells);
+        let current = rooms.get(entity).ok().map(|room| room.0);
+        if current != room {
+            if let Some(current) = current {
+                room_manager.remove_entity(entity, current);
+            }
+            match room {
+                Some(room) => {
+                    room_manager.add_entity(entity, room);
+                    commands.entity(entity).insert(ReplicatedRoom(room));
+                }
+                None => {
+                    commands.entity(entity).remove::<ReplicatedRoom>();
+                }
+            }
+        }
+
+        if parents.contains(entity) {
+            if let Ok(cell) = cells.get(entity) {
+                room_manager.remove_entity(entity, cell_room(cell.0));
+                commands.entity(entity).remove::<(GridCell, PlayerPosition)>();
+            }
+        }
+
+        if let Ok(inventory) = inventories.get(entity) {
+            pending.extend(inventory.items.iter().copied());
+        }
     }
 }
 
@@ -115,14 +442,65 @@ fn build_server_plugin() -> ServerPlugins {
 impl Plugin for ServerNetworkingPlugin {
     fn build(&self, app: &mut App) {
         // Add server-specific systems/plugins
-        app.add_systems(Startup, start_server);
-        app.add_systems(Update, handle_connections);
+        app.add_systems(Startup, (start_server, start_auth_service));
+        app.add_systems(
+            Update,
+            (handle_connections, handle_disconnections, expire_parked_players, update_grid_cells).chain()
+        );
+        app.add_systems(Update, (replicate_items, update_item_rooms).chain());
         app.insert_resource(Global::default());   
         app.add_plugins(ItemsPlugin);
+        app.add_plugins(WorldSavePlugin);
+        app.add_plugins(ChatServerPlugin);
+        app.add_plugins(ReplicationPriorityPlugin);
         app.add_plugins(EntropyPlugin::<WyRand>::default());
     }
 }
 
+fn auth_service(settings: &Settings, accounts: Accounts) -> Result<AuthService, String> {
+    let auth = &settings.server.auth;
+    Ok(AuthService {
+        credentials: CredentialStore::from_file(&auth.credentials)?,
+        accounts,
+        version: ProtocolVersion::current(),
+        transports: settings.server.transport.iter().map(ServerTransports::port_and_compression).collect(),
+        private_key: server_private_key(&settings.server),
+        token_expire_secs: auth.token_expire_secs,
+    })
+}
+
+fn auth_addr(settings: &Settings) -> SocketAddr {
+    SocketAddr::new(settings.server.auth.address, settings.server.auth.port)
+}
+
+// Entry point for --auth-only. There's no bevy app and so no logging, errors go straight to stderr.
+pub fn run_auth_service(settings: &Settings) {
+    let result = Accounts::load(&settings.server.save_dir)
+        .map_err(|err| err.to_string())
+        .and_then(|accounts| auth_service(settings, accounts))
+        .and_then(|service| service.run(auth_addr(settings)).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        eprintln!("Auth service failed: {}", err);
+        std::process::exit(1);
+    }
+}
+
+// Runs the auth service in-process alongside the game server, sharing the accounts with it.
+fn start_auth_service(mut commands: Commands, settings: Res<Settings>) {
+    let accounts = match Accounts::load(&settings.server.save_dir) {
+        Ok(accounts) => accounts,
+        Err(err) => {
+            error!("Auth service not started, nobody will be able to log in: {}", err);
+            return;
+        }
+    };
+    commands.insert_resource(accounts.clone());
+    match auth_service(&settings, accounts) {
+        Ok(service) => service.spawn(auth_addr(&settings)),
+        Err(err) => error!("Auth service not started, nobody will be able to log in: {}", err),
+    }
+}
+
 // Quick fn to start the server using lightyear commands
 pub fn start_server(mut commands: Commands) {
     commands.start_server();
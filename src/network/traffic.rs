@@ -0,0 +1,153 @@
+// Per-channel counts of the messages the server sends, so it shows when one kind of traffic starts
+// crowding out the rest. They come from lightyear's own channel stats (its "trace" feature), so
+// replication counts too, and only what got past the send_bandwidth_cap shows up.
+// Lightyear 0.18 only keeps how many messages went out per channel, not how many bytes, and the
+// client's connection doesn't expose them at all, so the client has nothing to report.
+// Bytes are only known for our own channels, where they're counted as each message is handed to
+// lightyear. That's before the cap, so a throttled channel shows more bytes than actually left.
+//
+// Set SharedSettings::traffic_report_secs to get a summary in the log every so often.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+#[cfg(feature = "server")]
+use lightyear::channel::builder::{ EntityActionsChannel, EntityUpdatesChannel };
+#[cfg(feature = "server")]
+use lightyear::prelude::{ server, Channel, ClientId, NetworkTarget };
+use rustc_hash::FxHashMap;
+use serde::Serialize;
+
+#[cfg(feature = "server")]
+use crate::network::protocol::{ BulkChannel, ChatChannel, RpcChannel };
+use crate::utils::settings::Settings;
+
+#[derive(Resource, Debug, Default)]
+pub struct ChannelTraffic {
+    // Messages sent since startup, by channel name.
+    pub total: FxHashMap<&'static str, u64>,
+    // Since the last report.
+    pub window: FxHashMap<&'static str, u64>,
+    // Encoded message bytes on our own channels, see count_bytes.
+    pub total_bytes: FxHashMap<&'static str, u64>,
+    pub window_bytes: FxHashMap<&'static str, u64>,
+    // What each client's connection had sent on each channel when we last looked.
+    #[cfg(feature = "server")]
+    seen: FxHashMap<(ClientId, &'static str), usize>,
+}
+
+#[cfg(feature = "server")]
+impl ChannelTraffic {
+    // Called wherever the server sends on one of our channels, with how many clients the message went to.
+    pub fn count_bytes<C: Channel, M: Serialize>(&mut self, message: &M, clients: usize) {
+        let bytes = (encoded_size(message) * clients) as u64;
+        *self.total_bytes.entry(C::name()).or_default() += bytes;
+        *self.window_bytes.entry(C::name()).or_default() += bytes;
+    }
+}
+
+// How many of the connected clients a send to target reaches.
+#[cfg(feature = "server")]
+pub fn clients_reached(connection: &server::ConnectionManager, target: &NetworkTarget) -> usize {
+    connection
+        .connected_clients()
+        .filter(|client_id| target.targets(client_id))
+        .count()
+}
+
+// Same encoding lightyear uses for messages, without its per-message header.
+pub fn encode<M: Serialize>(message: &M) -> Vec<u8> {
+    bincode::serde::encode_to_vec(message, bincode::config::standard()).unwrap_or_default()
+}
+
+pub fn encoded_size<M: Serialize>(message: &M) -> usize {
+    encode(message).len()
+}
+
+// Lightyear counts per connection and from the start of it, so only what's new since last time is added.
+#[cfg(feature = "server")]
+fn count_sent<C: Channel>(connection: Res<server::ConnectionManager>, mut traffic: ResMut<ChannelTraffic>) {
+    let traffic = &mut *traffic;
+    for client_id in connection.connected_clients() {
+        let Some(stats) = connection
+            .connection(client_id)
+            .ok()
+            .and_then(|connection| connection.message_manager.channel_send_stats::<C>()) else {
+            continue;
+        };
+        let sent = stats.messages_sent();
+        let seen = traffic.seen.insert((client_id, C::name()), sent).unwrap_or(0);
+        let new = sent.saturating_sub(seen) as u64;
+        *traffic.total.entry(C::name()).or_default() += new;
+        *traffic.window.entry(C::name()).or_default() += new;
+    }
+}
+
+#[cfg(feature = "server")]
+fn forget_disconnected(
+    mut disconnections: EventReader<server::DisconnectEvent>,
+    mut traffic: ResMut<ChannelTraffic>
+) {
+    for disconnection in disconnections.read() {
+        traffic.seen.retain(|(client_id, _), _| *client_id != disconnection.client_id);
+    }
+}
+
+#[derive(Resource)]
+struct TrafficReport(Timer);
+
+fn start_traffic_report(mut commands: Commands, settings: Res<Settings>) {
+    let secs = settings.shared.traffic_report_secs;
+    if secs > 0.0 {
+        let timer = Timer::new(Duration::from_secs_f32(secs), TimerMode::Repeating);
+        commands.insert_resource(TrafficReport(timer));
+    }
+}
+
+fn report_traffic(
+    time: Res<Time<Real>>,
+    mut report: ResMut<TrafficReport>,
+    mut traffic: ResMut<ChannelTraffic>
+) {
+    if !report.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    let secs = report.0.duration().as_secs_f32();
+    let mut channels: Vec<_> = traffic.window.drain().collect();
+    channels.sort_by_key(|(name, _)| *name);
+    for (name, messages) in channels {
+        let rate = (messages as f32) / secs;
+        match traffic.window_bytes.get(name) {
+            Some(bytes) => info!("[traffic] {}: {:.1} msg/s, {:.0} B/s", name, rate, (*bytes as f32) / secs),
+            None => info!("[traffic] {}: {:.1} msg/s", name, rate),
+        }
+    }
+    traffic.window_bytes.clear();
+}
+
+#[derive(Clone)]
+pub struct TrafficPlugin;
+
+impl Plugin for TrafficPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChannelTraffic>();
+        app.add_systems(Startup, start_traffic_report);
+        app.add_systems(Last, report_traffic.run_if(resource_exists::<TrafficReport>));
+        // Lightyear sends in PostUpdate, so by Last this frame's messages are in the stats.
+        // A client app in host-server mode has no server connection to look at.
+        #[cfg(feature = "server")]
+        app.add_systems(
+            Last,
+            (
+                forget_disconnected,
+                count_sent::<EntityActionsChannel>,
+                count_sent::<EntityUpdatesChannel>,
+                count_sent::<ChatChannel>,
+                count_sent::<RpcChannel>,
+                count_sent::<BulkChannel>,
+            )
+                .before(report_traffic)
+                .run_if(resource_exists::<server::ConnectionManager>)
+        );
+    }
+}
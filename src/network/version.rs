@@ -0,0 +1,552 @@
+// Protocol versioning. The netcode protocol id is a hash of the game version and the shape of
+// everything ProtocolPlugin registers, so any change to what goes over the wire gets a new id.
+// The auth service compares the client's with its own before handing out a connect token, so an
+// outdated client is told why instead of timing out against a server that ignores it.
+//
+// ProtocolPlugin describes each thing it registers with the AppProtocolManifestExt methods and the
+// register_described_component macro below, never with lightyear's register calls on their own.
+// The shape of a type is worked out by walking its Deserialize impl, see shape.
+
+use std::fmt::{ self, Write as _ };
+use std::sync::OnceLock;
+
+use bevy::prelude::*;
+use lightyear::prelude::{
+    AppChannelExt,
+    AppMessageExt,
+    Channel,
+    ChannelDirection,
+    ChannelSettings,
+    Message,
+    Mode,
+    SharedPlugin,
+};
+use serde::de::{
+    self,
+    DeserializeOwned,
+    DeserializeSeed,
+    EnumAccess,
+    IntoDeserializer,
+    MapAccess,
+    SeqAccess,
+    VariantAccess,
+    Visitor,
+};
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+use crate::network::protocol::ProtocolPlugin;
+use crate::network::shared::shared_config;
+
+pub const GAME_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Sent along with every login.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub game: String,
+    pub id: u64,
+}
+
+impl ProtocolVersion {
+    pub fn current() -> Self {
+        Self { game: GAME_VERSION.to_string(), id: protocol_id() }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (protocol {:016x})", self.game, self.id)
+    }
+}
+
+// Used as the netcode protocol id, which every connect token carries.
+pub fn protocol_id() -> u64 {
+    static ID: OnceLock<u64> = OnceLock::new();
+    *ID.get_or_init(|| {
+        let digest = Sha256::new()
+            .chain_update(GAME_VERSION.as_bytes())
+            .chain_update(b"\n")
+            .chain_update(protocol_fingerprint(&protocol_manifest()).as_bytes())
+            .finalize();
+        u64::from_be_bytes(digest[..8].try_into().unwrap())
+    })
+}
+
+// Hex sha256 of the manifest alone, without the game version.
+pub fn protocol_fingerprint(manifest: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for entry in manifest {
+        hasher.update(entry.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Builds the protocol in an app of its own, since the id is needed before the real ones exist.
+pub fn protocol_manifest() -> Vec<String> {
+    let mut app = App::new();
+    app.add_plugins(SharedPlugin { config: shared_config(Mode::Separate) });
+    app.add_plugins(ProtocolPlugin);
+    app.world_mut().remove_resource::<ProtocolManifest>().unwrap_or_default().0
+}
+
+// One line per registered message, component, input and channel, in registration order.
+#[derive(Resource, Debug, Default)]
+pub struct ProtocolManifest(pub Vec<String>);
+
+// Messages and channels are registered and described in one go. Components go through
+// register_described_component instead, describe_component is only there for it to call.
+pub trait AppProtocolManifestExt {
+    fn describe_component<C: DeserializeOwned>(&mut self, direction: ChannelDirection) -> &mut Self;
+    fn describe_input<A: DeserializeOwned>(&mut self) -> &mut Self;
+    fn register_described_message<M: Message + Serialize + DeserializeOwned>(
+        &mut self,
+        direction: ChannelDirection
+    ) -> &mut Self;
+    fn add_described_channel<C: Channel>(&mut self, settings: ChannelSettings) -> &mut Self;
+}
+
+impl AppProtocolManifestExt for App {
+    fn describe_component<C: DeserializeOwned>(&mut self, direction: ChannelDirection) -> &mut Self {
+        describe(self, format!("component {:?} {}", direction, shape::<C>()))
+    }
+
+    fn describe_input<A: DeserializeOwned>(&mut self) -> &mut Self {
+        describe(self, format!("input {}", shape::<A>()))
+    }
+
+    fn register_described_message<M: Message + Serialize + DeserializeOwned>(
+        &mut self,
+        direction: ChannelDirection
+    ) -> &mut Self {
+        self.register_message::<M>(direction);
+        describe(self, format!("message {:?} {}", direction, shape::<M>()))
+    }
+
+    // Only the mode matters to the other side, priorities and send rates are local.
+    fn add_described_channel<C: Channel>(&mut self, settings: ChannelSettings) -> &mut Self {
+        describe(self, format!("channel {} {:?}", C::name(), settings.mode));
+        self.add_channel::<C>(settings);
+        self
+    }
+}
+
+// Describes and registers a component, and hands back lightyear's registration so prediction and
+// the like can be chained on. A macro because that registration type isn't public.
+#[macro_export]
+macro_rules! register_described_component {
+    ($app:expr, $component:ty, $direction:expr) => {
+        {
+            let direction = $direction;
+            let app = $crate::network::version::AppProtocolManifestExt::describe_component::<$component>(
+                &mut *$app,
+                direction
+            );
+            lightyear::prelude::AppComponentExt::register_component::<$component>(app, direction)
+        }
+    };
+}
+
+fn describe(app: &mut App, entry: String) -> &mut App {
+    app.world_mut().get_resource_or_insert_with(ProtocolManifest::default).0.push(entry);
+    app
+}
+
+// How deep options, sequences and maps are followed, so a recursive type can't go on forever.
+const MAX_DEPTH: usize = 16;
+
+// The shape of T as it goes over the wire: struct and field names, enum variants and the
+// primitives underneath. Each pass takes the next variant of every enum, until all of them
+// have been through once.
+pub fn shape<T: DeserializeOwned>() -> String {
+    let mut out = String::new();
+    let mut widest = 1;
+    let mut pass = 0;
+    while pass < widest {
+        let tracer = Tracer { out: &mut out, pass, widest: &mut widest, depth: 0 };
+        if let Err(err) = T::deserialize(tracer) {
+            let _ = write!(out, " !{}", err);
+        }
+        out.push(';');
+        pass += 1;
+    }
+    out
+}
+
+#[derive(Debug)]
+struct TraceError(String);
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        TraceError(msg.to_string())
+    }
+}
+
+// A Deserializer that makes up a value for everything it's asked for and writes down what that was.
+struct Tracer<'a> {
+    out: &'a mut String,
+    pass: usize,
+    widest: &'a mut usize,
+    depth: usize,
+}
+
+impl Tracer<'_> {
+    fn nested(&mut self) -> Tracer<'_> {
+        Tracer { out: &mut *self.out, pass: self.pass, widest: &mut *self.widest, depth: self.depth + 1 }
+    }
+
+    fn write(&mut self, token: &str) {
+        self.out.push(' ');
+        self.out.push_str(token);
+    }
+}
+
+macro_rules! trace_primitive {
+    ($method:ident, $visit:ident, $value:expr) => {
+        fn $method<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, TraceError> {
+            self.write(&stringify!($method)["deserialize_".len()..]);
+            visitor.$visit($value)
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Tracer<'_> {
+    type Error = TraceError;
+
+    trace_primitive!(deserialize_bool, visit_bool, false);
+    trace_primitive!(deserialize_i8, visit_i8, 0);
+    trace_primitive!(deserialize_i16, visit_i16, 0);
+    trace_primitive!(deserialize_i32, visit_i32, 0);
+    trace_primitive!(deserialize_i64, visit_i64, 0);
+    trace_primitive!(deserialize_u8, visit_u8, 0);
+    trace_primitive!(deserialize_u16, visit_u16, 0);
+    trace_primitive!(deserialize_u32, visit_u32, 0);
+    // Entities go over the wire as u64 and not every u64 is a valid one, this one is.
+    trace_primitive!(deserialize_u64, visit_u64, Entity::PLACEHOLDER.to_bits());
+    trace_primitive!(deserialize_f32, visit_f32, 0.0);
+    trace_primitive!(deserialize_f64, visit_f64, 0.0);
+    trace_primitive!(deserialize_char, visit_char, ' ');
+    trace_primitive!(deserialize_str, visit_str, "");
+    trace_primitive!(deserialize_string, visit_str, "");
+    trace_primitive!(deserialize_bytes, visit_bytes, &[]);
+    trace_primitive!(deserialize_byte_buf, visit_bytes, &[]);
+    trace_primitive!(deserialize_identifier, visit_str, "");
+
+    // Only self-describing formats get asked for "anything", which bincode isn't.
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, TraceError> {
+        self.write("any");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, TraceError> {
+        self.write("unit");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, TraceError> {
+        self.write("option");
+        if self.depth >= MAX_DEPTH {
+            return visitor.visit_none();
+        }
+        visitor.visit_some(self.nested())
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        visitor: V
+    ) -> Result<V::Value, TraceError> {
+        self.write(name);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        visitor: V
+    ) -> Result<V::Value, TraceError> {
+        self.write(name);
+        visitor.visit_newtype_struct(self.nested())
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, TraceError> {
+        self.write("seq");
+        let remaining = if self.depth >= MAX_DEPTH { 0 } else { 1 };
+        visitor.visit_seq(Elements { tracer: self.nested(), remaining })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(mut self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        self.write(&format!("tuple{}", len));
+        visitor.visit_seq(Elements { tracer: self.nested(), remaining: len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        len: usize,
+        visitor: V
+    ) -> Result<V::Value, TraceError> {
+        self.write(&format!("{}{}", name, len));
+        visitor.visit_seq(Elements { tracer: self.nested(), remaining: len })
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, TraceError> {
+        self.write("map");
+        let remaining = if self.depth >= MAX_DEPTH { 0 } else { 1 };
+        visitor.visit_map(Entries { tracer: self.nested(), remaining })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, TraceError> {
+        self.write(name);
+        visitor.visit_map(Fields { tracer: self.nested(), fields, next: 0 })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        mut self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, TraceError> {
+        self.write(&format!("{}<{}>", name, variants.join("|")));
+        let Some(last) = variants.len().checked_sub(1) else {
+            return Err(TraceError(format!("{} has no variants", name)));
+        };
+        *self.widest = (*self.widest).max(variants.len());
+        let variant = variants[self.pass.min(last)];
+        self.write(variant);
+        visitor.visit_enum(Variant { tracer: self.nested(), variant })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        // Like the bincode that actually goes over the wire.
+        false
+    }
+}
+
+struct Elements<'a> {
+    tracer: Tracer<'a>,
+    remaining: usize,
+}
+
+impl<'de> SeqAccess<'de> for Elements<'_> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(self.tracer.nested()).map(Some)
+    }
+}
+
+struct Entries<'a> {
+    tracer: Tracer<'a>,
+    remaining: usize,
+}
+
+impl<'de> MapAccess<'de> for Entries<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(self.tracer.nested()).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, TraceError> {
+        seed.deserialize(self.tracer.nested())
+    }
+}
+
+struct Fields<'a> {
+    tracer: Tracer<'a>,
+    fields: &'static [&'static str],
+    next: usize,
+}
+
+impl<'de> MapAccess<'de> for Fields<'_> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, TraceError> {
+        let Some(field) = self.fields.get(self.next) else {
+            return Ok(None);
+        };
+        self.tracer.write(&format!("{}:", field));
+        seed.deserialize(IntoDeserializer::<TraceError>::into_deserializer(*field)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, TraceError> {
+        self.next += 1;
+        seed.deserialize(self.tracer.nested())
+    }
+}
+
+struct Variant<'a> {
+    tracer: Tracer<'a>,
+    variant: &'static str,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Variant<'a> {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), TraceError> {
+        let value = seed.deserialize(IntoDeserializer::<TraceError>::into_deserializer(self.variant))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant<'_> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(mut self, seed: T) -> Result<T::Value, TraceError> {
+        seed.deserialize(self.tracer.nested())
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(mut self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_seq(Elements { tracer: self.tracer.nested(), remaining: len })
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        mut self,
+        fields: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, TraceError> {
+        visitor.visit_map(Fields { tracer: self.tracer.nested(), fields, next: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lightyear::prelude::{ ChannelRegistry, ComponentRegistry, MessageRegistry };
+
+    use super::*;
+
+    // Every protocol the game has shipped with, by version. When the test below fails, bump the
+    // version in Cargo.toml and add the new version with the fingerprint from the failure message.
+    const KNOWN_PROTOCOLS: &[(&str, &str)] = &[
+        ("0.0.1", "5e572af9e485e79a363cf024cc494f322b22bf7c2b9d2203fe583b7a152c4ff0"),
+    ];
+
+    #[test]
+    fn protocol_changes_come_with_a_version_bump() {
+        let fingerprint = protocol_fingerprint(&protocol_manifest());
+        let Some((_, known)) = KNOWN_PROTOCOLS.iter().find(|(version, _)| *version == GAME_VERSION) else {
+            panic!("No protocol recorded for {}, add (\"{}\", \"{}\")", GAME_VERSION, GAME_VERSION, fingerprint);
+        };
+        assert_eq!(
+            *known,
+            fingerprint,
+            "The protocol changed but the version is still {}. Bump it and add the new fingerprint",
+            GAME_VERSION
+        );
+    }
+
+    // Lightyear 0.18 can't list what's in its component and message registries, but their Debug output
+    // has the next net id, which is how many things have been registered.
+    fn registered<R: Resource + fmt::Debug>(app: &App) -> usize {
+        let registry = format!("{:?}", app.world().resource::<R>());
+        registry
+            .split("next_net_id: ")
+            .nth(1)
+            .and_then(|rest| rest.split(|c: char| !c.is_ascii_digit()).next())
+            .and_then(|id| id.parse().ok())
+            .expect("the registry's Debug output no longer has its next_net_id")
+    }
+
+    fn channel_names(app: &App) -> Vec<String> {
+        let registry = app.world().resource::<ChannelRegistry>();
+        registry
+            .channels()
+            .keys()
+            .filter_map(|kind| registry.name(kind).map(str::to_string))
+            .collect()
+    }
+
+    // Anything registered without being described would change the wire format but not the id.
+    #[test]
+    fn protocol_plugin_only_registers_described_things() {
+        let mut lightyear = App::new();
+        lightyear.add_plugins(SharedPlugin { config: shared_config(Mode::Separate) });
+        let mut app = App::new();
+        app.add_plugins(SharedPlugin { config: shared_config(Mode::Separate) });
+        app.add_plugins(ProtocolPlugin);
+        // Inputs are only registered once the app is finished.
+        lightyear.finish();
+        app.finish();
+        let manifest = app.world_mut().remove_resource::<ProtocolManifest>().unwrap_or_default().0;
+        let described = |kind: &str| manifest.iter().filter(|entry| entry.starts_with(kind)).count();
+
+        // An input is one message carrying the actions and one component holding them.
+        let inputs = described("input ");
+        let components = registered::<ComponentRegistry>(&app) - registered::<ComponentRegistry>(&lightyear);
+        assert_eq!(components, described("component ") + inputs, "components registered vs described");
+        let messages = registered::<MessageRegistry>(&app) - registered::<MessageRegistry>(&lightyear);
+        assert_eq!(messages, described("message ") + inputs, "messages registered vs described");
+
+        let builtin = channel_names(&lightyear);
+        let mut channels: Vec<_> = channel_names(&app)
+            .into_iter()
+            .filter(|name| !builtin.contains(name))
+            .collect();
+        channels.sort();
+        let mut described: Vec<_> = manifest
+            .iter()
+            .filter_map(|entry| entry.strip_prefix("channel "))
+            .filter_map(|entry| entry.split(' ').next())
+            .map(str::to_string)
+            .collect();
+        described.sort();
+        assert_eq!(channels, described, "channels registered vs described");
+    }
+
+    #[test]
+    fn shapes_tell_types_apart() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        enum Before {
+            A(u32),
+            B {
+                x: f32,
+            },
+        }
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        enum After {
+            A(u32),
+            B {
+                x: f64,
+            },
+        }
+        // Same names, only the second variant's field changed.
+        assert_ne!(shape::<Before>().replace("Before", ""), shape::<After>().replace("After", ""));
+    }
+}
@@ -0,0 +1,179 @@
+// Chat panel in the bottom left. Enter starts typing and sends, Escape throws the line away.
+// Movement is switched off while typing, otherwise WASD would walk around instead of ending up in the message.
+
+use bevy::input::keyboard::{ Key, KeyboardInput };
+use bevy::input::ButtonState;
+use bevy::prelude::*;
+use leafwing_input_manager::prelude::{ ActionState, InputMap };
+
+use crate::network::chat::{ parse_chat_input, AskWhoIsOnline, ChatLog, SendChat };
+use crate::network::protocol::{ ChatMessage, ChatScope, ChatSender, PlayerActions };
+
+// How many of the latest messages the panel shows.
+const VISIBLE_MESSAGES: usize = 10;
+
+const CHAT_FONT_SIZE: f32 = 16.0;
+
+#[derive(Resource, Default)]
+struct ChatInput {
+    typing: bool,
+    line: String,
+}
+
+#[derive(Component)]
+struct ChatHistory;
+
+#[derive(Component)]
+struct ChatInputLine;
+
+pub(crate) struct ChatPanelPlugin;
+
+impl Plugin for ChatPanelPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatInput>();
+        app.add_systems(Startup, spawn_chat_panel);
+        app.add_systems(Update, (type_chat, update_chat_history, update_chat_input).chain());
+    }
+}
+
+fn spawn_chat_panel(mut commands: Commands) {
+    commands
+        .spawn(Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(10.0),
+            left: Val::Px(10.0),
+            width: Val::Px(420.0),
+            flex_direction: FlexDirection::Column,
+            ..default()
+        })
+        .with_children(|panel| {
+            panel.spawn((
+                Node {
+                    flex_direction: FlexDirection::Column,
+                    ..default()
+                },
+                ChatHistory,
+            ));
+            panel.spawn((
+                Text::default(),
+                TextColor(Color::srgb(0.95, 0.95, 0.95)),
+                TextFont::from_font_size(CHAT_FONT_SIZE),
+                BackgroundColor(Color::BLACK.with_alpha(0.4)),
+                Visibility::Hidden,
+                ChatInputLine,
+            ));
+        });
+}
+
+fn type_chat(
+    mut keys: EventReader<KeyboardInput>,
+    mut input: ResMut<ChatInput>,
+    mut sends: EventWriter<SendChat>,
+    mut asks: EventWriter<AskWhoIsOnline>,
+    mut actions: Query<&mut ActionState<PlayerActions>, With<InputMap<PlayerActions>>>
+) {
+    for key in keys.read() {
+        if key.state != ButtonState::Pressed {
+            continue;
+        }
+        match (&key.logical_key, input.typing) {
+            (Key::Enter, false) => {
+                input.typing = true;
+            }
+            (Key::Enter, true) => {
+                if input.line.trim() == "/who" {
+                    asks.send(AskWhoIsOnline);
+                } else if let Some(request) = parse_chat_input(&input.line) {
+                    sends.send(SendChat(request));
+                }
+                input.line.clear();
+                input.typing = false;
+            }
+            (Key::Escape, true) => {
+                input.line.clear();
+                input.typing = false;
+            }
+            (Key::Backspace, true) => {
+                input.line.pop();
+            }
+            (Key::Space, true) => input.line.push(' '),
+            (Key::Character(text), true) => input.line.push_str(text),
+            _ => {}
+        }
+    }
+
+    // Disabling also releases whatever was held, so the player doesn't keep walking while we type.
+    for mut action in actions.iter_mut() {
+        if input.typing && !action.disabled() {
+            action.disable();
+        } else if !input.typing && action.disabled() {
+            action.enable();
+        }
+    }
+}
+
+fn format_message(message: &ChatMessage) -> String {
+    let name = match &message.sender {
+        ChatSender::System => {
+            return message.text.clone();
+        }
+        ChatSender::Player { name, .. } => name,
+    };
+    match &message.scope {
+        ChatScope::Global => format!("{}: {}", name, message.text),
+        ChatScope::Proximity => format!("[local] {}: {}", name, message.text),
+        ChatScope::Team => format!("[team] {}: {}", name, message.text),
+        ChatScope::Whisper(to) => format!("{} -> {}: {}", name, to, message.text),
+    }
+}
+
+fn message_color(message: &ChatMessage) -> Color {
+    if message.sender == ChatSender::System {
+        return Color::srgb(1.0, 0.85, 0.4);
+    }
+    match message.scope {
+        ChatScope::Global => Color::srgb(0.95, 0.95, 0.95),
+        ChatScope::Proximity => Color::srgb(0.75, 0.75, 0.75),
+        ChatScope::Team => Color::srgb(0.5, 0.8, 1.0),
+        ChatScope::Whisper(_) => Color::srgb(0.9, 0.6, 0.9),
+    }
+}
+
+// Rebuilt from scratch whenever something arrives, it's only a handful of lines.
+fn update_chat_history(
+    mut commands: Commands,
+    log: Res<ChatLog>,
+    history: Query<Entity, With<ChatHistory>>
+) {
+    if !log.is_changed() {
+        return;
+    }
+    let skip = log.messages.len().saturating_sub(VISIBLE_MESSAGES);
+    for panel in history.iter() {
+        commands
+            .entity(panel)
+            .despawn_descendants()
+            .with_children(|panel| {
+                for message in log.messages.iter().skip(skip) {
+                    panel.spawn((
+                        Text(format_message(message)),
+                        TextColor(message_color(message)),
+                        TextFont::from_font_size(CHAT_FONT_SIZE),
+                    ));
+                }
+            });
+    }
+}
+
+fn update_chat_input(
+    input: Res<ChatInput>,
+    mut line: Query<(&mut Text, &mut Visibility), With<ChatInputLine>>
+) {
+    if !input.is_changed() {
+        return;
+    }
+    for (mut text, mut visibility) in line.iter_mut() {
+        text.0 = format!("> {}_", input.line);
+        *visibility = if input.typing { Visibility::Inherited } else { Visibility::Hidden };
+    }
+}
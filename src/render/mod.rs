@@ -1 +1,7 @@
 pub mod ui;
+#[cfg(feature = "client")]
+pub mod prediction;
+#[cfg(feature = "client")]
+pub mod players;
+#[cfg(feature = "client")]
+pub mod chat;
@@ -0,0 +1,65 @@
+// Draws players. Remote players are interpolated between server snapshots, the local one is predicted,
+// either way we just follow PlayerPosition and lightyear takes care of making it smooth.
+
+use bevy::prelude::*;
+use lightyear::prelude::client::{ Interpolated, Predicted };
+use lightyear::prelude::Replicating;
+
+use crate::network::protocol::{ PlayerColor, PlayerId, PlayerPosition };
+
+// World units are a lot smaller than pixels, this is how many pixels one of them takes up.
+pub const PIXELS_PER_UNIT: f32 = 32.0;
+
+// Size of the placeholder square a player is drawn as, in world units.
+const PLAYER_SIZE: f32 = 1.0;
+
+pub(crate) struct PlayerRenderPlugin;
+
+impl Plugin for PlayerRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, add_player_visuals);
+        // After interpolation and visual correction have written this frame's position.
+        app.add_systems(PostUpdate, sync_player_transforms.before(TransformSystem::TransformPropagate));
+    }
+}
+
+fn to_translation(position: &PlayerPosition) -> Vec3 {
+    Vec3::new(position.x, position.y, 0.0) * PIXELS_PER_UNIT
+}
+
+// Waits for the position so interpolated players don't flash at the origin before their first snapshot.
+// Confirmed copies are skipped, only the predicted or interpolated one is drawn.
+// When hosting there are no copies at all and the server's own entities are drawn.
+#[allow(clippy::type_complexity)]
+fn add_player_visuals(
+    mut commands: Commands,
+    players: Query<
+        (Entity, &PlayerColor, &PlayerPosition),
+        (
+            With<PlayerId>,
+            Without<Sprite>,
+            Or<(With<Predicted>, With<Interpolated>, With<Replicating>)>,
+        )
+    >
+) {
+    for (entity, color, position) in players.iter() {
+        commands.entity(entity).insert((
+            Sprite {
+                color: color.0,
+                custom_size: Some(Vec2::splat(PLAYER_SIZE * PIXELS_PER_UNIT)),
+                ..default()
+            },
+            Transform::from_translation(to_translation(position)),
+        ));
+    }
+}
+
+// The sprite lives on the player entity itself, so it goes away with it when the server despawns the player.
+#[allow(clippy::type_complexity)]
+fn sync_player_transforms(
+    mut players: Query<(&PlayerPosition, &mut Transform), (With<PlayerId>, With<Sprite>)>
+) {
+    for (position, mut transform) in players.iter_mut() {
+        transform.translation = to_translation(position);
+    }
+}
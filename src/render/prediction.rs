@@ -0,0 +1,115 @@
+// Debug overlay for client-side prediction. Shows how often we mispredict and how far off we were,
+// which is what we look at when tuning against the conditioner's latency profiles. F3 toggles it.
+
+use bevy::prelude::*;
+use lightyear::client::prediction::diagnostics::PredictionMetrics;
+use lightyear::prelude::client::{ ConnectionManager, Correction, Predicted, PredictionSet };
+
+use crate::network::protocol::PlayerPosition;
+
+// Everything the overlay displays. Correction distances are in world units.
+#[derive(Resource, Debug, Default)]
+pub struct PredictionStats {
+    pub rollbacks: u32,
+    pub rollback_ticks: u32,
+    pub corrections: u32,
+    pub last_correction: f32,
+    pub max_correction: f32,
+    pub total_correction: f32,
+}
+
+impl PredictionStats {
+    pub fn average_correction(&self) -> f32 {
+        if self.corrections == 0 {
+            0.0
+        } else {
+            self.total_correction / (self.corrections as f32)
+        }
+    }
+}
+
+#[derive(Component)]
+struct PredictionOverlay;
+
+pub(crate) struct PredictionDebugPlugin;
+
+impl Plugin for PredictionDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PredictionStats>();
+        app.add_systems(Startup, spawn_overlay);
+        // Right after the replay the predicted position is the corrected one,
+        // while the Correction still remembers what we had predicted.
+        app.add_systems(PreUpdate, measure_corrections.after(PredictionSet::Rollback));
+        app.add_systems(Update, (toggle_overlay, update_overlay));
+    }
+}
+
+fn measure_corrections(
+    metrics: Res<PredictionMetrics>,
+    mut stats: ResMut<PredictionStats>,
+    players: Query<(&PlayerPosition, &Correction<PlayerPosition>), With<Predicted>>
+) {
+    // Only a rollback this frame means a fresh misprediction.
+    if metrics.rollbacks == stats.rollbacks {
+        return;
+    }
+    stats.rollbacks = metrics.rollbacks;
+    stats.rollback_ticks = metrics.rollback_ticks;
+
+    for (position, correction) in players.iter() {
+        let distance = position.0.distance(correction.original_prediction.0);
+        stats.corrections += 1;
+        stats.last_correction = distance;
+        stats.max_correction = stats.max_correction.max(distance);
+        stats.total_correction += distance;
+    }
+}
+
+fn spawn_overlay(mut commands: Commands) {
+    commands.spawn((
+        Text::default(),
+        TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        TextFont::from_font_size(14.0),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        },
+        Visibility::Hidden,
+        PredictionOverlay,
+    ));
+}
+
+fn toggle_overlay(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut overlay: Query<&mut Visibility, With<PredictionOverlay>>
+) {
+    if !keys.just_pressed(KeyCode::F3) {
+        return;
+    }
+    for mut visibility in overlay.iter_mut() {
+        visibility.toggle_visible_hidden();
+    }
+}
+
+fn update_overlay(
+    stats: Res<PredictionStats>,
+    connection: Option<Res<ConnectionManager>>,
+    mut overlay: Query<&mut Text, With<PredictionOverlay>>
+) {
+    let ping = connection.map_or("-".to_string(), |connection| {
+        format!("{:.0}ms", connection.ping_manager.rtt().as_secs_f32() * 1000.0)
+    });
+    for mut text in overlay.iter_mut() {
+        text.0 = format!(
+            "rtt: {}\nmispredictions: {}\nticks replayed: {}\ncorrection last: {:.3} avg: {:.3} max: {:.3}",
+            ping,
+            stats.rollbacks,
+            stats.rollback_ticks,
+            stats.last_correction,
+            stats.average_correction(),
+            stats.max_correction
+        );
+    }
+}
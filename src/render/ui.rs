@@ -5,6 +5,15 @@ use lightyear::prelude::client::ClientCommands;
 use lightyear::prelude::client::*;
 use serde::Deserialize;
 
+#[cfg(feature = "client")]
+use crate::render::prediction::PredictionDebugPlugin;
+#[cfg(feature = "client")]
+use crate::network::client::{ LoginError, LoginRequest };
+#[cfg(feature = "client")]
+use crate::render::players::PlayerRenderPlugin;
+#[cfg(feature = "client")]
+use crate::render::chat::ChatPanelPlugin;
+
 #[derive(Resource)]
 struct GameName(String);
 
@@ -25,7 +34,15 @@ impl Plugin for UiRenderPlugin {
         #[cfg(feature = "gui")]
         app.add_systems(Startup, set_window_title);
         #[cfg(feature = "client")]
-        spawn_connect_button(app)
+        spawn_connect_button(app);
+        #[cfg(feature = "client")]
+        app.add_systems(Update, update_status_message);
+        #[cfg(feature = "client")]
+        app.add_plugins(PredictionDebugPlugin);
+        #[cfg(feature = "client")]
+        app.add_plugins(PlayerRenderPlugin);
+        #[cfg(feature = "client")]
+        app.add_plugins(ChatPanelPlugin);
     }
 }
 
@@ -38,6 +55,37 @@ fn set_window_title(mut window: Query<&mut Window>, game_name: Res<GameName>) {
 #[derive(Component)]
 struct StatusMessageMarker;
 
+#[cfg(feature = "client")]
+const CONNECT_HINT: &str = "Click to connect/Disconnect";
+
+#[cfg(feature = "client")]
+fn hint_color() -> Color {
+    Color::srgb(0.9, 0.9, 0.9).with_alpha(0.4)
+}
+
+#[cfg(feature = "client")]
+/// Shows why the last login failed, e.g. a version mismatch, in place of the usual hint
+fn update_status_message(
+    login_error: Res<LoginError>,
+    mut status: Query<(&mut Text, &mut TextColor), With<StatusMessageMarker>>
+) {
+    if !login_error.is_changed() {
+        return;
+    }
+    for (mut text, mut color) in status.iter_mut() {
+        match &login_error.0 {
+            Some(reason) => {
+                text.0 = format!("Login failed: {}", reason);
+                color.0 = Color::srgb(1.0, 0.45, 0.45);
+            }
+            None => {
+                text.0 = CONNECT_HINT.to_string();
+                color.0 = hint_color();
+            }
+        }
+    }
+}
+
 #[cfg(feature = "client")]
 /// Create a button that allow you to connect/disconnect to a server
 pub(crate) fn spawn_connect_button(app: &mut App) {
@@ -52,8 +100,8 @@ pub(crate) fn spawn_connect_button(app: &mut App) {
         })
         .with_children(|parent| {
             parent.spawn((
-                Text("Click to connect/Disconnect".to_string()),
-                TextColor(Color::srgb(0.9, 0.9, 0.9).with_alpha(0.4)),
+                Text(CONNECT_HINT.to_string()),
+                TextColor(hint_color()),
                 TextFont::from_font_size(18.0),
                 StatusMessageMarker,
                 Node {
@@ -89,7 +137,7 @@ pub(crate) fn spawn_connect_button(app: &mut App) {
                     | {
                         match state.get() {
                             NetworkingState::Disconnected => {
-                                commands.connect_client();
+                                commands.send_event(LoginRequest);
                             }
                             NetworkingState::Connecting | NetworkingState::Connected => {
                                 commands.disconnect_client();
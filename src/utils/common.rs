@@ -1,11 +1,11 @@
 use bevy::prelude::{ Component, Reflect };
-use serde::Deserialize;
+use serde::{ Deserialize, Serialize };
 use rustc_hash::FxHashSet;
 use bevy_rand::prelude::{ WyRand, Entropy };
 use rand_core::RngCore;
 
 // Can be used for lots, but currently only using for item properties
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PropertyValue {
     Bool(bool),
     Int(i32),
@@ -13,7 +13,28 @@ pub enum PropertyValue {
     Text(String),
 }
 
-#[derive(Component, Debug, Clone, Deserialize, Reflect)]
+// Just the variant of a PropertyValue, used wherever the type matters but the value doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum PropertyKind {
+    Bool,
+    Int,
+    Float,
+    Text,
+}
+
+impl PropertyValue {
+    pub fn kind(&self) -> PropertyKind {
+        match self {
+            PropertyValue::Bool(_) => PropertyKind::Bool,
+            PropertyValue::Int(_) => PropertyKind::Int,
+            PropertyValue::Float(_) => PropertyKind::Float,
+            PropertyValue::Text(_) => PropertyKind::Text,
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize, Reflect)]
+#[serde(transparent)]
 pub struct Weight(pub f32);
 
 impl Default for Weight {
@@ -22,16 +43,12 @@ impl Default for Weight {
     }
 }
 
-#[derive(Component, Debug, Clone, Deserialize)]
+#[derive(Component, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Tags(pub FxHashSet<String>);
 
-impl Default for Tags {
-    fn default() -> Self {
-        Self(Default::default())
-    }
-}
-
-#[derive(Component, Clone, Debug, Deserialize, Reflect)]
+#[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize, Reflect)]
+#[serde(transparent)]
 pub struct DisplayName(pub String);
 
 impl Default for DisplayName {
@@ -40,7 +57,8 @@ impl Default for DisplayName {
     }
 }
 
-#[derive(Component, Clone, Debug, Deserialize, Reflect)]
+#[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize, Reflect)]
+#[serde(transparent)]
 pub struct Icon(pub String);
 
 impl Default for Icon {
@@ -49,20 +67,289 @@ impl Default for Icon {
     }
 }
 
+// Random selection helpers. Everything here only draws from the rng it's given, in a fixed order,
+// so the same seed and the same input always give the same result. Iterating a hashset isn't in a
+// fixed order though, so sort the input first wherever that matters.
+
+// Uniform random number in 0..bound, without the bias a plain modulo would have. `bound` must not be 0.
+pub fn random_below(bound: u64, rng: &mut Entropy<WyRand>) -> u64 {
+    // Lemire's method: multiply into 128 bits and reject the few values that would skew the result.
+    let threshold = bound.wrapping_neg() % bound;
+    loop {
+        let product = (rng.next_u64() as u128) * (bound as u128);
+        if (product as u64) >= threshold {
+            return (product >> 64) as u64;
+        }
+    }
+}
+
+// Random float in the open interval (0, 1).
+pub fn random_unit(rng: &mut Entropy<WyRand>) -> f64 {
+    ((rng.next_u64() >> 11) as f64 + 0.5) / ((1u64 << 53) as f64)
+}
+
+// Random number in the inclusive range min..=max.
+pub fn random_range(min: u32, max: u32, rng: &mut Entropy<WyRand>) -> u32 {
+    if max <= min {
+        return min;
+    }
+    min + (random_below((max - min) as u64 + 1, rng) as u32)
+}
+
 // Uses the bevy_rand crate to choose randoms from a hashset.
 pub fn choose_random<T, I>(iter: I, rng: &mut Entropy<WyRand>) -> Option<T>
     where I: IntoIterator<Item = T>
 {
-    let mut iter = iter.into_iter();
     let mut chosen = None;
     let mut count = 0;
 
-    while let Some(item) = iter.next() {
+    for item in iter {
         count += 1;
-        if (rng.next_u32() as usize) % count == 0 {
+        if random_below(count, rng) == 0 {
             chosen = Some(item);
         }
     }
 
     chosen
 }
+
+// Same idea as choose_random, but each element comes with a weight.
+// Elements with a weight of 0 are never picked.
+pub fn choose_weighted<T, I>(iter: I, rng: &mut Entropy<WyRand>) -> Option<T>
+    where I: IntoIterator<Item = (T, u32)>
+{
+    let mut chosen = None;
+    let mut total: u64 = 0;
+
+    for (item, weight) in iter {
+        if weight == 0 {
+            continue;
+        }
+        total += weight as u64;
+        if random_below(total, rng) < (weight as u64) {
+            chosen = Some(item);
+        }
+    }
+
+    chosen
+}
+
+// Picks up to `amount` distinct elements, each equally likely. Returned in no particular order.
+pub fn choose_multiple<T, I>(iter: I, amount: usize, rng: &mut Entropy<WyRand>) -> Vec<T>
+    where I: IntoIterator<Item = T>
+{
+    let mut chosen = Vec::with_capacity(amount);
+    if amount == 0 {
+        return chosen;
+    }
+
+    for (index, item) in iter.into_iter().enumerate() {
+        if index < amount {
+            chosen.push(item);
+        } else {
+            let slot = random_below((index + 1) as u64, rng) as usize;
+            if slot < amount {
+                chosen[slot] = item;
+            }
+        }
+    }
+
+    chosen
+}
+
+// Fisher-Yates shuffle in place.
+pub fn shuffle<T>(items: &mut [T], rng: &mut Entropy<WyRand>) {
+    for index in (1..items.len()).rev() {
+        let other = random_below((index + 1) as u64, rng) as usize;
+        items.swap(index, other);
+    }
+}
+
+// Weighted sampling without replacement: picks up to `amount` distinct elements, where each pick
+// is weighted among whatever hasn't been picked yet. Elements with a weight of 0 are never picked.
+// Returned in pick order, so the first element is distributed exactly like choose_weighted.
+pub fn choose_weighted_multiple<T, I>(iter: I, amount: usize, rng: &mut Entropy<WyRand>) -> Vec<T>
+    where I: IntoIterator<Item = (T, u32)>
+{
+    // Efraimidis-Spirakis: every element gets the key ln(u) / weight, the highest keys win.
+    let mut keyed: Vec<(f64, T)> = iter
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0)
+        .map(|(item, weight)| (random_unit(rng).ln() / (weight as f64), item))
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.truncate(amount);
+    keyed
+        .into_iter()
+        .map(|(_, item)| item)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::SeedableRng;
+
+    const SAMPLES: usize = 100_000;
+
+    fn rng(seed: u64) -> Entropy<WyRand> {
+        Entropy::<WyRand>::seed_from_u64(seed)
+    }
+
+    // Pearson's chi-squared statistic of observed counts against expected probabilities.
+    fn chi_squared(observed: &[usize], expected: &[f64]) -> f64 {
+        let total: usize = observed.iter().sum();
+        observed
+            .iter()
+            .zip(expected)
+            .map(|(observed, probability)| {
+                let expected = probability * (total as f64);
+                ((*observed as f64) - expected).powi(2) / expected
+            })
+            .sum()
+    }
+
+    // Critical values at p = 0.001, so a correct implementation fails about once in a thousand seeds.
+    // The seeds are fixed, so these either always pass or always fail.
+    fn critical_value(degrees_of_freedom: usize) -> f64 {
+        match degrees_of_freedom {
+            1 => 10.83,
+            2 => 13.82,
+            3 => 16.27,
+            4 => 18.47,
+            5 => 20.52,
+            9 => 27.88,
+            _ => unreachable!(),
+        }
+    }
+
+    fn assert_distribution(observed: &[usize], expected: &[f64]) {
+        let statistic = chi_squared(observed, expected);
+        let critical = critical_value(observed.len() - 1);
+        assert!(
+            statistic < critical,
+            "chi-squared {} above {} for {:?} (expected {:?})",
+            statistic,
+            critical,
+            observed,
+            expected
+        );
+    }
+
+    #[test]
+    fn same_seed_same_results() {
+        let items: Vec<u32> = (0..50).collect();
+        let weights: Vec<(u32, u32)> = items.iter().map(|i| (*i, i + 1)).collect();
+        let (mut a, mut b) = (rng(7), rng(7));
+
+        assert_eq!(choose_random(&items, &mut a), choose_random(&items, &mut b));
+        assert_eq!(
+            choose_weighted(weights.clone(), &mut a),
+            choose_weighted(weights.clone(), &mut b)
+        );
+        assert_eq!(choose_multiple(&items, 5, &mut a), choose_multiple(&items, 5, &mut b));
+        assert_eq!(
+            choose_weighted_multiple(weights.clone(), 5, &mut a),
+            choose_weighted_multiple(weights, 5, &mut b)
+        );
+        let (mut shuffled_a, mut shuffled_b) = (items.clone(), items.clone());
+        shuffle(&mut shuffled_a, &mut a);
+        shuffle(&mut shuffled_b, &mut b);
+        assert_eq!(shuffled_a, shuffled_b);
+    }
+
+    #[test]
+    fn random_range_is_uniform_and_inclusive() {
+        let mut rng = rng(1);
+        let mut counts = [0; 6];
+        for _ in 0..SAMPLES {
+            let value = random_range(10, 15, &mut rng);
+            assert!((10..=15).contains(&value));
+            counts[(value - 10) as usize] += 1;
+        }
+        assert_distribution(&counts, &[1.0 / 6.0; 6]);
+        assert_eq!(random_range(4, 4, &mut rng), 4);
+    }
+
+    #[test]
+    fn choose_random_is_uniform() {
+        let mut rng = rng(2);
+        let mut counts = [0; 10];
+        for _ in 0..SAMPLES {
+            counts[choose_random(0..10, &mut rng).unwrap()] += 1;
+        }
+        assert_distribution(&counts, &[0.1; 10]);
+        assert_eq!(choose_random(Vec::<u32>::new(), &mut rng), None);
+    }
+
+    #[test]
+    fn choose_weighted_follows_weights() {
+        let mut rng = rng(3);
+        let weights = [(0, 1), (1, 2), (2, 0), (3, 7)];
+        let mut counts = [0; 4];
+        for _ in 0..SAMPLES {
+            counts[choose_weighted(weights, &mut rng).unwrap()] += 1;
+        }
+        assert_eq!(counts[2], 0);
+        assert_distribution(&[counts[0], counts[1], counts[3]], &[0.1, 0.2, 0.7]);
+        assert_eq!(choose_weighted([(0, 0)], &mut rng), None);
+    }
+
+    #[test]
+    fn choose_multiple_is_distinct_and_uniform() {
+        let mut rng = rng(4);
+        let mut counts = [0; 10];
+        for _ in 0..SAMPLES {
+            let mut chosen = choose_multiple(0..10, 3, &mut rng);
+            chosen.sort();
+            chosen.dedup();
+            assert_eq!(chosen.len(), 3);
+            for item in chosen {
+                counts[item] += 1;
+            }
+        }
+        // Every element should be picked in 3 out of 10 samples.
+        assert_distribution(&counts, &[0.1; 10]);
+        assert_eq!(choose_multiple(0..2, 5, &mut rng).len(), 2);
+    }
+
+    #[test]
+    fn shuffle_positions_are_uniform() {
+        let mut rng = rng(5);
+        // Where the first element ends up should be uniform over every position.
+        let mut counts = [0; 5];
+        for _ in 0..SAMPLES {
+            let mut items = [0, 1, 2, 3, 4];
+            shuffle(&mut items, &mut rng);
+            counts[items.iter().position(|item| *item == 0).unwrap()] += 1;
+            items.sort();
+            assert_eq!(items, [0, 1, 2, 3, 4]);
+        }
+        assert_distribution(&counts, &[0.2; 5]);
+    }
+
+    #[test]
+    fn weighted_sampling_without_replacement() {
+        let mut rng = rng(6);
+        let weights = [(0, 1), (1, 2), (2, 3), (3, 0)];
+        let mut first = [0; 3];
+        let mut second = [0; 3];
+        for _ in 0..SAMPLES {
+            let chosen = choose_weighted_multiple(weights, 2, &mut rng);
+            assert_eq!(chosen.len(), 2);
+            assert_ne!(chosen[0], chosen[1]);
+            assert!(!chosen.contains(&3));
+            first[chosen[0]] += 1;
+            second[chosen[1]] += 1;
+        }
+        // The first pick is a plain weighted choice.
+        assert_distribution(&first, &[1.0 / 6.0, 2.0 / 6.0, 3.0 / 6.0]);
+        // The second is weighted among what's left, e.g. P(0 second) = P(1 first) * 1/4 + P(2 first) * 1/3.
+        let second_expected = [
+            (2.0 / 6.0) * (1.0 / 4.0) + (3.0 / 6.0) * (1.0 / 3.0),
+            (1.0 / 6.0) * (2.0 / 5.0) + (3.0 / 6.0) * (2.0 / 3.0),
+            (1.0 / 6.0) * (3.0 / 5.0) + (2.0 / 6.0) * (3.0 / 4.0),
+        ];
+        assert_distribution(&second, &second_expected);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod settings;
 pub mod common;
 pub mod macros;
+#[cfg(test)]
+pub mod scratch;
@@ -0,0 +1,32 @@
+// Scratch directories for tests. Each one lives under the system temp dir and is removed again
+// when it's dropped, so a failing assertion doesn't leave it behind.
+
+use std::ops::Deref;
+use std::path::{ Path, PathBuf };
+
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    // Tests running at the same time each need a name of their own. Emptied first, in case a
+    // run got killed before it could clean up.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("fleshborn-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,10 +1,14 @@
 #![allow(unused_imports)]
 #![allow(unused_variables)]
-use std::net::{ Ipv4Addr, SocketAddr };
+use std::fmt;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+use std::path::{ Path, PathBuf };
 
 use bevy::asset::ron;
-use bevy::prelude::{ Resource, info, default };
+use bevy::prelude::{ Resource, info, warn, default };
 use bevy::utils::Duration;
+use clap::ValueEnum;
+use serde::{ Deserialize, Serialize };
 
 use async_compat::Compat;
 use bevy::tasks::IoTaskPool;
@@ -15,24 +19,42 @@ use lightyear::prelude::{ CompressionConfig, LinkConditionerConfig };
 
 use lightyear::prelude::{ client, server };
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+use crate::game::app::Cli;
+use crate::network::version::protocol_id;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
 pub enum ClientTransports {
     Udp,
     WebTransport,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+// Every transport compresses its packets its own way, clients have to use the same as the port they connect to.
+// Lightyear 0.18 compresses packets after netcode has encrypted them, so don't expect much of any of it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum ServerTransports {
     Udp {
         local_port: u16,
+        #[serde(default)]
+        compression: CompressionConfig,
     },
     WebTransport {
         local_port: u16,
         certificate: WebTransportCertificateSettings,
+        #[serde(default)]
+        compression: CompressionConfig,
     },
 }
 
-#[derive(Clone, Debug)]
+impl ServerTransports {
+    pub fn port_and_compression(&self) -> (u16, CompressionConfig) {
+        match self {
+            ServerTransports::Udp { local_port, compression } |
+            ServerTransports::WebTransport { local_port, compression, .. } => (*local_port, *compression),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Conditioner {
     /// One way latency in milliseconds
     pub latency_ms: u16,
@@ -52,7 +74,7 @@ impl Conditioner {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ServerSettings {
     /// If true, disable any rendering-related plugins
     pub headless: bool,
@@ -65,16 +87,135 @@ pub struct ServerSettings {
 
     /// Which transport to use
     pub transport: Vec<ServerTransports>,
+
+    /// a 32-byte array to authenticate via the Netcode.io protocol. Only the server ever needs it,
+    /// clients get connect tokens from the auth service instead.
+    pub private_key: [u8; 32],
+
+    /// Where the auth service listens and who it lets in
+    #[serde(default)]
+    pub auth: AuthSettings,
+
+    /// Where accounts, characters and the world are saved
+    #[serde(default = "default_save_dir")]
+    pub save_dir: PathBuf,
+
+    /// How often the world is saved while running. 0 only saves on shutdown.
+    #[serde(default = "default_autosave_secs")]
+    pub autosave_secs: f32,
+
+    /// How long a disconnected player's character stays in the world waiting for a reconnect.
+    /// 0 despawns it right away.
+    #[serde(default)]
+    pub disconnect_grace_secs: f32,
+
+    /// Side length of the interest management grid cells, in world units
+    #[serde(default = "default_grid_cell_size")]
+    pub grid_cell_size: f32,
+
+    /// How many cells around their own a client gets updates for
+    #[serde(default = "default_view_radius")]
+    pub view_radius: u32,
+
+    /// Bytes per second the server sends each client at most. Channels with a higher priority get
+    /// their messages out first when it's tight, and entity updates go by network::priority.
+    /// There's no cap per channel, only this one for everything.
+    /// 0 sends everything as soon as possible.
+    #[serde(default)]
+    pub send_bandwidth_cap: u32,
+
+    /// What players are allowed to say and how often
+    #[serde(default)]
+    pub chat: ChatSettings,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatSettings {
+    /// Longest message accepted, in characters
+    pub max_length: usize,
+
+    /// How many messages a player can send within rate_limit_secs
+    pub rate_limit_messages: u32,
+
+    /// Length of the rate limiting window
+    pub rate_limit_secs: f32,
+
+    /// How far proximity chat carries, in world units
+    pub proximity_radius: f32,
+
+    /// RON file with the words to mask in chat
+    pub filter: PathBuf,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            max_length: 200,
+            rate_limit_messages: 5,
+            rate_limit_secs: 10.0,
+            proximity_radius: 24.0,
+            filter: PathBuf::from("assets/chat_filter.ron"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthSettings {
+    /// Address the auth service listens on. Passwords reach it in plain text, so only put it on
+    /// a network you trust, never on one the internet can reach. See network::auth.
+    #[serde(default = "default_auth_address")]
+    pub address: IpAddr,
+
+    /// TCP port the auth service listens on
+    pub port: u16,
+
+    /// RON file with the accepted users and invite codes
+    pub credentials: PathBuf,
+
+    /// How long an issued connect token stays valid
+    pub token_expire_secs: i32,
 }
 
-#[derive(Clone, Debug)]
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            address: default_auth_address(),
+            port: 5002,
+            credentials: PathBuf::from("assets/auth.ron"),
+            token_expire_secs: 30,
+        }
+    }
+}
+
+fn default_save_dir() -> PathBuf {
+    PathBuf::from("saves")
+}
+
+fn default_autosave_secs() -> f32 {
+    300.0
+}
+
+fn default_auth_address() -> IpAddr {
+    Ipv4Addr::LOCALHOST.into()
+}
+
+fn default_auth_port() -> u16 {
+    AuthSettings::default().port
+}
+
+fn default_grid_cell_size() -> f32 {
+    16.0
+}
+
+fn default_view_radius() -> u32 {
+    2
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClientSettings {
     /// If true, enable bevy_inspector_egui
     pub inspector: bool,
 
-    /// The client id
-    pub client_id: u64,
-
     /// The client port to listen on
     pub client_port: u16,
 
@@ -84,26 +225,31 @@ pub struct ClientSettings {
     /// The port of the server
     pub server_port: u16,
 
+    /// The port of the server's auth service
+    #[serde(default = "default_auth_port")]
+    pub auth_port: u16,
+
     /// Which transport to use
     pub transport: ClientTransports,
 
     /// Possibly add a conditioner to simulate network conditions
     pub conditioner: Option<Conditioner>,
+
+    /// How packets are compressed, has to match the server transport on server_port
+    #[serde(default)]
+    pub compression: CompressionConfig,
 }
 
-#[derive(Copy, Clone, Debug)]
+// The protocol id isn't a setting, it's derived from the protocol itself. See network::version.
+// Neither is compression, it goes with each connection. See ServerTransports and ClientSettings.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct SharedSettings {
-    /// An id to identify the protocol version
-    pub protocol_id: u64,
-
-    /// a 32-byte array to authenticate via the Netcode.io protocol
-    pub private_key: [u8; 32],
-
-    /// compression options
-    pub compression: CompressionConfig,
+    /// How often the per-channel traffic summary is logged. 0 never logs it.
+    #[serde(default)]
+    pub traffic_report_secs: f32,
 }
 
-#[derive(Resource, Debug, Clone)]
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
     pub server: ServerSettings,
     pub client: ClientSettings,
@@ -112,31 +258,24 @@ pub struct Settings {
 
 #[cfg(feature = "server")]
 pub(crate) fn build_server_netcode_config(
-    conditioner: Option<&Conditioner>,
-    shared: &SharedSettings,
-    transport_config: server::ServerTransport
+    server: &ServerSettings,
+    transport_config: server::ServerTransport,
+    compression: CompressionConfig
 ) -> server::NetConfig {
-    let conditioner = conditioner.map(|c| LinkConditionerConfig {
+    let conditioner = server.conditioner.as_ref().map(|c| LinkConditionerConfig {
         incoming_latency: Duration::from_millis(c.latency_ms as u64),
         incoming_jitter: Duration::from_millis(c.jitter_ms as u64),
         incoming_loss: c.packet_loss,
     });
-    // Use private key from environment variable, if set. Otherwise from settings file.
-    let privkey = if let Some(key) = parse_private_key_from_env() {
-        info!("Using private key from LIGHTYEAR_PRIVATE_KEY env var");
-        key
-    } else {
-        shared.private_key
-    };
 
     let netcode_config = server::NetcodeConfig
         ::default()
-        .with_protocol_id(shared.protocol_id)
-        .with_key(privkey);
+        .with_protocol_id(protocol_id())
+        .with_key(server_private_key(server));
     let io_config = server::IoConfig {
         transport: transport_config,
         conditioner,
-        compression: shared.compression,
+        compression,
     };
     server::NetConfig::Netcode {
         config: netcode_config,
@@ -144,7 +283,16 @@ pub(crate) fn build_server_netcode_config(
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg(feature = "server")]
+pub(crate) fn build_server_packet_config(server: &ServerSettings) -> server::PacketConfig {
+    let config = server::PacketConfig::default();
+    if server.send_bandwidth_cap == 0 {
+        return config;
+    }
+    config.with_send_bandwidth_bytes_per_second_cap(server.send_bandwidth_cap).enable_bandwidth_cap()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum WebTransportCertificateSettings {
     /// Generate a self-signed certificate, with given SANs list to add to the certifictate
     /// eg: ["example.com", "*.gameserver.example.org", "10.1.2.3", "::1"]
@@ -214,6 +362,17 @@ impl From<&WebTransportCertificateSettings> for server::Identity {
     }
 }
 
+/// Use private key from environment variable, if set. Otherwise from settings file.
+#[cfg(feature = "server")]
+pub(crate) fn server_private_key(server: &ServerSettings) -> [u8; PRIVATE_KEY_BYTES] {
+    if let Some(key) = parse_private_key_from_env() {
+        info!("Using private key from LIGHTYEAR_PRIVATE_KEY env var");
+        key
+    } else {
+        server.private_key
+    }
+}
+
 /// Parse the settings into a list of `NetConfig` that are used to configure how the lightyear server
 /// listens for incoming client connections
 #[cfg(feature = "server")]
@@ -222,24 +381,20 @@ pub(crate) fn get_server_net_configs(settings: &Settings) -> Vec<server::NetConf
         .iter()
         .map(|t| {
             match t {
-                ServerTransports::Udp { local_port } =>
+                ServerTransports::Udp { local_port, compression } =>
                     build_server_netcode_config(
-                        settings.server.conditioner.as_ref(),
-                        &settings.shared,
+                        &settings.server,
                         server::ServerTransport::UdpSocket(
                             SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *local_port)
-                        )
+                        ),
+                        *compression
                     ),
-                ServerTransports::WebTransport { local_port, certificate } => {
+                ServerTransports::WebTransport { local_port, certificate, compression } => {
                     let transport_config = server::ServerTransport::WebTransportServer {
                         server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), *local_port),
                         certificate: certificate.into(),
                     };
-                    build_server_netcode_config(
-                        settings.server.conditioner.as_ref(),
-                        &settings.shared,
-                        transport_config
-                    )
+                    build_server_netcode_config(&settings.server, transport_config, *compression)
                 }
             }
         })
@@ -248,22 +403,13 @@ pub(crate) fn get_server_net_configs(settings: &Settings) -> Vec<server::NetConf
 
 /// Build a netcode config for the client
 pub(crate) fn build_client_netcode_config(
-    client_id: u64,
-    server_addr: SocketAddr,
     conditioner: Option<&Conditioner>,
-    shared: &SharedSettings,
-    transport_config: client::ClientTransport
+    transport_config: client::ClientTransport,
+    compression: CompressionConfig
 ) -> client::NetConfig {
     let conditioner = conditioner.map(|c| c.build());
-    // TODO no point having the private key in shared settings. client's shouldn't know it.
-    // use dummy zeroed key explicitly here.
-    let auth = Authentication::Manual {
-        server_addr,
-        client_id,
-        private_key: shared.private_key,
-        protocol_id: shared.protocol_id,
-    };
-    info!("Auth: {auth:?}");
+    // Filled in with a token from the auth service right before connecting.
+    let auth = Authentication::None;
     info!("TransportConfig: {transport_config:?}");
     let netcode_config = client::NetcodeConfig {
         // Make sure that the server times out clients when their connection is closed
@@ -273,7 +419,7 @@ pub(crate) fn build_client_netcode_config(
     let io_config = client::IoConfig {
         transport: transport_config,
         conditioner,
-        compression: shared.compression,
+        compression,
     };
     client::NetConfig::Netcode {
         auth,
@@ -284,7 +430,7 @@ pub(crate) fn build_client_netcode_config(
 
 /// Parse the settings into a `NetConfig` that is used to configure how the lightyear client
 /// connects to the server
-pub(crate) fn get_client_net_config(settings: &Settings, client_id: u64) -> client::NetConfig {
+pub(crate) fn get_client_net_config(settings: &Settings) -> client::NetConfig {
     let server_addr = SocketAddr::new(
         settings.client.server_addr.into(),
         settings.client.server_port
@@ -293,22 +439,18 @@ pub(crate) fn get_client_net_config(settings: &Settings, client_id: u64) -> clie
     match &settings.client.transport {
         ClientTransports::Udp =>
             build_client_netcode_config(
-                client_id,
-                server_addr,
                 settings.client.conditioner.as_ref(),
-                &settings.shared,
-                client::ClientTransport::UdpSocket(client_addr)
+                client::ClientTransport::UdpSocket(client_addr),
+                settings.client.compression
             ),
         ClientTransports::WebTransport =>
             build_client_netcode_config(
-                client_id,
-                server_addr,
                 settings.client.conditioner.as_ref(),
-                &settings.shared,
                 client::ClientTransport::WebTransportClient {
                     client_addr,
                     server_addr,
-                }
+                },
+                settings.client.compression
             ),
     }
 }
@@ -321,7 +463,7 @@ fn parse_private_key_from_env() -> Option<[u8; PRIVATE_KEY_BYTES]> {
     };
     let private_key: Vec<u8> = key_str
         .chars()
-        .filter(|c| (c.is_ascii_digit() || *c == ','))
+        .filter(|c| c.is_ascii_digit() || *c == ',')
         .collect::<String>()
         .split(',')
         .map(|s| { s.parse::<u8>().expect("Failed to parse number in private key") })
@@ -336,43 +478,95 @@ fn parse_private_key_from_env() -> Option<[u8; PRIVATE_KEY_BYTES]> {
     Some(bytes)
 }
 
-pub(crate) fn get_settings() -> Settings {
-    Settings {
-        server: ServerSettings {
-            headless: false,
-            inspector: true,
-            conditioner: Some(Conditioner {
-                latency_ms: 200,
-                jitter_ms: 20,
-                packet_loss: 0.05,
-            }),
-            transport: vec![
-                ServerTransports::WebTransport {
-                    local_port: 5000,
-                    certificate: WebTransportCertificateSettings::FromFile {
-                        cert: "assets/certificates/cert.pem".to_string(),
-                        key: "assets/certificates/key.pem".to_string(),
-                    },
-                },
-                ServerTransports::Udp { local_port: 5001 }
-            ],
-        },
-        client: ClientSettings {
-            inspector: true,
-            client_id: 0,
-            client_port: 0, // 0 means that the OS will assign a random port
-            server_addr: Ipv4Addr::LOCALHOST,
-            server_port: 5000, // change the port depending on the transport used
-            transport: ClientTransports::WebTransport,
-            conditioner: None,
-        },
-        shared: SharedSettings {
-            protocol_id: 0,
-            private_key: [
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0,
-            ],
-            compression: CompressionConfig::None,
-        },
+/// Everything that can go wrong while loading the settings file.
+#[derive(Debug)]
+pub enum SettingsError {
+    /// The settings file could not be read from disk
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The settings file was read, but isn't valid RON for [`Settings`]
+    Parse {
+        path: PathBuf,
+        source: ron::error::SpannedError,
+    },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io { path, source } => {
+                write!(f, "Failed to read settings file {}: {}", path.display(), source)
+            }
+            SettingsError::Parse { path, source } => {
+                write!(
+                    f,
+                    "Failed to parse settings file {} at line {}, column {}: {}",
+                    path.display(),
+                    source.position.line,
+                    source.position.col,
+                    source.code
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SettingsError::Io { source, .. } => Some(source),
+            SettingsError::Parse { source, .. } => Some(source),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads and deserializes the settings from a RON file.
+    pub fn from_file(path: &Path) -> Result<Self, SettingsError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| SettingsError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        ron::de::from_str(&contents).map_err(|source| SettingsError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Layers the command-line flags on top of the values from the settings file.
+    /// Environment variables are handled by clap, so anything set through them is already in `cli`.
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(headless) = cli.headless {
+            self.server.headless = headless;
+        }
+        if let Some(inspector) = cli.inspector {
+            self.server.inspector = inspector;
+            self.client.inspector = inspector;
+        }
+        if let Some(server_addr) = cli.server_addr {
+            self.client.server_addr = server_addr;
+        }
+        if let Some(server_port) = cli.server_port {
+            self.client.server_port = server_port;
+        }
+        if let Some(client_port) = cli.client_port {
+            self.client.client_port = client_port;
+        }
+        if let Some(transport) = &cli.transport {
+            self.client.transport = transport.clone();
+        }
+        if cli.no_conditioner {
+            self.server.conditioner = None;
+            self.client.conditioner = None;
+        }
     }
 }
+
+/// Loads the settings file pointed to by the CLI (or `FLESHBORN_SETTINGS`), then applies overrides.
+pub(crate) fn get_settings(cli: &Cli) -> Result<Settings, SettingsError> {
+    let mut settings = Settings::from_file(&cli.settings)?;
+    settings.apply_cli(cli);
+    Ok(settings)
+}